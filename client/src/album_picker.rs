@@ -0,0 +1,109 @@
+use data::{Album, AlbumImages, AlbumRequest};
+use gloo_net::http::Request;
+use leptos::logging::error;
+use leptos::prelude::*;
+use wasm_bindgen_futures::spawn_local;
+
+async fn fetch_albums() -> Option<Vec<Album>> {
+    let response = Request::get("/albums")
+        .send()
+        .await
+        .map_err(|e| error!("Albums request failed: {:?}", e))
+        .ok()?;
+    response
+        .json()
+        .await
+        .map_err(|e| error!("Failed to parse albums: {:?}", e))
+        .ok()
+}
+
+/// Sends a request changing an album and returns the album as it is now.
+async fn send_album(request: Result<Request, gloo_net::Error>) -> Option<Album> {
+    let response = request
+        .map_err(|e| error!("Failed to build album request: {:?}", e))
+        .ok()?
+        .send()
+        .await
+        .map_err(|e| error!("Album request failed: {:?}", e))
+        .ok()?;
+    response
+        .json()
+        .await
+        .map_err(|e| error!("Failed to parse album: {:?}", e))
+        .ok()
+}
+
+/// Album selection with a button adding the marked images to it. The empty selection creates a
+/// new album from the marked images.
+#[component]
+pub fn AlbumPicker(marked_images: RwSignal<Vec<String>>) -> impl IntoView {
+    let albums = RwSignal::new(Vec::<Album>::new());
+    let selected = RwSignal::new(String::new());
+
+    let refresh = move || {
+        spawn_local(async move {
+            if let Some(fetched) = fetch_albums().await {
+                albums.set(fetched);
+            }
+        });
+    };
+    refresh();
+
+    let add_marked = move |_| {
+        let images = marked_images.get_untracked();
+        let album = selected.get_untracked();
+        let request = if album.is_empty() {
+            let Ok(Some(name)) = window().prompt_with_message("Name of the new album") else {
+                return;
+            };
+            Request::post("/albums").json(&AlbumRequest {
+                name,
+                images,
+                ..Default::default()
+            })
+        } else {
+            Request::post(&format!("/albums/{album}/images")).json(&AlbumImages { images })
+        };
+        spawn_local(async move {
+            if let Some(album) = send_album(request).await {
+                selected.set(album.id);
+                refresh();
+            }
+        });
+    };
+
+    view! {
+        <div style="display: flex; gap: 0.25rem; font-size: 1rem;">
+            <select
+                prop:value=move || selected.get()
+                on:change=move |ev| selected.set(event_target_value(&ev))
+                style="border-radius: 4px; border: none;"
+            >
+                <option value="">"New album…"</option>
+                {move || albums
+                    .get()
+                    .into_iter()
+                    .map(|album| view! {
+                        <option value=album.id.clone()>{format!("{} ({})", album.name, album.size)}</option>
+                    })
+                    .collect::<Vec<_>>()}
+            </select>
+            <button
+                on:click=add_marked
+                disabled=move || marked_images.get().is_empty()
+                title="Add the marked images to the album"
+                style="
+                    padding: 0.3rem 0.75rem;
+                    font-size: 1rem;
+                    border-radius: 4px;
+                    border: none;
+                    background-color: #4caf50;
+                    color: white;
+                    cursor: pointer;
+                "
+            >
+                {move || format!("Add {} to album", marked_images.get().len())}
+            </button>
+        </div>
+    }
+}
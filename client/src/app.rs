@@ -1,6 +1,8 @@
-use serde_wasm_bindgen::to_value;
 use crate::image_grid::ImageGrid;
 use crate::header::Header;
+use crate::map_view::MapView;
+use crate::user::user_id;
+use data::USER_HEADER;
 use leptos::prelude::*;
 use leptos::ev::KeyboardEvent;
 use leptos::view;
@@ -8,53 +10,167 @@ use leptos::component;
 use leptos::IntoView;
 use leptos::prelude::*;
 use wasm_bindgen_futures::spawn_local;
-use data::SearchResponse;
 use data::SearchParams;
+use data::{RoundRequest, RoundResponse, SearchMode, SearchSession, SessionRound};
+use leptos::wasm_bindgen::JsValue;
 use gloo_net::http::Request;
 use leptos::logging::error;
 use leptos::server_fn::codec::Json;
-use serde_json::from_str;
 use serde_urlencoded::to_string;
 use urlencoding::encode;
 
+/// Number of uncertain images requested when suggestions are enabled.
+const SUGGESTIONS: usize = 8;
+/// Diversity of the results when "Varied" is checked.
+const DIVERSITY: f32 = 0.3;
+
+/// Session and round named in the page URL, so a shared link opens the same results.
+fn round_from_url() -> Option<(String, usize)> {
+    let search = window().location().search().ok()?;
+    let params = web_sys::UrlSearchParams::new_with_str(&search).ok()?;
+    Some((params.get("session")?, params.get("round")?.parse().ok()?))
+}
+
+fn set_round_url(session: &str, round: usize) {
+    let url = format!("?session={}&round={}", encode(session), round);
+    if let Ok(history) = window().history() {
+        let _ = history.replace_state_with_url(&JsValue::NULL, "", Some(&url));
+    }
+}
+
+async fn create_session() -> Option<String> {
+    match Request::post("/sessions").send().await {
+        Ok(response) => match response.json::<SearchSession>().await {
+            Ok(session) => Some(session.id),
+            Err(e) => {
+                error!("Failed to parse SearchSession: {:?}", e);
+                None
+            }
+        },
+        Err(e) => {
+            error!("Session request failed: {:?}", e);
+            None
+        }
+    }
+}
+
 #[component]
 pub fn App() -> impl IntoView {
     let (search_term, set_search_term) = signal(String::new());
     let (results, set_results) = signal(Vec::new());
+    let (query_vector, set_query_vector) = signal(Vec::<f32>::new());
+    let (uncertain, set_uncertain) = signal(Vec::new());
+    let suggest = RwSignal::new(false);
+    let mode = RwSignal::new(SearchMode::default());
+    let diverse = RwSignal::new(false);
     let marked_images = RwSignal::<Vec<String>>::new(vec![]);
+    let show_map = RwSignal::new(false);
+
+    let negative_images = RwSignal::<Vec<String>>::new(vec![]);
+    let session = RwSignal::<Option<String>>::new(None);
+    let round = RwSignal::<Option<SessionRound>>::new(None);
+
+    // shows a round and restores the query it was made with, so refining continues from there
+    let show_round = move |parsed: RoundResponse| {
+        set_round_url(&parsed.session, parsed.round.index);
+        set_query_vector.set(parsed.response.query_vector);
+        set_uncertain.set(parsed.response.uncertain);
+        set_results.set(parsed.response.images);
+        set_search_term.set(parsed.round.params.q.clone());
+        marked_images.set(parsed.round.params.referenced_images.clone());
+        negative_images.set(parsed.round.params.negative_images.clone());
+        mode.set(parsed.round.params.mode);
+        session.set(Some(parsed.session));
+        round.set(Some(parsed.round));
+    };
+
+    let load_round = move |session_id: String, index: usize| {
+        spawn_local(async move {
+            let url = format!("/sessions/{}/rounds/{}", encode(&session_id), index);
+            match Request::get(&url).send().await {
+                Ok(response) => match response.json::<RoundResponse>().await {
+                    Ok(parsed) => show_round(parsed),
+                    Err(e) => error!("Failed to parse RoundResponse: {:?}", e),
+                },
+                Err(e) => error!("Round request failed: {:?}", e),
+            }
+        });
+    };
 
+    // every search is a new round of the session, refining the round currently shown
     let perform_search = move |params: SearchParams| {
+        let params = SearchParams {
+            negative_images: negative_images.get_untracked(),
+            suggestions: if suggest.get_untracked() { SUGGESTIONS } else { 0 },
+            mode: mode.get_untracked(),
+            diversity: if diverse.get_untracked() { DIVERSITY } else { 0.0 },
+            ..params
+        };
         error!("Params before encode: {:?}", params);
 
         spawn_local(async move {
-            match to_value(&params) {
-                Ok(js_value) => {
-                    let request = Request::post("/search")
-                        .header("Content-Type", "application/json")
-                        .body(js_sys::JSON::stringify(&js_value).unwrap().as_string().unwrap())
-                        .unwrap();
-
-                    if let Ok(response) = request.send().await {
-                        if let Ok(text) = response.text().await {
-                            if let Ok(parsed) = from_str::<SearchResponse>(&text) {
-                                set_results.set(parsed.images);
-                            } else {
-                                error!("Failed to parse SearchResponse");
-                            }
-                        } else {
-                            error!("Failed to read response text");
-                        }
-                    } else {
-                        error!("Request failed");
-                    }
-                }
+            let session_id = match session.get_untracked() {
+                Some(session_id) => session_id,
+                None => match create_session().await {
+                    Some(session_id) => session_id,
+                    None => return,
+                },
+            };
+            let body = RoundRequest {
+                parent: round.get_untracked().map(|round| round.index),
+                params,
+            };
+            let url = format!("/sessions/{}/rounds", encode(&session_id));
+            let request = match Request::post(&url).header(USER_HEADER, &user_id()).json(&body) {
+                Ok(request) => request,
                 Err(e) => {
                     error!("Failed to serialize params: {:?}", e);
+                    return;
                 }
+            };
+            match request.send().await {
+                Ok(response) => match response.json::<RoundResponse>().await {
+                    Ok(parsed) => show_round(parsed),
+                    Err(e) => error!("Failed to parse RoundResponse: {:?}", e),
+                },
+                Err(e) => error!("Request failed: {:?}", e),
             }
         });
     };
 
+    // a recent or saved search runs again with its own feedback images
+    let rerun = move |params: SearchParams| {
+        set_search_term.set(params.q.clone());
+        marked_images.set(params.referenced_images.clone());
+        negative_images.set(params.negative_images.clone());
+        mode.set(params.mode);
+        perform_search(params);
+    };
+
+    // steps back to the round the current one refined, the next search branches from there
+    let go_back = move || {
+        if let (Some(session_id), Some(parent)) = (
+            session.get_untracked(),
+            round.get_untracked().and_then(|round| round.parent),
+        ) {
+            load_round(session_id, parent);
+        }
+    };
+
+    if let Some((session_id, index)) = round_from_url() {
+        load_round(session_id, index);
+    }
+
+    // images around a spot on the map become the references of a new search
+    let search_from_map = move |image_paths: Vec<String>| {
+        marked_images.set(image_paths.clone());
+        show_map.set(false);
+        perform_search(SearchParams {
+            q: search_term.get_untracked(),
+            referenced_images: image_paths,
+            ..Default::default()
+        });
+    };
 
     let perform_scan = move || {
         spawn_local(async move {
@@ -70,11 +186,26 @@ pub fn App() -> impl IntoView {
 
     view! {
         <div style="display: flex; flex-direction: column; height: 100vh; background-color: #161618;">
-            <Header search_term search_term_set=set_search_term on_submit=perform_search on_scan=perform_scan marked_images=marked_images />
+            <Header search_term search_term_set=set_search_term on_submit=perform_search on_rerun=rerun on_scan=perform_scan on_back=go_back round=round marked_images=marked_images show_map=show_map suggest=suggest mode=mode diverse=diverse />
             <main style="flex: 1; padding-top: 60px;">
-                <div style="padding-top: 1rem;">
-                    <ImageGrid images=results marked_images=marked_images/>
-                </div>
+                <Show
+                    when=move || show_map.get()
+                    fallback=move || view! {
+                        <Show when=move || !uncertain.get().is_empty() fallback=|| ()>
+                            <div style="padding: 1rem 0; border-bottom: 1px solid #646472;">
+                                <h2 style="color: white; font-size: 1rem; margin: 0 0 0.5rem 0.5rem;">
+                                    "Not sure about these, marking the matching ones refines the search the most"
+                                </h2>
+                                <ImageGrid images=uncertain marked_images=marked_images negative_images=negative_images query_vector=query_vector/>
+                            </div>
+                        </Show>
+                        <div style="padding-top: 1rem;">
+                            <ImageGrid images=results marked_images=marked_images negative_images=negative_images query_vector=query_vector/>
+                        </div>
+                    }
+                >
+                    <MapView on_select=search_from_map />
+                </Show>
             </main>
         </div>
     }
@@ -0,0 +1,78 @@
+use data::ImageCaption;
+use gloo_net::http::Request;
+use leptos::logging::error;
+use leptos::prelude::*;
+use urlencoding::encode;
+use wasm_bindgen_futures::spawn_local;
+use web_sys::MouseEvent;
+
+/// Editable caption and notes of an image, saved with the button. Both are found by full-text
+/// search.
+#[component]
+pub fn CaptionEditor(image_id: String, caption: RwSignal<ImageCaption>) -> impl IntoView {
+    let draft = RwSignal::new(caption.get_untracked());
+    let saving = RwSignal::new(false);
+    let image_id = StoredValue::new(image_id);
+
+    let save = move |_: MouseEvent| {
+        let url = format!("/images/{}/caption", encode(&image_id.get_value()));
+        let body = draft.get_untracked();
+        saving.set(true);
+        spawn_local(async move {
+            match Request::put(&url).json(&body) {
+                Ok(request) => match request.send().await {
+                    Ok(response) => match response.json::<ImageCaption>().await {
+                        Ok(saved) => {
+                            draft.set(saved.clone());
+                            caption.set(saved);
+                        }
+                        Err(e) => error!("Failed to parse ImageCaption: {:?}", e),
+                    },
+                    Err(e) => error!("Caption request failed: {:?}", e),
+                },
+                Err(e) => error!("Failed to serialize caption: {:?}", e),
+            }
+            saving.set(false);
+        });
+    };
+
+    let field_style = "width: 100%; box-sizing: border-box; border-radius: 4px; border: none; font-size: 0.9rem;";
+    view! {
+        <div
+            style="display: flex; flex-direction: column; gap: 0.25rem; width: 300px;"
+            on:click:stop_propagation=move |_: MouseEvent| {}
+            on:mousedown=move |ev: MouseEvent| ev.stop_propagation()
+            on:wheel=move |ev| ev.stop_propagation()
+        >
+            <input
+                type="text"
+                placeholder="Caption"
+                style=field_style
+                prop:value=move || draft.get().caption.unwrap_or_default()
+                on:input=move |ev| draft.update(|draft| draft.caption = Some(event_target_value(&ev)))
+            />
+            <textarea
+                placeholder="Notes"
+                rows="3"
+                style=field_style
+                prop:value=move || draft.get().notes.unwrap_or_default()
+                on:input=move |ev| draft.update(|draft| draft.notes = Some(event_target_value(&ev)))
+            />
+            <button
+                on:click=save
+                disabled=move || saving.get() || draft.get() == caption.get()
+                style="
+                    align-self: flex-end;
+                    padding: 0.2rem 0.75rem;
+                    border-radius: 4px;
+                    border: none;
+                    background-color: #4caf50;
+                    color: white;
+                    cursor: pointer;
+                "
+            >
+                "Save"
+            </button>
+        </div>
+    }
+}
@@ -1,20 +1,30 @@
 use leptos::prelude::*;
 use leptos::*;
-use data::SearchParams;
+use crate::album_picker::AlbumPicker;
+use crate::search_menu::SearchMenu;
+use data::{SearchMode, SearchParams, SessionRound};
 #[component]
 pub fn Header(
     search_term: ReadSignal<String>,
     search_term_set: WriteSignal<String>,
     on_submit: impl Fn(SearchParams) + 'static + Copy,
+    on_rerun: impl Fn(SearchParams) + 'static + Copy,
     on_scan: impl Fn() + 'static + Copy,
+    on_back: impl Fn() + 'static + Copy,
+    round: RwSignal<Option<SessionRound>>,
     marked_images: RwSignal::<Vec<String>>,
+    show_map: RwSignal<bool>,
+    suggest: RwSignal<bool>,
+    mode: RwSignal<SearchMode>,
+    diverse: RwSignal<bool>,
 ) -> impl IntoView {
+    let (menu_open, set_menu_open) = signal(false);
 
     let on_key_down = move |ev: web_sys::KeyboardEvent| {
         if ev.key() == "Enter" {
             let term = search_term.get();
             if !term.trim().is_empty() {
-                on_submit(SearchParams{q: term, referenced_images: marked_images.get().clone()});
+                on_submit(SearchParams{q: term, referenced_images: marked_images.get().clone(), ..Default::default()});
             }
         }
     };
@@ -52,20 +62,95 @@ pub fn Header(
             >
                 "Scan"
             </button>
-            <input
-                type="search"
-                placeholder="Search..."
-                prop:value=search_term
-                on:input=move |ev| search_term_set.set(event_target_value(&ev))
-                on:keydown=on_key_down
+            <button
+                on:click=move |_| show_map.update(|show| *show = !*show)
+                style="
+                    padding: 0.3rem 0.75rem;
+                    font-size: 1rem;
+                    border-radius: 4px;
+                    border: none;
+                    background-color: #4caf50;
+                    color: white;
+                    cursor: pointer;
+                "
+            >
+                {move || if show_map.get() { "Results" } else { "Map" }}
+            </button>
+            <button
+                on:click=move |_| on_back()
+                disabled=move || round.get().and_then(|round| round.parent).is_none()
+                title="Back to the round this one refined"
                 style="
-                    padding: 0.15rem;
+                    padding: 0.3rem 0.75rem;
                     font-size: 1rem;
                     border-radius: 4px;
                     border: none;
-                    width: 400px;
+                    background-color: #4caf50;
+                    color: white;
+                    cursor: pointer;
                 "
-            />
+            >
+                {move || match round.get() {
+                    Some(round) => format!("Back (round {})", round.index + 1),
+                    None => "Back".to_string(),
+                }}
+            </button>
+            <label style="font-size: 1rem;">
+                <input
+                    type="checkbox"
+                    prop:checked=move || suggest.get()
+                    on:change=move |ev| suggest.set(event_target_checked(&ev))
+                />
+                " Suggest"
+            </label>
+            <label style="font-size: 1rem;" title="Show varied matches instead of many shots of the same scene">
+                <input
+                    type="checkbox"
+                    prop:checked=move || diverse.get()
+                    on:change=move |ev| diverse.set(event_target_checked(&ev))
+                />
+                " Varied"
+            </label>
+            <select
+                title="Match the text against the image contents, against paths and captions, or both"
+                style="font-size: 1rem; border-radius: 4px; border: none;"
+                on:change=move |ev| mode.set(match event_target_value(&ev).as_str() {
+                    "lexical" => SearchMode::Lexical,
+                    "hybrid" => SearchMode::Hybrid,
+                    _ => SearchMode::Vector,
+                })
+            >
+                <option value="vector" selected=move || mode.get() == SearchMode::Vector>"Images"</option>
+                <option value="hybrid" selected=move || mode.get() == SearchMode::Hybrid>"Images + text"</option>
+                <option value="lexical" selected=move || mode.get() == SearchMode::Lexical>"Paths and captions"</option>
+            </select>
+            <AlbumPicker marked_images=marked_images />
+            <div style="position: relative;">
+                <input
+                    type="search"
+                    placeholder="Search..."
+                    prop:value=search_term
+                    on:input=move |ev| search_term_set.set(event_target_value(&ev))
+                    on:keydown=on_key_down
+                    on:focus=move |_| set_menu_open.set(true)
+                    on:blur=move |_| set_menu_open.set(false)
+                    style="
+                        padding: 0.15rem;
+                        font-size: 1rem;
+                        border-radius: 4px;
+                        border: none;
+                        width: 400px;
+                    "
+                />
+                <SearchMenu
+                    open=menu_open
+                    round=round
+                    on_select=move |params| {
+                        set_menu_open.set(false);
+                        on_rerun(params);
+                    }
+                />
+            </div>
         </header>
     }
 }
\ No newline at end of file
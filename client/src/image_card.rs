@@ -1,14 +1,48 @@
 use crate::image_modal::ImageModal;
-use data::ImageReference;
+use crate::rating::RatingControls;
+use data::{ImageCaption, ImageReference, MediaKind};
 use leptos::prelude::*;
 use leptos::*;
 
 #[component]
-pub fn ImageCard(image: ImageReference, marked_images: RwSignal<Vec<String>>) -> impl IntoView {
+pub fn ImageCard(
+    image: ImageReference,
+    marked_images: RwSignal<Vec<String>>,
+    negative_images: RwSignal<Vec<String>>,
+    query_vector: ReadSignal<Vec<f32>>,
+) -> impl IntoView {
     let (is_open, set_is_open) = signal(false);
     let image_path = image.image_path.clone();
-    let image_path_for_click = image_path.clone();
+    let image_path_for_click = image.display_path().to_string();
+    let media_url = image.media_url();
+    let kind = image.kind;
+    let frame = image.frame;
+    let region = image.region;
+    let image_id = image.id.clone();
+    let tags = image.tags.clone();
+    let rating = RwSignal::new(image.rating);
+    let favourite = RwSignal::new(image.favourite);
+    let rating_image_id = image_id.clone();
+    let caption = RwSignal::new(ImageCaption {
+        caption: image.caption.clone(),
+        notes: image.notes.clone(),
+    });
+    // identical copies of the file are listed on hover
+    let paths = std::iter::once(image.image_path.clone())
+        .chain(image.alternate_paths.iter().cloned())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let alt = if tags.is_empty() {
+        "Bild".to_string()
+    } else {
+        tags.iter().map(|tag| tag.label.as_str()).collect::<Vec<_>>().join(", ")
+    };
     let image_path_for_checkbox = image_path.clone();
+    let image_path_for_checked = image_path.clone();
+    let image_path_for_rejected = image_path.clone();
+    let image_path_for_reject = image_path.clone();
+    let is_marked = move || marked_images.get().contains(&image_path_for_checked);
+    let is_rejected = move || negative_images.get().contains(&image_path_for_rejected);
     let checkbox_click = {
         move |_| {
             let mut current = marked_images.get();
@@ -20,11 +54,22 @@ pub fn ImageCard(image: ImageReference, marked_images: RwSignal<Vec<String>>) ->
             } else {
                 // Hinzufügen
                 current.push(image_path.clone());
+                negative_images.update(|negatives| negatives.retain(|m| *m != image_path));
             }
 
             marked_images.set(current);
         }
     };
+    // an image is either wanted or not wanted, marking it one way clears the other
+    let reject_click = move |_| {
+        let path = image_path_for_reject.clone();
+        if negative_images.get_untracked().contains(&path) {
+            negative_images.update(|negatives| negatives.retain(|m| *m != path));
+        } else {
+            marked_images.update(|marked| marked.retain(|m| *m != path));
+            negative_images.update(|negatives| negatives.push(path));
+        }
+    };
 
     view! {
         <div
@@ -38,7 +83,24 @@ pub fn ImageCard(image: ImageReference, marked_images: RwSignal<Vec<String>>) ->
                 overflow: hidden;            "
         >
             <div style="padding: 0.25rem;">
-                <input type="checkbox" on:click=checkbox_click />
+                <input type="checkbox" prop:checked=is_marked on:click=checkbox_click />
+                <button
+                    title="Not this, move the search away from this image"
+                    on:click=reject_click
+                    style=move || format!(
+                        "margin-left: 0.25rem; padding: 0 0.4rem; border: none; border-radius: 4px; cursor: pointer; color: white; background-color: {};",
+                        if is_rejected() { "#c0392b" } else { "#3a3a42" }
+                    )
+                >
+                    "✕"
+                </button>
+                // best matching frame of an animated image
+                {frame.map(|frame| view! {
+                    <span style="margin-left: 0.5rem; font-size: 0.8rem;">{format!("Frame {frame}")}</span>
+                })}
+                <span style="float: right;">
+                    <RatingControls image_id=rating_image_id rating=rating favourite=favourite />
+                </span>
             </div>
 
             <div style="
@@ -49,23 +111,77 @@ pub fn ImageCard(image: ImageReference, marked_images: RwSignal<Vec<String>>) ->
                 overflow: hidden;
                 cursor: pointer;
             ">
-                <img
-                    src=image_path_for_click.clone()
-                    alt="Bild"
-                    style="
-                        max-width: 100%;
-                        max-height: 100%;
-                        object-fit: contain;
-                        display: block;
-                    "
-                    on:click=move |_| set_is_open.set(true)
-                />
+                {match kind {
+                    MediaKind::Video => view! {
+                        <video
+                            src=media_url.clone()
+                            preload="metadata"
+                            muted=true
+                            style="
+                                max-width: 100%;
+                                max-height: 100%;
+                                object-fit: contain;
+                                display: block;
+                            "
+                            on:click=move |_| set_is_open.set(true)
+                        />
+                    }
+                    .into_any(),
+                    MediaKind::Image => view! {
+                        <img
+                            src=image_path_for_click.clone()
+                            alt=alt.clone()
+                            title=paths.clone()
+                            style="
+                                max-width: 100%;
+                                max-height: 100%;
+                                object-fit: contain;
+                                display: block;
+                            "
+                            on:click=move |_| set_is_open.set(true)
+                        />
+                    }
+                    .into_any(),
+                }}
+            </div>
+
+            {move || caption.get().caption.map(|text| view! {
+                <div style="padding: 0 0.25rem; font-size: 0.8rem; font-style: italic; overflow: hidden; text-overflow: ellipsis; white-space: nowrap;">
+                    {text}
+                </div>
+            })}
+            <div style="display: flex; flex-wrap: wrap; gap: 0.25rem; padding: 0.25rem;">
+                {tags
+                    .into_iter()
+                    .map(|tag| view! {
+                        <span
+                            title=format!("{:.2}", tag.score)
+                            style="
+                                padding: 0 0.4rem;
+                                font-size: 0.75rem;
+                                border-radius: 8px;
+                                background-color: #4caf50;
+                                color: white;
+                            "
+                        >
+                            {tag.label}
+                        </span>
+                    })
+                    .collect_view()}
             </div>
         </div>
 
         <Show when=move || is_open.get() fallback=|| ()>
             <ImageModal
                 image_path=image_path_for_click.clone() // ✅ sicher zu benutzen
+                kind=kind
+                media_url=media_url.clone()
+                region=region
+                image_id=image_id.clone()
+                query_vector=query_vector
+                rating=rating
+                favourite=favourite
+                caption=caption
                 on_close=move || set_is_open.set(false)
             />
         </Show>
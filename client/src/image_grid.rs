@@ -8,6 +8,8 @@ use leptos::*;
 pub fn ImageGrid(
     images: ReadSignal<Vec<ImageReference>>,
     marked_images: RwSignal<Vec<String>>,
+    negative_images: RwSignal<Vec<String>>,
+    query_vector: ReadSignal<Vec<f32>>,
 ) -> impl IntoView {
     let items = move || images.get();
 
@@ -22,7 +24,7 @@ pub fn ImageGrid(
                 each=items
                 key=|image| image.id.clone()
                 children=move |image| view! {
-                    <ImageCard image=image marked_images=marked_images/>
+                    <ImageCard image=image marked_images=marked_images negative_images=negative_images query_vector=query_vector/>
                 }
             />
         </div>
@@ -1,58 +1,99 @@
+use crate::caption::CaptionEditor;
+use crate::pan_zoom::PanZoom;
+use crate::rating::RatingControls;
+use data::{Heatmap, HeatmapRequest, ImageCaption, MediaKind, Region, Tag};
+use gloo_net::http::Request;
 use leptos::callback::Callback;
 use leptos::html::Div;
 use leptos::prelude::*;
 use leptos::*;
+use leptos::logging::error;
+use urlencoding::encode;
+use wasm_bindgen_futures::spawn_local;
 use web_sys::{MouseEvent, WheelEvent};
 #[component]
-pub fn ImageModal(image_path: String, on_close: impl Fn() + 'static) -> impl IntoView {
-    let (scale, set_scale) = create_signal(1.0_f64);
-    let (offset, set_offset) = create_signal((0.0_f64, 0.0_f64));
-    let container_ref = create_node_ref::<Div>();
-    let is_dragging = create_rw_signal(false);
-    let (last_mouse_pos, set_last_mouse_pos) = create_signal((0.0, 0.0));
-
-    let on_wheel = move |ev: WheelEvent| {
-        ev.prevent_default();
-        let delta = ev.delta_y();
-        let scale_factor: f64 = if delta > 0.0 { 0.9 } else { 1.1 };
-
-        if let Some(container) = container_ref.get() {
-            let rect = container.get_bounding_client_rect();
-            let mouse_x = ev.client_x() as f64 - rect.left();
-            let mouse_y = ev.client_y() as f64 - rect.top();
-
-            let old_scale = scale.get();
-            let new_scale = (old_scale * scale_factor).clamp(0.5, 5.0);
-
-            let (ox, oy) = offset.get();
-            let new_ox = (ox - mouse_x) * scale_factor + mouse_x;
-            let new_oy = (oy - mouse_y) * scale_factor + mouse_y;
-
-            set_offset.set((new_ox, new_oy));
-            set_scale.set(new_scale);
-        }
-    };
-
-    let on_mouse_down = move |ev: MouseEvent| {
-        ev.prevent_default();
-        is_dragging.set(true);
-        set_last_mouse_pos.set((ev.client_x() as f64, ev.client_y() as f64));
+pub fn ImageModal(
+    image_path: String,
+    on_close: impl Fn() + 'static,
+    #[prop(optional)] kind: MediaKind,
+    /// Video URL including the start position, used instead of `image_path` for videos.
+    #[prop(optional)]
+    media_url: Option<String>,
+    /// Best matching region, drawn as a frame on top of the image.
+    #[prop(default = None)]
+    region: Option<Region>,
+    /// Record id and query of the search result, needed to request a relevance heatmap.
+    #[prop(optional)]
+    image_id: Option<String>,
+    #[prop(optional)]
+    query_vector: Option<ReadSignal<Vec<f32>>>,
+    /// Stars and favourite flag, shared with the card so changes show up in both.
+    #[prop(optional)]
+    rating: Option<RwSignal<Option<u8>>>,
+    #[prop(optional)]
+    favourite: Option<RwSignal<bool>>,
+    /// Caption and notes, editable in the modal and shared with the card.
+    #[prop(optional)]
+    caption: Option<RwSignal<ImageCaption>>,
+) -> impl IntoView {
+    let heatmap = RwSignal::<Option<Heatmap>>::new(None);
+    let labels = RwSignal::<Vec<Tag>>::new(Vec::new());
+    if let Some(image_id) = image_id.clone() {
+        spawn_local(async move {
+            let url = format!("/images/{}/labels?k=5", encode(&image_id));
+            match Request::get(&url).send().await {
+                Ok(response) => match response.json::<Vec<Tag>>().await {
+                    Ok(parsed) => labels.set(parsed),
+                    Err(e) => error!("Failed to parse labels: {:?}", e),
+                },
+                Err(e) => error!("Labels request failed: {:?}", e),
+            }
+        });
+    }
+    // nearest text labels double as a description for screen readers
+    let description = move || {
+        labels
+            .get()
+            .iter()
+            .map(|tag| tag.label.clone())
+            .collect::<Vec<_>>()
+            .join(", ")
     };
-
-    let on_mouse_move = move |ev: MouseEvent| {
-        if is_dragging.get() {
-            let (last_x, last_y) = last_mouse_pos.get();
-            let dx = ev.client_x() as f64 - last_x;
-            let dy = ev.client_y() as f64 - last_y;
-            let (ox, oy) = offset.get();
-            set_offset.set((ox + dx, oy + dy));
-            set_last_mouse_pos.set((ev.client_x() as f64, ev.client_y() as f64));
+    let rating_image_id = image_id.clone();
+    let caption_image_id = image_id.clone();
+    let can_explain = image_id.is_some() && query_vector.is_some_and(|query| !query.get_untracked().is_empty());
+    let toggle_heatmap = move |_: MouseEvent| {
+        if heatmap.get().is_some() {
+            heatmap.set(None);
+            return;
         }
+        let (Some(image_id), Some(query_vector)) = (image_id.clone(), query_vector) else {
+            return;
+        };
+        let request = HeatmapRequest {
+            image_id,
+            query_vector: query_vector.get_untracked(),
+            grid: None,
+        };
+        spawn_local(async move {
+            let body = serde_json::to_string(&request).unwrap();
+            let response = Request::post("/heatmap")
+                .header("Content-Type", "application/json")
+                .body(body)
+                .unwrap()
+                .send()
+                .await;
+            match response {
+                Ok(response) => match response.json::<Heatmap>().await {
+                    Ok(parsed) => heatmap.set(Some(parsed)),
+                    Err(e) => error!("Failed to parse Heatmap: {:?}", e),
+                },
+                Err(e) => error!("Heatmap request failed: {:?}", e),
+            }
+        });
     };
-
-    let on_mouse_up = move |_: MouseEvent| {
-        is_dragging.set(false);
-    };
+    let container_ref = NodeRef::<Div>::new();
+    let pan_zoom = PanZoom::new(container_ref, 0.5, 5.0);
 
     view! {
         <div
@@ -70,32 +111,144 @@ pub fn ImageModal(image_path: String, on_close: impl Fn() + 'static) -> impl Int
             <div
                 node_ref=container_ref
                 style="width: 100%; height: 100%; position: relative; overflow: hidden;"
-                on:wheel=on_wheel
-                on:mousemove=on_mouse_move
-                on:mouseup=on_mouse_up
-                on:mousedown=on_mouse_down
+                on:wheel=move |ev: WheelEvent| pan_zoom.on_wheel(ev)
+                on:mousemove=move |ev: MouseEvent| pan_zoom.on_mouse_move(ev)
+                on:mouseup=move |_: MouseEvent| pan_zoom.on_mouse_up()
+                on:mousedown=move |ev: MouseEvent| pan_zoom.on_mouse_down(ev)
                 on:click:stop_propagation=move |_: MouseEvent| {}
             >
-                <img
-                    src=image_path
-                    draggable="false"
-                    style=move || {
-                        let (ox, oy) = offset.get();
-                        let s = scale.get();
-                        format!(
-                            "\
-                            transform: translate({}px, {}px) scale({});\
-                            transform-origin: 0 0;\
-                            user-select: none;\
-                            pointer-events: none;\
-                            position: absolute;\
-                            top: 0; left: 0;\
-                            max-width: none;\
-                            ",
-                            ox, oy, s
-                        )
+                <Show when=move || can_explain && kind == MediaKind::Image fallback=|| ()>
+                    <button
+                        on:click=toggle_heatmap.clone()
+                        on:mousedown=move |ev: MouseEvent| ev.stop_propagation()
+                        style="
+                            position: absolute;
+                            top: 1rem; left: 1rem;
+                            z-index: 1;
+                            padding: 0.3rem 0.75rem;
+                            font-size: 1rem;
+                            border-radius: 4px;
+                            border: none;
+                            background-color: #4caf50;
+                            color: white;
+                            cursor: pointer;
+                        "
+                    >
+                        {move || if heatmap.get().is_some() { "Hide heatmap" } else { "Why this result?" }}
+                    </button>
+                </Show>
+                {match (rating_image_id, rating, favourite) {
+                    (Some(image_id), Some(rating), Some(favourite)) => Some(view! {
+                        <div style="
+                            position: absolute;
+                            top: 1rem; right: 1rem;
+                            z-index: 1;
+                            padding: 0.3rem 0.75rem;
+                            border-radius: 4px;
+                            background-color: rgba(0,0,0,0.6);
+                        ">
+                            <RatingControls image_id=image_id rating=rating favourite=favourite />
+                        </div>
+                    }),
+                    _ => None,
+                }}
+                {caption_image_id.zip(caption).map(|(image_id, caption)| view! {
+                    <div style="
+                        position: absolute;
+                        bottom: 1rem; right: 1rem;
+                        z-index: 1;
+                        padding: 0.5rem;
+                        border-radius: 4px;
+                        background-color: rgba(0,0,0,0.6);
+                    ">
+                        <CaptionEditor image_id=image_id caption=caption />
+                    </div>
+                })}
+                <Show when=move || !labels.get().is_empty() fallback=|| ()>
+                    <div style="
+                        position: absolute;
+                        bottom: 1rem; left: 1rem;
+                        z-index: 1;
+                        padding: 0.3rem 0.75rem;
+                        border-radius: 4px;
+                        background-color: rgba(0,0,0,0.6);
+                        color: white;
+                        font-size: 0.9rem;
+                    ">
+                        {move || labels
+                            .get()
+                            .into_iter()
+                            .map(|tag| format!("{} ({:.2})", tag.label, tag.score))
+                            .collect::<Vec<_>>()
+                            .join(" · ")}
+                    </div>
+                </Show>
+                {match kind {
+                    MediaKind::Video => view! {
+                        <video
+                            src=media_url.unwrap_or(image_path)
+                            controls=true
+                            autoplay=true
+                            style="max-width: 100%; max-height: 100%; position: absolute; inset: 0; margin: auto;"
+                        />
+                    }
+                    .into_any(),
+                    MediaKind::Image => view! {
+                        <div style=move || {
+                            format!(
+                                "\
+                                {}\
+                                user-select: none;\
+                                pointer-events: none;\
+                                position: absolute;\
+                                top: 0; left: 0;\
+                                ",
+                                pan_zoom.transform()
+                            )
+                        }>
+                            <img
+                                src=image_path
+                                alt=description
+                                draggable="false"
+                                style="display: block; max-width: none;"
+                            />
+                            {move || heatmap.get().map(|heatmap| view! {
+                                <div style=format!(
+                                    "\
+                                    position: absolute; inset: 0;\
+                                    display: grid;\
+                                    grid-template-columns: repeat({0}, 1fr);\
+                                    grid-template-rows: repeat({0}, 1fr);\
+                                    ",
+                                    heatmap.grid
+                                )>
+                                    {heatmap
+                                        .values
+                                        .into_iter()
+                                        .map(|value| view! {
+                                            <div style=format!("background-color: rgba(255, 60, 0, {:.2});", value * 0.6) />
+                                        })
+                                        .collect_view()}
+                                </div>
+                            })}
+                            {region.map(|region| view! {
+                                <div style=format!(
+                                    "\
+                                    position: absolute;\
+                                    left: {}%; top: {}%; width: {}%; height: {}%;\
+                                    border: 3px solid #ffd400;\
+                                    box-shadow: 0 0 0 9999px rgba(0,0,0,0.35);\
+                                    ",
+                                    region.x * 100.0,
+                                    region.y * 100.0,
+                                    region.width * 100.0,
+                                    region.height * 100.0
+                                ) />
+                            })}
+                        </div>
                     }
-                />
+                    .into_any(),
+                }}
             </div>
         </div>
     }
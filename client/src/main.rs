@@ -1,8 +1,15 @@
 pub mod app;
+mod album_picker;
+mod caption;
 pub mod header;
 pub mod image_grid;
 pub mod image_card;
 mod image_modal;
+mod map_view;
+mod pan_zoom;
+mod rating;
+mod search_menu;
+mod user;
 
 use crate::app::App;
 use leptos::mount::mount_to_body;
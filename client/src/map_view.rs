@@ -0,0 +1,171 @@
+use crate::pan_zoom::PanZoom;
+use data::{MapPoint, MapTile};
+use gloo_net::http::Request;
+use leptos::control_flow::For;
+use leptos::html::Div;
+use leptos::logging::error;
+use leptos::prelude::*;
+use std::collections::{HashMap, HashSet};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{MouseEvent, WheelEvent};
+
+/// Width of the whole map in pixels at scale 1.
+const MAP_SIZE: f64 = 1024.0;
+/// Deepest zoom level the server serves tiles for.
+const MAX_TILE_ZOOM: u32 = 12;
+/// Size of a thumbnail on screen, independent of the zoom.
+const THUMBNAIL_SIZE: f64 = 48.0;
+/// Images within this many pixels of a click seed the search.
+const SELECTION_RADIUS: f64 = 80.0;
+const MAX_SELECTION: usize = 8;
+
+type TileKey = (u32, u32, u32);
+
+/// Zoomable map of the whole library where similar images sit near each other. Clicking a spot
+/// hands the images around it to `on_select`.
+#[component]
+pub fn MapView(on_select: impl Fn(Vec<String>) + 'static + Copy) -> impl IntoView {
+    let container_ref = NodeRef::<Div>::new();
+    let pan_zoom = PanZoom::new(container_ref, 0.5, 4096.0);
+    let tiles = RwSignal::new(HashMap::<TileKey, Vec<MapPoint>>::new());
+    let requested = StoredValue::new(HashSet::<TileKey>::new());
+
+    // one more tile level per doubling of the scale, so a tile always covers 1024 to 2048 pixels
+    let visible_tiles = move || -> Vec<TileKey> {
+        let scale = pan_zoom.scale.get();
+        let (ox, oy) = pan_zoom.offset.get();
+        let Some(container) = container_ref.get() else {
+            return Vec::new();
+        };
+        let rect = container.get_bounding_client_rect();
+        let zoom = (scale.log2().floor().max(0.0) as u32).min(MAX_TILE_ZOOM);
+        let tiles_per_side = 1u32 << zoom;
+        let tile_size = MAP_SIZE * scale / tiles_per_side as f64;
+        let range = |offset: f64, extent: f64| {
+            let first = (-offset / tile_size).floor().max(0.0) as u32;
+            let last = ((extent - offset) / tile_size).floor().min(tiles_per_side as f64 - 1.0);
+            if last < 0.0 { 1..=0 } else { first..=last as u32 }
+        };
+        range(oy, rect.height())
+            .flat_map(|y| range(ox, rect.width()).map(move |x| (zoom, x, y)))
+            .collect()
+    };
+
+    Effect::new(move |_| {
+        for tile in visible_tiles() {
+            if requested.with_value(|requested| requested.contains(&tile)) {
+                continue;
+            }
+            requested.update_value(|requested| {
+                requested.insert(tile);
+            });
+            spawn_local(async move {
+                let (zoom, x, y) = tile;
+                let url = format!("/map/tiles/{zoom}/{x}/{y}");
+                match Request::get(&url).send().await {
+                    Ok(response) => match response.json::<MapTile>().await {
+                        Ok(parsed) => tiles.update(|tiles| {
+                            tiles.insert(tile, parsed.points);
+                        }),
+                        Err(e) => error!("Failed to parse MapTile: {:?}", e),
+                    },
+                    Err(e) => error!("Map tile request failed: {:?}", e),
+                }
+            });
+        }
+    });
+
+    // tiles still loading are filled with the points of the nearest loaded coarser tile
+    let points = move || -> Vec<MapPoint> {
+        let visible = visible_tiles();
+        tiles.with(|tiles| {
+            visible
+                .into_iter()
+                .flat_map(|(zoom, x, y)| {
+                    let size = 1.0 / (1u32 << zoom) as f32;
+                    let (left, top) = (x as f32 * size, y as f32 * size);
+                    (0..=zoom)
+                        .find_map(|up| tiles.get(&(zoom - up, x >> up, y >> up)))
+                        .into_iter()
+                        .flatten()
+                        .filter(move |point| {
+                            point.x >= left && point.x < left + size && point.y >= top && point.y < top + size
+                        })
+                        .cloned()
+                        .collect::<Vec<_>>()
+                })
+                .collect()
+        })
+    };
+
+    let on_click = move |ev: MouseEvent| {
+        if pan_zoom.was_dragged() {
+            return;
+        }
+        let Some(position) = pan_zoom.container_position(&ev) else {
+            return;
+        };
+        let (cx, cy) = pan_zoom.to_content(position);
+        let radius = SELECTION_RADIUS / pan_zoom.scale.get_untracked();
+        let mut nearby: Vec<(f64, String)> = points()
+            .into_iter()
+            .map(|point| {
+                let distance = (point.x as f64 * MAP_SIZE - cx).hypot(point.y as f64 * MAP_SIZE - cy);
+                (distance, point.image_path)
+            })
+            .filter(|(distance, _)| *distance <= radius)
+            .collect();
+        nearby.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let selection: Vec<String> = nearby.into_iter().take(MAX_SELECTION).map(|(_, path)| path).collect();
+        if !selection.is_empty() {
+            on_select(selection);
+        }
+    };
+
+    view! {
+        <div
+            node_ref=container_ref
+            style="width: 100%; height: calc(100vh - 60px); position: relative; overflow: hidden; cursor: crosshair;"
+            on:wheel=move |ev: WheelEvent| pan_zoom.on_wheel(ev)
+            on:mousemove=move |ev: MouseEvent| pan_zoom.on_mouse_move(ev)
+            on:mouseup=move |_: MouseEvent| pan_zoom.on_mouse_up()
+            on:mousedown=move |ev: MouseEvent| pan_zoom.on_mouse_down(ev)
+            on:click=on_click
+        >
+            <div style=move || format!(
+                "{} position: absolute; top: 0; left: 0; width: {MAP_SIZE}px; height: {MAP_SIZE}px; pointer-events: none;",
+                pan_zoom.transform()
+            )>
+                <For
+                    each=points
+                    key=|point| point.id.clone()
+                    children=move |point| {
+                        let size = move || THUMBNAIL_SIZE / pan_zoom.scale.get();
+                        view! {
+                            <img
+                                src=point.preview_path.clone().unwrap_or(point.image_path.clone())
+                                alt=""
+                                loading="lazy"
+                                draggable="false"
+                                style=move || format!(
+                                    "\
+                                    position: absolute;\
+                                    left: {}px; top: {}px;\
+                                    width: {2}px; height: {2}px;\
+                                    margin: -{3}px 0 0 -{3}px;\
+                                    object-fit: cover;\
+                                    border-radius: 2px;\
+                                    ",
+                                    point.x as f64 * MAP_SIZE,
+                                    point.y as f64 * MAP_SIZE,
+                                    size(),
+                                    size() / 2.0
+                                )
+                            />
+                        }
+                    }
+                />
+            </div>
+        </div>
+    }
+}
@@ -0,0 +1,105 @@
+use leptos::html::Div;
+use leptos::prelude::*;
+use web_sys::{MouseEvent, WheelEvent};
+
+/// Mouse wheel zoom around the cursor and panning by dragging, shared by the image modal and the
+/// map. The content is positioned with [`PanZoom::transform`] inside the `container` element.
+#[derive(Clone, Copy)]
+pub struct PanZoom {
+    pub scale: RwSignal<f64>,
+    pub offset: RwSignal<(f64, f64)>,
+    container: NodeRef<Div>,
+    min_scale: f64,
+    max_scale: f64,
+    is_dragging: RwSignal<bool>,
+    last_mouse_pos: RwSignal<(f64, f64)>,
+    /// Distance the mouse moved since the button went down, tells a click from a drag.
+    drag_distance: RwSignal<f64>,
+}
+
+impl PanZoom {
+    pub fn new(container: NodeRef<Div>, min_scale: f64, max_scale: f64) -> Self {
+        Self {
+            scale: RwSignal::new(1.0),
+            offset: RwSignal::new((0.0, 0.0)),
+            container,
+            min_scale,
+            max_scale,
+            is_dragging: RwSignal::new(false),
+            last_mouse_pos: RwSignal::new((0.0, 0.0)),
+            drag_distance: RwSignal::new(0.0),
+        }
+    }
+
+    pub fn on_wheel(&self, ev: WheelEvent) {
+        ev.prevent_default();
+        let scale_factor: f64 = if ev.delta_y() > 0.0 { 0.9 } else { 1.1 };
+        let Some((mouse_x, mouse_y)) = self.container_position(&ev) else {
+            return;
+        };
+
+        let old_scale = self.scale.get();
+        let new_scale = (old_scale * scale_factor).clamp(self.min_scale, self.max_scale);
+        // keep the point under the cursor in place, also when the scale hits its limits
+        let applied_factor = new_scale / old_scale;
+        let (ox, oy) = self.offset.get();
+        self.offset.set((
+            (ox - mouse_x) * applied_factor + mouse_x,
+            (oy - mouse_y) * applied_factor + mouse_y,
+        ));
+        self.scale.set(new_scale);
+    }
+
+    pub fn on_mouse_down(&self, ev: MouseEvent) {
+        ev.prevent_default();
+        self.is_dragging.set(true);
+        self.drag_distance.set(0.0);
+        self.last_mouse_pos.set((ev.client_x() as f64, ev.client_y() as f64));
+    }
+
+    pub fn on_mouse_move(&self, ev: MouseEvent) {
+        if self.is_dragging.get() {
+            let (last_x, last_y) = self.last_mouse_pos.get();
+            let dx = ev.client_x() as f64 - last_x;
+            let dy = ev.client_y() as f64 - last_y;
+            let (ox, oy) = self.offset.get();
+            self.offset.set((ox + dx, oy + dy));
+            self.drag_distance.update(|distance| *distance += dx.hypot(dy));
+            self.last_mouse_pos.set((ev.client_x() as f64, ev.client_y() as f64));
+        }
+    }
+
+    pub fn on_mouse_up(&self) {
+        self.is_dragging.set(false);
+    }
+
+    /// Whether the last press of the mouse button moved the content, so the following click
+    /// should not count as one.
+    pub fn was_dragged(&self) -> bool {
+        self.drag_distance.get_untracked() > 4.0
+    }
+
+    /// CSS placing the content at the current offset and scale.
+    pub fn transform(&self) -> String {
+        let (ox, oy) = self.offset.get();
+        format!(
+            "transform: translate({}px, {}px) scale({}); transform-origin: 0 0;",
+            ox,
+            oy,
+            self.scale.get()
+        )
+    }
+
+    /// Position of the mouse relative to the top left of the container.
+    pub fn container_position(&self, ev: &MouseEvent) -> Option<(f64, f64)> {
+        let rect = self.container.get_untracked()?.get_bounding_client_rect();
+        Some((ev.client_x() as f64 - rect.left(), ev.client_y() as f64 - rect.top()))
+    }
+
+    /// Converts a position in the container into unscaled content coordinates.
+    pub fn to_content(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let (ox, oy) = self.offset.get_untracked();
+        let scale = self.scale.get_untracked();
+        ((x - ox) / scale, (y - oy) / scale)
+    }
+}
@@ -0,0 +1,78 @@
+use data::ImageRating;
+use gloo_net::http::Request;
+use leptos::logging::error;
+use leptos::prelude::*;
+use urlencoding::encode;
+use wasm_bindgen_futures::spawn_local;
+use web_sys::MouseEvent;
+
+const MAX_RATING: u8 = 5;
+
+/// Stars and favourite flag of an image. Clicking the current number of stars again clears the
+/// rating. Every change is saved right away.
+#[component]
+pub fn RatingControls(
+    image_id: String,
+    rating: RwSignal<Option<u8>>,
+    favourite: RwSignal<bool>,
+) -> impl IntoView {
+    let image_id = StoredValue::new(image_id);
+    let save = move || {
+        let url = format!("/images/{}/rating", encode(&image_id.get_value()));
+        let body = ImageRating {
+            rating: rating.get_untracked(),
+            favourite: favourite.get_untracked(),
+        };
+        spawn_local(async move {
+            match Request::put(&url).json(&body) {
+                Ok(request) => match request.send().await {
+                    Ok(response) if response.ok() => {}
+                    Ok(response) => error!("Saving the rating failed: {}", response.status()),
+                    Err(e) => error!("Rating request failed: {:?}", e),
+                },
+                Err(e) => error!("Failed to serialize rating: {:?}", e),
+            }
+        });
+    };
+
+    view! {
+        <span
+            style="display: inline-flex; align-items: center; gap: 0.1rem; font-size: 1rem;"
+            on:click:stop_propagation=move |_: MouseEvent| {}
+            on:mousedown=move |ev: MouseEvent| ev.stop_propagation()
+        >
+            {(1..=MAX_RATING)
+                .map(|stars| view! {
+                    <span
+                        title=format!("{stars} stars")
+                        style=move || format!(
+                            "cursor: pointer; color: {};",
+                            if rating.get().is_some_and(|rating| rating >= stars) { "#ffc107" } else { "#aaa" }
+                        )
+                        on:click=move |_| {
+                            rating.update(|rating| {
+                                *rating = if *rating == Some(stars) { None } else { Some(stars) }
+                            });
+                            save();
+                        }
+                    >
+                        "★"
+                    </span>
+                })
+                .collect_view()}
+            <span
+                title="Favourite"
+                style=move || format!(
+                    "cursor: pointer; margin-left: 0.25rem; color: {};",
+                    if favourite.get() { "#e91e63" } else { "#aaa" }
+                )
+                on:click=move |_| {
+                    favourite.update(|favourite| *favourite = !*favourite);
+                    save();
+                }
+            >
+                "♥"
+            </span>
+        </span>
+    }
+}
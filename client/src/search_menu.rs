@@ -0,0 +1,209 @@
+use crate::user::user_id;
+use data::USER_HEADER;
+use data::{HistoryEntry, SavedSearch, SavedSearchRequest, SearchParams, SessionRound};
+use gloo_net::http::Request;
+use leptos::control_flow::For;
+use leptos::logging::error;
+use leptos::prelude::*;
+use wasm_bindgen_futures::spawn_local;
+use web_sys::MouseEvent;
+
+async fn fetch_history() -> Option<Vec<HistoryEntry>> {
+    let response = Request::get("/history?limit=10")
+        .header(USER_HEADER, &user_id())
+        .send()
+        .await
+        .map_err(|e| error!("History request failed: {:?}", e))
+        .ok()?;
+    response
+        .json()
+        .await
+        .map_err(|e| error!("Failed to parse history: {:?}", e))
+        .ok()
+}
+
+async fn fetch_saved() -> Option<Vec<SavedSearch>> {
+    let response = Request::get("/saved-searches")
+        .send()
+        .await
+        .map_err(|e| error!("Saved searches request failed: {:?}", e))
+        .ok()?;
+    response
+        .json()
+        .await
+        .map_err(|e| error!("Failed to parse saved searches: {:?}", e))
+        .ok()
+}
+
+/// Sends a saved search request and reports whether the server accepted it.
+async fn send_saved(request: Result<Request, gloo_net::Error>) -> bool {
+    match request {
+        Ok(request) => match request.send().await {
+            Ok(response) => response.ok(),
+            Err(e) => {
+                error!("Saved search request failed: {:?}", e);
+                false
+            }
+        },
+        Err(e) => {
+            error!("Failed to build saved search request: {:?}", e);
+            false
+        }
+    }
+}
+
+fn label(params: &SearchParams) -> String {
+    let mut label = if params.q.trim().is_empty() { "(images only)".to_string() } else { params.q.clone() };
+    let feedback = params.referenced_images.len() + params.negative_images.len();
+    if feedback > 0 {
+        label.push_str(&format!(" +{feedback} marked"));
+    }
+    label
+}
+
+/// Dropdown under the search input listing the recent searches of this browser and the saved
+/// searches. Buttons react on mouse down, so the input keeps its focus and the menu stays open.
+#[component]
+pub fn SearchMenu(
+    open: ReadSignal<bool>,
+    round: RwSignal<Option<SessionRound>>,
+    on_select: impl Fn(SearchParams) + 'static + Copy,
+) -> impl IntoView {
+    let history = RwSignal::new(Vec::<HistoryEntry>::new());
+    let saved = RwSignal::new(Vec::<SavedSearch>::new());
+
+    let refresh = move || {
+        spawn_local(async move {
+            if let Some(entries) = fetch_history().await {
+                history.set(entries);
+            }
+            if let Some(searches) = fetch_saved().await {
+                saved.set(searches);
+            }
+        });
+    };
+    Effect::new(move |_| {
+        if open.get() {
+            refresh();
+        }
+    });
+
+    let save_current = move |ev: MouseEvent| {
+        ev.prevent_default();
+        let Some(round) = round.get_untracked() else {
+            return;
+        };
+        let Ok(Some(name)) = window().prompt_with_message("Name of the saved search") else {
+            return;
+        };
+        let body = SavedSearchRequest {
+            name,
+            params: Some(round.params),
+        };
+        spawn_local(async move {
+            if send_saved(Request::post("/saved-searches").json(&body)).await {
+                refresh();
+            }
+        });
+    };
+
+    let entry_style = "display: flex; align-items: center; gap: 0.25rem; padding: 0.25rem 0.5rem; cursor: pointer;";
+    let small_button = "padding: 0 0.4rem; border: none; border-radius: 4px; cursor: pointer; color: white; background-color: #3a3a42;";
+
+    view! {
+        <Show when=move || open.get() fallback=|| ()>
+            <div style="
+                position: absolute;
+                top: 100%;
+                left: 0;
+                right: 0;
+                max-height: 60vh;
+                overflow-y: auto;
+                background-color: #2a2a30;
+                color: white;
+                font-size: 0.9rem;
+                border-radius: 0 0 4px 4px;
+                box-shadow: 0 4px 8px rgba(0, 0, 0, 0.5);
+            ">
+                <div style="padding: 0.25rem 0.5rem; color: #aaa;">"Recent"</div>
+                <For
+                    each=move || history.get().into_iter().enumerate()
+                    key=|(index, entry)| (*index, entry.searched_at)
+                    children=move |(_, entry)| {
+                        let params = entry.params.clone();
+                        view! {
+                            <div
+                                style=entry_style
+                                on:mousedown=move |ev: MouseEvent| {
+                                    ev.prevent_default();
+                                    on_select(params.clone());
+                                }
+                            >
+                                {label(&entry.params)}
+                            </div>
+                        }
+                    }
+                />
+                <div style="display: flex; justify-content: space-between; padding: 0.25rem 0.5rem; color: #aaa;">
+                    "Saved"
+                    <button
+                        style=small_button
+                        disabled=move || round.get().is_none()
+                        on:mousedown=save_current
+                    >
+                        "Save current"
+                    </button>
+                </div>
+                <For
+                    each=move || saved.get()
+                    key=|search| (search.id.clone(), search.name.clone())
+                    children=move |search| {
+                        let params = search.params.clone();
+                        let rename_search = search.clone();
+                        let delete_id = search.id.clone();
+                        let rename = move |ev: MouseEvent| {
+                            ev.prevent_default();
+                            ev.stop_propagation();
+                            let Ok(Some(name)) = window()
+                                .prompt_with_message_and_default("New name", &rename_search.name)
+                            else {
+                                return;
+                            };
+                            let url = format!("/saved-searches/{}", rename_search.id);
+                            let body = SavedSearchRequest { name, params: None };
+                            spawn_local(async move {
+                                if send_saved(Request::put(&url).json(&body)).await {
+                                    refresh();
+                                }
+                            });
+                        };
+                        let delete = move |ev: MouseEvent| {
+                            ev.prevent_default();
+                            ev.stop_propagation();
+                            let url = format!("/saved-searches/{}", delete_id);
+                            spawn_local(async move {
+                                if send_saved(Request::delete(&url).build()).await {
+                                    refresh();
+                                }
+                            });
+                        };
+                        view! {
+                            <div
+                                style=entry_style
+                                title=label(&search.params)
+                                on:mousedown=move |ev: MouseEvent| {
+                                    ev.prevent_default();
+                                    on_select(params.clone());
+                                }
+                            >
+                                <span style="flex: 1;">{search.name.clone()}</span>
+                                <button style=small_button title="Rename" on:mousedown=rename>"✎"</button>
+                                <button style=small_button title="Delete" on:mousedown=delete>"✕"</button>
+                            </div>
+                        }
+                    }
+                />
+            </div>
+        </Show>
+    }
+}
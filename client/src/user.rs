@@ -0,0 +1,19 @@
+use leptos::prelude::window;
+
+const STORAGE_KEY: &str = "image_search_user";
+
+/// Random id of this browser, created on first use and kept in local storage. There are no
+/// accounts, it only keeps the search histories of different people apart.
+pub fn user_id() -> String {
+    let storage = window().local_storage().ok().flatten();
+    if let Some(id) = storage.as_ref().and_then(|storage| storage.get_item(STORAGE_KEY).ok().flatten()) {
+        return id;
+    }
+    let id: String = (0..4)
+        .map(|_| format!("{:08x}", (js_sys::Math::random() * u32::MAX as f64) as u32))
+        .collect();
+    if let Some(storage) = storage {
+        let _ = storage.set_item(STORAGE_KEY, &id);
+    }
+    id
+}
@@ -1,26 +1,188 @@
 use serde::{Deserialize, Serialize};
 use urlencoding::encode;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Header naming the user a search is recorded for. There are no accounts, the web client keeps
+/// a random id in local storage.
+pub const USER_HEADER: &str = "x-user-id";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
 pub struct SearchParams {
     pub q: String,
     #[serde(default)]
     pub referenced_images: Vec<String>,
+    /// Images marked as not wanted, the query moves away from their average embedding.
+    #[serde(default)]
+    pub negative_images: Vec<String>,
+    /// How the text, the referenced and the negative images are mixed into the query vector.
+    #[serde(default)]
+    pub weights: QueryWeights,
+    /// Score every stored embedding instead of using the approximate KNN index.
+    #[serde(default)]
+    pub exact: bool,
+    /// Only return images carrying all of these tags.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Return only the best matching image of each group of near-duplicates.
+    #[serde(default)]
+    pub collapse_duplicates: bool,
+    /// Number of images near the decision boundary of the query to return in `uncertain`.
+    #[serde(default)]
+    pub suggestions: usize,
+    /// Only search the images of this album.
+    #[serde(default)]
+    pub album: Option<String>,
+    /// Leave out the images of this album, to find what is still missing from it.
+    #[serde(default)]
+    pub exclude_album: Option<String>,
+    /// Only return images rated at least this many stars.
+    #[serde(default)]
+    pub min_rating: Option<u8>,
+    #[serde(default)]
+    pub favourites_only: bool,
+    /// Added to the similarity for a five star image and subtracted for a zero star one, so the
+    /// better shot wins between near ties. Unrated images count as average. `0` ranks by
+    /// similarity alone. Only used in vector mode, the other modes rank by text matches too.
+    #[serde(default)]
+    pub rating_boost: f32,
+    #[serde(default)]
+    pub mode: SearchMode,
+    /// Trade-off between relevance and variety of the top results, from `0` (ranked by relevance
+    /// only) to `1`. Around `0.3` keeps bursts of near-identical shots from filling the first
+    /// screen.
+    #[serde(default)]
+    pub diversity: f32,
+}
+/// What a search matches the query text against.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    /// CLIP similarity of the images to the text and the referenced images.
+    #[default]
+    Vector,
+    /// Words of paths, file names, captions and notes, ranked by BM25.
+    Lexical,
+    /// Both rankings merged with reciprocal rank fusion.
+    Hybrid,
+}
+/// Weights of the parts of a query. The defaults average text and referenced images, the way a
+/// search was ranked before negatives existed.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct QueryWeights {
+    pub text: f32,
+    pub positive: f32,
+    pub negative: f32,
+}
+impl Default for QueryWeights {
+    fn default() -> Self {
+        Self {
+            text: 0.5,
+            positive: 0.5,
+            negative: 0.25,
+        }
+    }
 }
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SearchResponse {
     pub images: Vec<ImageReference>,
+    /// Vector the images were ranked by, sent back to explain a result with `/heatmap`.
+    #[serde(default)]
+    pub query_vector: Vec<f32>,
+    /// Results the ranking is least sure about, marking them refines the query the most.
+    #[serde(default)]
+    pub uncertain: Vec<ImageReference>,
+}
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaKind {
+    #[default]
+    Image,
+    Video,
+}
+/// Label assigned by zero-shot tagging with the cosine similarity it was assigned with.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Tag {
+    pub label: String,
+    pub score: f32,
+}
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TagCount {
+    pub label: String,
+    pub count: usize,
+}
+/// Bounding box relative to the image size, all values in 0..1.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
 }
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ImageReference {
     pub id: String,
     pub image_path: String,
+    /// Browser-viewable JPEG for formats like HEIC or RAW that can't be shown directly.
+    #[serde(default)]
+    pub preview_path: Option<String>,
+    #[serde(default)]
+    pub kind: MediaKind,
+    /// Position in seconds of the best matching keyframe of a video.
+    #[serde(default)]
+    pub timestamp: Option<f64>,
+    /// Index of the best matching frame of an animated GIF or WebP.
+    #[serde(default)]
+    pub frame: Option<u32>,
+    /// Best matching region of an image indexed with region tiles.
+    #[serde(default)]
+    pub region: Option<Region>,
+    #[serde(default)]
+    pub tags: Vec<Tag>,
+    /// Group of near-duplicates the image belongs to, see `/duplicates`.
+    #[serde(default)]
+    pub duplicate_group: Option<String>,
+    /// Other paths of files with exactly the same contents.
+    #[serde(default)]
+    pub alternate_paths: Vec<String>,
+    /// Stars from 0 to 5 given by a user.
+    #[serde(default)]
+    pub rating: Option<u8>,
+    #[serde(default)]
+    pub favourite: bool,
+    /// Caption and notes written by a user, indexed for full-text search.
+    #[serde(default)]
+    pub caption: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
 }
 impl ImageReference {
     pub fn new(image_path: String) -> Self {
         Self {
             id: encode(&image_path).parse().unwrap(),
             image_path,
+            preview_path: None,
+            kind: MediaKind::Image,
+            timestamp: None,
+            frame: None,
+            region: None,
+            tags: Vec::new(),
+            duplicate_group: None,
+            alternate_paths: Vec::new(),
+            rating: None,
+            favourite: false,
+            caption: None,
+            notes: None,
+        }
+    }
+    /// URL the browser should load to display the image.
+    pub fn display_path(&self) -> &str {
+        self.preview_path.as_deref().unwrap_or(&self.image_path)
+    }
+    /// Like `display_path`, videos start playing at the matching keyframe.
+    pub fn media_url(&self) -> String {
+        match self.timestamp {
+            Some(timestamp) => format!("{}#t={timestamp:.1}", self.display_path()),
+            None => self.display_path().to_string(),
         }
     }
 }
@@ -50,3 +212,233 @@ pub struct ImageReferenceScore {
 pub struct ImagePathResult {
     pub image_path: String,
 }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DuplicateImage {
+    pub id: String,
+    pub image_path: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Size of the file in bytes.
+    pub file_size: Option<u64>,
+    /// Unix timestamp in seconds of the last modification of the file.
+    pub modified: Option<i64>,
+}
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DuplicateGroup {
+    pub id: String,
+    pub images: Vec<DuplicateImage>,
+}
+/// Group of similar images found by clustering the library, see `/clusters`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClusterSummary {
+    pub id: usize,
+    pub size: usize,
+    /// Labels nearest to the cluster centroid, best first.
+    pub labels: Vec<Tag>,
+    /// Paths of the images closest to the centroid.
+    pub representatives: Vec<String>,
+}
+/// Example images a concept classifier is trained on, paths in the `media/...` form.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ConceptRequest {
+    pub name: String,
+    #[serde(default)]
+    pub positives: Vec<String>,
+    #[serde(default)]
+    pub negatives: Vec<String>,
+}
+/// Examples added to a concept before it is trained again.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ConceptExamples {
+    #[serde(default)]
+    pub positives: Vec<String>,
+    #[serde(default)]
+    pub negatives: Vec<String>,
+}
+/// Saved concept, its smart album holds the `size` images the classifier accepts.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Concept {
+    pub id: String,
+    pub name: String,
+    pub positives: Vec<String>,
+    pub negatives: Vec<String>,
+    pub size: usize,
+}
+/// Image placed on the library map, coordinates in 0..1 from the top left.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MapPoint {
+    pub id: String,
+    pub image_path: String,
+    #[serde(default)]
+    pub preview_path: Option<String>,
+    pub x: f32,
+    pub y: f32,
+}
+/// Images within one tile of the map, see `/map/tiles/{zoom}/{x}/{y}`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MapTile {
+    pub zoom: u32,
+    pub x: u32,
+    pub y: u32,
+    pub points: Vec<MapPoint>,
+}
+/// Search session, the refinement rounds that led to a result set. Rounds form a tree: stepping
+/// back to a round and searching again branches from it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchSession {
+    pub id: String,
+    pub created: i64,
+    pub rounds: Vec<SessionRound>,
+}
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionRound {
+    /// Position of the round in `SearchSession::rounds`.
+    pub index: usize,
+    /// Round this one refined, `None` for a fresh query.
+    pub parent: Option<usize>,
+    pub params: SearchParams,
+    pub created: i64,
+}
+/// Body of `POST /sessions/{id}/rounds`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RoundRequest {
+    #[serde(default)]
+    pub parent: Option<usize>,
+    pub params: SearchParams,
+}
+/// Results of a session round, the same for everyone opening the round.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RoundResponse {
+    pub session: String,
+    pub round: SessionRound,
+    pub response: SearchResponse,
+}
+/// Named search that can be run again, see `/saved-searches`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SavedSearch {
+    pub id: String,
+    pub name: String,
+    pub params: SearchParams,
+    pub created: i64,
+}
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SavedSearchRequest {
+    pub name: String,
+    /// Left out when renaming.
+    #[serde(default)]
+    pub params: Option<SearchParams>,
+}
+/// Search a user ran, newest first in `/history`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    pub params: SearchParams,
+    pub searched_at: i64,
+}
+/// Curated, ordered set of images, see `/albums`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Album {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    /// Path of the cover image, the first image unless one was picked.
+    pub cover: Option<String>,
+    pub size: usize,
+    pub created: i64,
+}
+/// Creates an album, or updates name, description and cover of one.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AlbumRequest {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub cover: Option<String>,
+    /// Initial images of a new album, ignored on updates.
+    #[serde(default)]
+    pub images: Vec<String>,
+}
+/// Images added to, removed from or reordered in an album, as `media/` paths.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AlbumImages {
+    pub images: Vec<String>,
+}
+/// Body of `PUT /images/{id}/rating`, replaces both values.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct ImageRating {
+    #[serde(default)]
+    pub rating: Option<u8>,
+    #[serde(default)]
+    pub favourite: bool,
+}
+/// Body of `PUT /images/{id}/caption`, replaces both texts.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ImageCaption {
+    #[serde(default)]
+    pub caption: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+}
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HeatmapRequest {
+    pub image_id: String,
+    pub query_vector: Vec<f32>,
+    /// Number of occluded patches per row and column.
+    #[serde(default)]
+    pub grid: Option<usize>,
+}
+/// Contribution of each patch of an image to its similarity with the query, row by row and
+/// scaled to 0..1.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Heatmap {
+    pub grid: usize,
+    pub values: Vec<f32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ScanStatus {
+    pub running: bool,
+    pub elapsed_seconds: f64,
+    pub stages: Vec<StageStatus>,
+    /// Configured number of images per forward pass.
+    pub inference_batch_size: usize,
+    /// Largest batch that fits into device memory, known once a batch ran out of memory.
+    pub batch_size_limit: Option<usize>,
+    pub out_of_memory_retries: u64,
+}
+/// Throughput of one stage of the indexing pipeline.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StageStatus {
+    pub name: String,
+    pub items: u64,
+    /// Time spent working, summed over all threads of the stage.
+    pub busy_seconds: f64,
+    /// Items per second of wall-clock time since the scan started.
+    pub items_per_second: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    Decode,
+    Permission,
+    UnsupportedFormat,
+    TooLarge,
+    Io,
+}
+/// An image that could not be indexed. It is skipped by later scans until the file changes.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImageFailure {
+    pub image_path: String,
+    pub kind: FailureKind,
+    pub message: String,
+    /// Unix timestamp in seconds of the last failed attempt.
+    pub failed_at: i64,
+    pub attempts: u32,
+}
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RetryFailuresRequest {
+    /// Paths as returned by `/failures`, all failures are retried if empty.
+    #[serde(default)]
+    pub image_paths: Vec<String>,
+}
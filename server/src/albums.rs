@@ -0,0 +1,344 @@
+use crate::AppState;
+use crate::database::random_key;
+use crate::failures::{media_dir_str, unix_now};
+use crate::search::{SEARCH_LIMIT, image_references, images_in_order};
+use axum::Json;
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::StatusCode;
+use data::{Album, AlbumImages, AlbumRequest, SearchResponse};
+use log::error;
+use serde::Deserialize;
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::{RecordId, Surreal};
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+fn album_id(key: &str) -> RecordId {
+    RecordId::from(("album", key))
+}
+
+/// `current` followed by the items of `added` it doesn't contain yet.
+fn append_unique<T: PartialEq + Clone>(current: &[T], added: &[T]) -> Vec<T> {
+    let mut result = current.to_vec();
+    for item in added {
+        if !result.contains(item) {
+            result.push(item.clone());
+        }
+    }
+    result
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageId {
+    id: RecordId,
+    image_path: String,
+    alternate_paths: Vec<String>,
+}
+
+/// Record ids of the whole files behind `media/` paths, in the order of the paths. A path in the
+/// `alternate_paths` of a record resolves to that record, paths that are not indexed are left out.
+async fn resolve_images(
+    db: &Surreal<Client>,
+    paths: &[String],
+    media_dir_str: &str,
+) -> Result<Vec<RecordId>, StatusCode> {
+    let image_paths: Vec<String> = paths
+        .iter()
+        .filter(|path| path.starts_with("media/"))
+        .map(|path| path.replacen("media/", media_dir_str, 1))
+        .collect();
+    let mut response = db
+        .query(
+            "SELECT id, image_path, alternate_paths OR [] AS alternate_paths FROM image
+            WHERE (image_path IN $image_paths OR alternate_paths CONTAINSANY $image_paths) AND parent IS NONE",
+        )
+        .bind(("image_paths", image_paths.clone()))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let images: Vec<ImageId> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(image_paths
+        .iter()
+        .filter_map(|path| {
+            images
+                .iter()
+                .find(|image| image.image_path == *path || image.alternate_paths.contains(path))
+        })
+        .map(|image| image.id.clone())
+        .collect())
+}
+
+/// Images of an album in album order.
+pub async fn album_image_ids(db: &Surreal<Client>, key: &str) -> Result<Vec<RecordId>, StatusCode> {
+    let mut response = db
+        .query("SELECT VALUE images FROM ONLY $id")
+        .bind(("id", album_id(key)))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let images: Option<Vec<RecordId>> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    images.ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Changes the images of an album with `assignment`, an expression of the current `images` and
+/// `$images`. The change is a single statement, so concurrent changes don't overwrite each other.
+async fn update_album_images(
+    db: &Surreal<Client>,
+    key: &str,
+    assignment: &str,
+    images: Vec<RecordId>,
+) -> Result<(), StatusCode> {
+    db.query(format!("UPDATE $id SET images = {assignment}"))
+        .bind(("id", album_id(key)))
+        .bind(("images", images))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct AlbumSummary {
+    key: String,
+    name: String,
+    description: String,
+    cover: Option<String>,
+    size: usize,
+    created: i64,
+}
+
+const ALBUM_FIELDS: &str = "key, name, description, created, array::len(images) AS size, (cover.image_path OR images[0].image_path) AS cover";
+
+fn to_album(album: AlbumSummary, media_dir_str: &str) -> Album {
+    Album {
+        id: album.key,
+        name: album.name,
+        description: album.description,
+        cover: album.cover.map(|path| path.replace(media_dir_str, "media/")),
+        size: album.size,
+        created: album.created,
+    }
+}
+
+async fn load_album(db: &Surreal<Client>, key: &str, media_dir_str: &str) -> Result<Album, StatusCode> {
+    let mut response = db
+        .query(format!("SELECT {ALBUM_FIELDS} FROM ONLY $id"))
+        .bind(("id", album_id(key)))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let album: Option<AlbumSummary> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(to_album(album.ok_or(StatusCode::NOT_FOUND)?, media_dir_str))
+}
+
+/// The cover picked in a request, `None` keeps the first image as cover.
+async fn resolve_cover(
+    db: &Surreal<Client>,
+    cover: &Option<String>,
+    media_dir_str: &str,
+) -> Result<Option<RecordId>, StatusCode> {
+    match cover {
+        Some(cover) => Ok(resolve_images(db, std::slice::from_ref(cover), media_dir_str)
+            .await?
+            .into_iter()
+            .next()),
+        None => Ok(None),
+    }
+}
+
+/// All albums by name.
+pub async fn web_albums(State(state): State<AppState>) -> Result<Json<Vec<Album>>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let db = &state.db;
+    let mut response = db
+        .query(format!("SELECT {ALBUM_FIELDS} FROM album ORDER BY name"))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let albums: Vec<AlbumSummary> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(
+        albums
+            .into_iter()
+            .map(|album| to_album(album, &media_dir_str))
+            .collect(),
+    ))
+}
+
+pub async fn web_create_album(
+    State(state): State<AppState>,
+    Json(request): Json<AlbumRequest>,
+) -> Result<Json<Album>, StatusCode> {
+    if request.name.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let media_dir_str = media_dir_str(&state)?;
+    let key = random_key();
+    let db = &state.db;
+    let images = resolve_images(db, &request.images, &media_dir_str).await?;
+    let cover = resolve_cover(db, &request.cover, &media_dir_str).await?;
+    db.query(
+        "CREATE $id SET key = $key, name = $name, description = $description, cover = $cover,
+            images = $images, created = $created",
+    )
+    .bind(("id", album_id(&key)))
+    .bind(("key", key.clone()))
+    .bind(("name", request.name))
+    .bind(("description", request.description))
+    .bind(("cover", cover))
+    .bind(("images", append_unique(&[], &images)))
+    .bind(("created", unix_now()))
+    .await
+    .map_err(|err| {
+        error!("DB query error: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(load_album(db, &key, &media_dir_str).await?))
+}
+
+pub async fn web_album(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+) -> Result<Json<Album>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let db = &state.db;
+    Ok(Json(load_album(db, &key, &media_dir_str).await?))
+}
+
+/// Changes name, description and cover. The images are changed with the `/images` endpoints.
+pub async fn web_update_album(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+    Json(request): Json<AlbumRequest>,
+) -> Result<Json<Album>, StatusCode> {
+    if request.name.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let media_dir_str = media_dir_str(&state)?;
+    let db = &state.db;
+    load_album(db, &key, &media_dir_str).await?;
+    let cover = resolve_cover(db, &request.cover, &media_dir_str).await?;
+    db.query("UPDATE $id SET name = $name, description = $description, cover = $cover")
+        .bind(("id", album_id(&key)))
+        .bind(("name", request.name))
+        .bind(("description", request.description))
+        .bind(("cover", cover))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(load_album(db, &key, &media_dir_str).await?))
+}
+
+pub async fn web_delete_album(State(state): State<AppState>, UrlPath(key): UrlPath<String>) -> StatusCode {
+    let db = &state.db;
+    match db.query("DELETE $id").bind(("id", album_id(&key))).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(err) => {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AlbumImagesQuery {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+/// Images of an album in album order.
+pub async fn web_album_images(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+    Query(query): Query<AlbumImagesQuery>,
+) -> Result<Json<SearchResponse>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(SEARCH_LIMIT);
+    let db = &state.db;
+    let page: Vec<RecordId> = album_image_ids(db, &key)
+        .await?
+        .into_iter()
+        .skip(query.offset)
+        .take(limit)
+        .collect();
+    let images = images_in_order(db, page).await?;
+    Ok(Json(SearchResponse {
+        images: image_references(db, images, &media_dir_str).await?,
+        query_vector: Vec::new(),
+        uncertain: Vec::new(),
+    }))
+}
+
+/// Appends images to an album, images already in it keep their place.
+pub async fn web_add_album_images(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+    Json(request): Json<AlbumImages>,
+) -> Result<Json<Album>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let db = &state.db;
+    let added = resolve_images(db, &request.images, &media_dir_str).await?;
+    update_album_images(db, &key, "array::union(images, $images)", added).await?;
+    Ok(Json(load_album(db, &key, &media_dir_str).await?))
+}
+
+pub async fn web_remove_album_images(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+    Json(request): Json<AlbumImages>,
+) -> Result<Json<Album>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let db = &state.db;
+    let removed = resolve_images(db, &request.images, &media_dir_str).await?;
+    update_album_images(db, &key, "array::complement(images, $images)", removed).await?;
+    Ok(Json(load_album(db, &key, &media_dir_str).await?))
+}
+
+/// Moves the listed images to the front of the album in the given order. The other images keep
+/// their relative order after them, listed images that are not in the album are ignored.
+pub async fn web_reorder_album(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+    Json(request): Json<AlbumImages>,
+) -> Result<Json<Album>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let db = &state.db;
+    let requested = resolve_images(db, &request.images, &media_dir_str).await?;
+    update_album_images(db, &key, "array::union(array::intersect($images, images), images)", requested).await?;
+    Ok(Json(load_album(db, &key, &media_dir_str).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_unique() {
+        assert_eq!(append_unique(&["a", "b"], &["b", "c", "c"]), vec!["a", "b", "c"]);
+    }
+}
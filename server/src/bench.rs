@@ -0,0 +1,102 @@
+use crate::AppState;
+use crate::recall::{LatencySummary, summarize};
+use crate::search::search;
+use anyhow::anyhow;
+use data::SearchParams;
+use log::{info, warn};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+#[derive(Debug, Serialize)]
+struct BenchReport {
+    concurrency: usize,
+    requests: usize,
+    failed: usize,
+    seconds: f64,
+    requests_per_second: f64,
+    latency: LatencySummary,
+    cache_hits: u64,
+    cache_misses: u64,
+}
+
+/// Sends `requests` searches through the same code path as `/search`, `concurrency` of them at a
+/// time, and prints a JSON report with the latency percentiles and the text embedding cache use.
+/// Queries repeat once the file is used up, like popular searches do.
+pub async fn run_bench(
+    state: &AppState,
+    queries: &Path,
+    concurrency: usize,
+    requests: usize,
+) -> anyhow::Result<()> {
+    let lines: Arc<Vec<String>> = Arc::new(
+        std::fs::read_to_string(queries)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect(),
+    );
+    if lines.is_empty() {
+        return Err(anyhow!("no queries in {queries:?}"));
+    }
+    info!("Sending {requests} searches, {concurrency} at a time.");
+
+    let (hits_before, misses_before) = state.embedder.cache_stats();
+    let next = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let clients: Vec<_> = (0..concurrency.max(1))
+        .map(|_| {
+            let state = state.clone();
+            let lines = lines.clone();
+            let next = next.clone();
+            tokio::spawn(async move {
+                let mut latencies = Vec::new();
+                let mut failed = 0;
+                loop {
+                    let request = next.fetch_add(1, Ordering::Relaxed);
+                    if request >= requests {
+                        break;
+                    }
+                    let params = SearchParams {
+                        q: lines[request % lines.len()].clone(),
+                        ..SearchParams::default()
+                    };
+                    let sent = Instant::now();
+                    match search(&state, &state.db, params).await {
+                        Ok(_) => latencies.push(sent.elapsed().as_secs_f64() * 1000.0),
+                        Err(status) => {
+                            warn!("Search {request} failed with {status}");
+                            failed += 1;
+                        }
+                    }
+                }
+                (latencies, failed)
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::with_capacity(requests);
+    let mut failed = 0;
+    for client in clients {
+        let (client_latencies, client_failed) = client.await?;
+        latencies.extend(client_latencies);
+        failed += client_failed;
+    }
+    let seconds = started.elapsed().as_secs_f64();
+    let (hits, misses) = state.embedder.cache_stats();
+    let report = BenchReport {
+        concurrency,
+        requests,
+        failed,
+        seconds,
+        requests_per_second: if seconds > 0.0 { latencies.len() as f64 / seconds } else { 0.0 },
+        latency: summarize(latencies),
+        cache_hits: hits - hits_before,
+        cache_misses: misses - misses_before,
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
@@ -0,0 +1,485 @@
+use crate::database::load_images;
+use crate::failures::media_dir_str;
+use crate::labels::{label_index, nearest_labels};
+use crate::search::{SEARCH_LIMIT, image_references};
+use crate::vector::{dot, norm};
+use crate::{AppState, DbImage};
+use axum::Json;
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::StatusCode;
+use data::{ClusterSummary, SearchResponse, Tag};
+use log::{error, info};
+use rand::Rng;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::{RecordId, Surreal};
+
+/// Lloyd iterations of a full rebuild, the assignments rarely change after that.
+const ITERATIONS: usize = 20;
+/// Number of labels describing a cluster.
+const CLUSTER_LABELS: usize = 3;
+/// Number of images shown as the face of a cluster.
+const REPRESENTATIVES: usize = 4;
+/// Number of image assignments written per round trip.
+const ASSIGNMENT_CHUNK_SIZE: usize = 1000;
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// Unit length centroids and, for every clustered vector, its cluster and cosine distance to the
+/// centroid.
+#[derive(Debug)]
+pub struct Clustering {
+    pub centroids: Vec<Vec<f32>>,
+    pub assignments: Vec<(usize, f32)>,
+}
+
+fn normalized(vector: &[f32]) -> Vec<f32> {
+    let norm = norm(vector);
+    if norm == 0.0 {
+        return vector.to_vec();
+    }
+    vector.iter().map(|value| value / norm).collect()
+}
+
+/// Cluster of the centroid nearest to a unit vector and the cosine distance to it.
+fn nearest_centroid(vector: &[f32], centroids: &[Vec<f32>]) -> (usize, f32) {
+    centroids
+        .iter()
+        .enumerate()
+        .map(|(index, centroid)| (index, 1.0 - dot(vector, centroid)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap_or((0, 1.0))
+}
+
+/// Spherical k-means: vectors are compared by cosine distance and centroids are normalized means.
+/// Seeded with k-means++ so clusters start spread out over the library.
+pub fn kmeans<R: Rng>(vectors: &[&[f32]], k: usize, rng: &mut R) -> Clustering {
+    let points: Vec<Vec<f32>> = vectors.par_iter().map(|vector| normalized(vector)).collect();
+    let k = k.min(points.len());
+    if k == 0 {
+        return Clustering {
+            centroids: Vec::new(),
+            assignments: Vec::new(),
+        };
+    }
+
+    let mut centroids = seed_centroids(&points, k, rng);
+    let mut assignments: Vec<(usize, f32)> = Vec::new();
+    for iteration in 0..ITERATIONS {
+        let next: Vec<(usize, f32)> = points
+            .par_iter()
+            .map(|point| nearest_centroid(point, &centroids))
+            .collect();
+        let converged = next
+            .iter()
+            .map(|(cluster, _)| cluster)
+            .eq(assignments.iter().map(|(cluster, _)| cluster));
+        assignments = next;
+        if converged || iteration + 1 == ITERATIONS {
+            break;
+        }
+        centroids = mean_centroids(&points, &assignments, &centroids);
+    }
+    Clustering {
+        centroids,
+        assignments,
+    }
+}
+
+/// k-means++: every further centroid is drawn with a probability proportional to the squared
+/// distance to the nearest centroid drawn so far.
+fn seed_centroids<R: Rng>(points: &[Vec<f32>], k: usize, rng: &mut R) -> Vec<Vec<f32>> {
+    let mut centroids = vec![points[rng.random_range(0..points.len())].clone()];
+    let mut distances: Vec<f32> = points
+        .iter()
+        .map(|point| (1.0 - dot(point, &centroids[0])).max(0.0))
+        .collect();
+    while centroids.len() < k {
+        let total: f32 = distances.iter().map(|distance| distance * distance).sum();
+        let next = if total > 0.0 {
+            let mut target = rng.random::<f32>() * total;
+            distances
+                .iter()
+                .position(|distance| {
+                    target -= distance * distance;
+                    target <= 0.0
+                })
+                .unwrap_or(points.len() - 1)
+        } else {
+            rng.random_range(0..points.len())
+        };
+        let centroid = points[next].clone();
+        for (distance, point) in distances.iter_mut().zip(points) {
+            *distance = distance.min((1.0 - dot(point, &centroid)).max(0.0));
+        }
+        centroids.push(centroid);
+    }
+    centroids
+}
+
+/// Normalized mean of the members of every cluster. A cluster that lost all members keeps its
+/// previous centroid.
+fn mean_centroids(points: &[Vec<f32>], assignments: &[(usize, f32)], previous: &[Vec<f32>]) -> Vec<Vec<f32>> {
+    let dimension = points[0].len();
+    let mut sums = vec![vec![0.0; dimension]; previous.len()];
+    for (point, (cluster, _)) in points.iter().zip(assignments) {
+        for (sum, value) in sums[*cluster].iter_mut().zip(point) {
+            *sum += value;
+        }
+    }
+    sums.iter()
+        .zip(previous)
+        .map(|(sum, previous)| {
+            if sum.iter().all(|value| *value == 0.0) {
+                previous.clone()
+            } else {
+                normalized(sum)
+            }
+        })
+        .collect()
+}
+
+/// Centroid of a cluster with `size` members after adding unit vectors to it. Only the normalized
+/// centroid is stored, so the old members count as `size` copies of it. Their mean is shorter than
+/// that, which weights them a little more than recomputing the mean would. A rebuild recomputes it.
+fn absorb(centroid: &[f32], size: usize, added: &[&[f32]]) -> Vec<f32> {
+    let mut sum: Vec<f32> = centroid.iter().map(|value| value * size as f32).collect();
+    for vector in added {
+        for (sum, value) in sum.iter_mut().zip(*vector) {
+            *sum += value;
+        }
+    }
+    normalized(&sum)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Representative {
+    id: RecordId,
+    image_path: String,
+    distance: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredCluster {
+    number: usize,
+    centroid: Vec<f32>,
+    size: usize,
+    labels: Vec<Tag>,
+    representatives: Vec<Representative>,
+}
+
+fn keep_closest(representatives: &mut Vec<Representative>) {
+    representatives.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    representatives.truncate(REPRESENTATIVES);
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredEmbedding {
+    id: RecordId,
+    image_path: String,
+    embedding: Vec<f32>,
+}
+
+#[derive(Debug, Serialize)]
+struct ClusterAssignment {
+    id: RecordId,
+    cluster: usize,
+    cluster_distance: f32,
+}
+
+/// Keeps the `cluster` table and the `cluster` of every image up to date. New images are assigned
+/// to the nearest centroid and the centroids move towards them. The library is clustered from
+/// scratch if it was never clustered, `--cluster-count` changed, the library more than doubled
+/// since the last full run or `rebuild` is set. Returns the number of clusters.
+pub async fn update_clusters(
+    state: &AppState,
+    db: &Surreal<Client>,
+    rebuild: bool,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let k = state.arguments.cluster_count;
+    if k == 0 {
+        return Ok(0);
+    }
+    let mut response = db
+        .query("SELECT number, centroid, size, labels, representatives FROM cluster ORDER BY number")
+        .await?;
+    let clusters: Vec<StoredCluster> = response.take(0)?;
+    let new: Vec<StoredEmbedding> =
+        load_images(db, "id, image_path, embedding", "AND cluster IS NONE", |image: &StoredEmbedding| {
+            &image.id
+        })
+        .await?;
+
+    // a library with fewer than k images has one cluster per image
+    let clustered: usize = clusters.iter().map(|cluster| cluster.size).sum();
+    let expected = k.min(clustered + new.len());
+    if rebuild || clusters.is_empty() || clusters.len() != expected || new.len() > clustered {
+        return rebuild_clusters(state, db, k).await;
+    }
+    if new.is_empty() {
+        return Ok(clusters.len());
+    }
+    add_to_clusters(state, db, clusters, new).await
+}
+
+async fn rebuild_clusters(
+    state: &AppState,
+    db: &Surreal<Client>,
+    k: usize,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let images: Vec<StoredEmbedding> =
+        load_images(db, "id, image_path, embedding", "", |image: &StoredEmbedding| &image.id).await?;
+    let vectors: Vec<&[f32]> = images.iter().map(|image| image.embedding.as_slice()).collect();
+    let clustering = kmeans(&vectors, k, &mut rand::rng());
+    let labels = label_index(state, db).await?;
+
+    let mut clusters: Vec<StoredCluster> = clustering
+        .centroids
+        .into_iter()
+        .enumerate()
+        .map(|(number, centroid)| StoredCluster {
+            number,
+            labels: nearest_labels(&centroid, &labels, CLUSTER_LABELS),
+            centroid,
+            size: 0,
+            representatives: Vec::new(),
+        })
+        .collect();
+    for (image, (cluster, distance)) in images.iter().zip(&clustering.assignments) {
+        let cluster = &mut clusters[*cluster];
+        cluster.size += 1;
+        cluster.representatives.push(Representative {
+            id: image.id.clone(),
+            image_path: image.image_path.clone(),
+            distance: *distance,
+        });
+        if cluster.representatives.len() > 2 * REPRESENTATIVES {
+            keep_closest(&mut cluster.representatives);
+        }
+    }
+    for cluster in &mut clusters {
+        keep_closest(&mut cluster.representatives);
+    }
+
+    let cluster_count = clusters.len();
+    db.query("DELETE cluster")
+        .query("FOR $cluster IN $clusters { CREATE type::thing('cluster', $cluster.number) CONTENT $cluster; }")
+        .bind(("clusters", clusters))
+        .await?
+        .check()?;
+    store_assignments(db, &images, &clustering.assignments).await?;
+    info!("Clustered {} images into {cluster_count} clusters.", images.len());
+    Ok(cluster_count)
+}
+
+async fn add_to_clusters(
+    state: &AppState,
+    db: &Surreal<Client>,
+    mut clusters: Vec<StoredCluster>,
+    new: Vec<StoredEmbedding>,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let points: Vec<Vec<f32>> = new.par_iter().map(|image| normalized(&image.embedding)).collect();
+    let centroids: Vec<Vec<f32>> = clusters.iter().map(|cluster| cluster.centroid.clone()).collect();
+    let assignments: Vec<(usize, f32)> = points
+        .par_iter()
+        .map(|point| nearest_centroid(point, &centroids))
+        .collect();
+    let labels = label_index(state, db).await?;
+
+    for (index, cluster) in clusters.iter_mut().enumerate() {
+        let added: Vec<usize> = (0..new.len()).filter(|&image| assignments[image].0 == index).collect();
+        if added.is_empty() {
+            continue;
+        }
+        let vectors: Vec<&[f32]> = added.iter().map(|&image| points[image].as_slice()).collect();
+        cluster.centroid = absorb(&cluster.centroid, cluster.size, &vectors);
+        cluster.size += added.len();
+        cluster.labels = nearest_labels(&cluster.centroid, &labels, CLUSTER_LABELS);
+        cluster.representatives.extend(added.iter().map(|&image| Representative {
+            id: new[image].id.clone(),
+            image_path: new[image].image_path.clone(),
+            distance: assignments[image].1,
+        }));
+        keep_closest(&mut cluster.representatives);
+    }
+
+    let cluster_count = clusters.len();
+    db.query("FOR $cluster IN $clusters { UPSERT type::thing('cluster', $cluster.number) CONTENT $cluster; }")
+        .bind(("clusters", clusters))
+        .await?
+        .check()?;
+    store_assignments(db, &new, &assignments).await?;
+    info!("Added {} images to {cluster_count} clusters.", new.len());
+    Ok(cluster_count)
+}
+
+async fn store_assignments(
+    db: &Surreal<Client>,
+    images: &[StoredEmbedding],
+    assignments: &[(usize, f32)],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for (images, assignments) in images
+        .chunks(ASSIGNMENT_CHUNK_SIZE)
+        .zip(assignments.chunks(ASSIGNMENT_CHUNK_SIZE))
+    {
+        let assignments: Vec<ClusterAssignment> = images
+            .iter()
+            .zip(assignments)
+            .map(|(image, (cluster, distance))| ClusterAssignment {
+                id: image.id.clone(),
+                cluster: *cluster,
+                cluster_distance: *distance,
+            })
+            .collect();
+        db.query(
+            "FOR $assignment IN $assignments {
+                UPDATE $assignment.id SET cluster = $assignment.cluster, cluster_distance = $assignment.cluster_distance;
+            }",
+        )
+        .bind(("assignments", assignments))
+        .await?
+        .check()?;
+    }
+    Ok(())
+}
+
+/// All clusters of the library, largest first.
+pub async fn web_clusters(State(state): State<AppState>) -> Result<Json<Vec<ClusterSummary>>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let db = &state.db;
+    let mut response = db
+        .query("SELECT number, centroid, size, labels, representatives FROM cluster ORDER BY size DESC")
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let clusters: Vec<StoredCluster> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(
+        clusters
+            .into_iter()
+            .map(|cluster| ClusterSummary {
+                id: cluster.number,
+                size: cluster.size,
+                labels: cluster.labels,
+                representatives: cluster
+                    .representatives
+                    .iter()
+                    .map(|representative| representative.image_path.replace(&media_dir_str, "media/"))
+                    .collect(),
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClusterImagesQuery {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+/// Images of a cluster, closest to the centroid first. The centroid is returned as the query
+/// vector so results can be explained like search results.
+pub async fn web_cluster_images(
+    State(state): State<AppState>,
+    UrlPath(cluster): UrlPath<usize>,
+    Query(query): Query<ClusterImagesQuery>,
+) -> Result<Json<SearchResponse>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(SEARCH_LIMIT);
+    let db = &state.db;
+    let mut response = db
+        .query("SELECT VALUE centroid FROM ONLY type::thing('cluster', $cluster)")
+        .query(
+            "SELECT id, image_path, kind, cluster_distance AS distance FROM image
+            WHERE parent IS NONE AND cluster = $cluster
+            ORDER BY distance LIMIT $limit START $offset",
+        )
+        .bind(("cluster", cluster))
+        .bind(("limit", limit))
+        .bind(("offset", query.offset))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let centroid: Option<Vec<f32>> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let centroid = centroid.ok_or(StatusCode::NOT_FOUND)?;
+    let db_images: Vec<DbImage> = response.take(1).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(SearchResponse {
+        images: image_references(db, db_images, &media_dir_str).await?,
+        query_vector: centroid,
+        uncertain: Vec::new(),
+    }))
+}
+
+/// Clusters the whole library from scratch, e.g. after new images shifted the clusters.
+pub async fn web_rebuild_clusters(State(state): State<AppState>) -> Result<Json<usize>, StatusCode> {
+    if state.arguments.cluster_count == 0 {
+        error!("Clustering requested with --cluster-count 0.");
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let db = &state.db;
+    let clusters = update_clusters(&state, db, true).await.map_err(|err| {
+        error!("Failed to cluster images: {err}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(clusters))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test_kmeans() {
+        let vectors: Vec<[f32; 3]> = vec![
+            [1.0, 0.1, 0.0],
+            [0.0, 1.0, 0.1],
+            [1.0, 0.0, 0.1],
+            [0.1, 1.0, 0.0],
+            [2.0, 0.1, 0.1],
+        ];
+        let slices: Vec<&[f32]> = vectors.iter().map(|vector| vector.as_slice()).collect();
+        let clustering = kmeans(&slices, 2, &mut StdRng::seed_from_u64(7));
+        let clusters: Vec<usize> = clustering.assignments.iter().map(|(cluster, _)| *cluster).collect();
+        assert_eq!(clusters[0], clusters[2]);
+        assert_eq!(clusters[0], clusters[4]);
+        assert_eq!(clusters[1], clusters[3]);
+        assert_ne!(clusters[0], clusters[1]);
+        assert!((norm(&clustering.centroids[0]) - 1.0).abs() < 1e-6);
+        assert!(clustering.assignments.iter().all(|(_, distance)| *distance < 0.1));
+    }
+
+    #[test]
+    fn test_kmeans_more_clusters_than_vectors() {
+        let vector = [1.0, 0.0];
+        let clustering = kmeans(&[&vector[..]], 4, &mut StdRng::seed_from_u64(7));
+        assert_eq!(clustering.centroids.len(), 1);
+        assert_eq!(clustering.assignments.len(), 1);
+    }
+
+    #[test]
+    fn test_absorb() {
+        let centroid = absorb(&[1.0, 0.0], 1, &[&[0.0, 1.0]]);
+        let expected = std::f32::consts::FRAC_1_SQRT_2;
+        assert!((centroid[0] - expected).abs() < 1e-6);
+        assert!((centroid[1] - expected).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,606 @@
+use crate::database::{ImagePages, random_key};
+use crate::failures::media_dir_str;
+use crate::search::{SEARCH_LIMIT, image_references, images_in_order};
+use crate::suggestions::closest_to_boundary;
+use crate::vector::{dot, norm};
+use crate::{AppState, DbImage};
+use axum::Json;
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::StatusCode;
+use data::{Concept, ConceptExamples, ConceptRequest, SearchResponse};
+use log::{error, info};
+use rayon::iter::{IntoParallelIterator, ParallelExtend, ParallelIterator};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::{RecordId, Surreal};
+
+const EPOCHS: usize = 300;
+const LEARNING_RATE: f32 = 0.5;
+const L2_PENALTY: f32 = 1e-3;
+/// Random library images added as negatives, so a concept can be learned from positives alone.
+const BACKGROUND_NEGATIVES: usize = 200;
+/// Images scoring at least this probability belong to the concept.
+const MEMBER_THRESHOLD: f32 = 0.5;
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// Logistic regression on normalized CLIP embeddings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinearModel {
+    pub weights: Vec<f32>,
+    pub bias: f32,
+}
+
+fn sigmoid(value: f32) -> f32 {
+    1.0 / (1.0 + (-value).exp())
+}
+
+fn normalized(vector: &[f32]) -> Vec<f32> {
+    let norm = norm(vector);
+    if norm == 0.0 {
+        return vector.to_vec();
+    }
+    vector.iter().map(|value| value / norm).collect()
+}
+
+impl LinearModel {
+    /// Probability that an image shows the concept.
+    pub fn score(&self, embedding: &[f32]) -> f32 {
+        if embedding.len() != self.weights.len() {
+            return 0.0;
+        }
+        let norm = norm(embedding);
+        let activation = if norm == 0.0 { 0.0 } else { dot(&self.weights, embedding) / norm };
+        sigmoid(activation + self.bias)
+    }
+}
+
+/// Trains the classifier by gradient descent. Both classes weigh the same however many examples
+/// they have. The embeddings are centred and scaled for training and the model is folded back so
+/// it scores raw embeddings.
+pub fn train(positives: &[&[f32]], negatives: &[&[f32]]) -> LinearModel {
+    let dimension = positives.iter().chain(negatives).map(|vector| vector.len()).next().unwrap_or(0);
+    let examples: Vec<(Vec<f32>, f32, f32)> = positives
+        .iter()
+        .map(|vector| (normalized(vector), 1.0, 0.5 / positives.len() as f32))
+        .chain(
+            negatives
+                .iter()
+                .map(|vector| (normalized(vector), 0.0, 0.5 / negatives.len() as f32)),
+        )
+        .filter(|(vector, _, _)| vector.len() == dimension)
+        .collect();
+    let mut mean = vec![0.0; dimension];
+    for (vector, _, _) in &examples {
+        for (mean, value) in mean.iter_mut().zip(vector) {
+            *mean += value / examples.len() as f32;
+        }
+    }
+    let scale = (dimension as f32).sqrt();
+    let features: Vec<Vec<f32>> = examples
+        .iter()
+        .map(|(vector, _, _)| vector.iter().zip(&mean).map(|(value, mean)| (value - mean) * scale).collect())
+        .collect();
+
+    let mut weights = vec![0.0; dimension];
+    let mut bias = 0.0;
+    for _ in 0..EPOCHS {
+        let mut weight_gradient = vec![0.0; dimension];
+        let mut bias_gradient = 0.0;
+        for (feature, (_, label, example_weight)) in features.iter().zip(&examples) {
+            let error = (sigmoid(dot(&weights, feature) + bias) - label) * example_weight;
+            for (gradient, value) in weight_gradient.iter_mut().zip(feature) {
+                *gradient += error * value;
+            }
+            bias_gradient += error;
+        }
+        for (weight, gradient) in weights.iter_mut().zip(&weight_gradient) {
+            *weight -= LEARNING_RATE * (gradient + L2_PENALTY * *weight);
+        }
+        bias -= LEARNING_RATE * bias_gradient;
+    }
+
+    let weights: Vec<f32> = weights.iter().map(|weight| weight * scale).collect();
+    LinearModel {
+        bias: bias - dot(&weights, &mean),
+        weights,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Member {
+    id: RecordId,
+    score: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredConcept {
+    /// Key of the record, identifies the concept in the URLs.
+    key: String,
+    name: String,
+    positives: Vec<String>,
+    negatives: Vec<String>,
+    model: LinearModel,
+    /// Images scoring above [`MEMBER_THRESHOLD`], best first.
+    members: Vec<Member>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConceptSummary {
+    key: String,
+    name: String,
+    positives: Vec<String>,
+    negatives: Vec<String>,
+    size: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConceptModel {
+    id: RecordId,
+    model: LinearModel,
+    members: Vec<Member>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredEmbedding {
+    id: RecordId,
+    image_path: String,
+    embedding: Vec<f32>,
+}
+
+fn sort_members(members: &mut [Member]) {
+    members.sort_by(|a, b| b.score.total_cmp(&a.score));
+}
+
+/// Pages of parent images with their embeddings, ordered by id.
+fn embedding_pages<'a>(
+    db: &'a Surreal<Client>,
+    filter: &'a str,
+) -> ImagePages<'a, StoredEmbedding, impl Fn(&StoredEmbedding) -> &RecordId> {
+    ImagePages::new(db, "id, image_path, embedding", filter, |image: &StoredEmbedding| &image.id)
+}
+
+/// Scores library images with every model, page by page. With `only_unscored` only images
+/// without `concepts_scored` are scored and then marked with it, those have been scored by every
+/// concept. Training a single concept leaves the flag alone, so new images it scores still reach
+/// the other concepts. Returns the members of each model.
+async fn score_images(
+    db: &Surreal<Client>,
+    models: &[&LinearModel],
+    only_unscored: bool,
+) -> Result<Vec<Vec<Member>>, Box<dyn Error + Send + Sync>> {
+    let filter = if only_unscored { "AND concepts_scored IS NONE" } else { "" };
+    let mut members = vec![Vec::new(); models.len()];
+    let mut pages = embedding_pages(db, filter);
+    while let Some(page) = pages.next_page().await? {
+        let ids: Vec<RecordId> = page.iter().map(|image| image.id.clone()).collect();
+        let scores: Vec<Vec<f32>> = page
+            .into_par_iter()
+            .map(|image| models.iter().map(|model| model.score(&image.embedding)).collect())
+            .collect();
+        for (id, scores) in ids.iter().zip(scores) {
+            for (members, score) in members.iter_mut().zip(scores) {
+                if score >= MEMBER_THRESHOLD {
+                    members.push(Member { id: id.clone(), score });
+                }
+            }
+        }
+        if only_unscored {
+            db.query("UPDATE image SET concepts_scored = true WHERE id IN $ids")
+                .bind(("ids", ids))
+                .await?
+                .check()?;
+        }
+    }
+    for members in &mut members {
+        sort_members(members);
+    }
+    Ok(members)
+}
+
+/// Library images whose score is closest to the decision boundary of the classifier, leaving out
+/// its examples. The user's verdict on these teaches the classifier the most.
+async fn uncertain_images(
+    db: &Surreal<Client>,
+    model: &LinearModel,
+    examples: &HashSet<String>,
+    count: usize,
+) -> Result<Vec<Member>, surrealdb::Error> {
+    let mut scored: Vec<(RecordId, f32)> = Vec::new();
+    let mut pages = embedding_pages(db, "");
+    while let Some(page) = pages.next_page().await? {
+        scored.par_extend(
+            page.into_par_iter()
+                .filter(|image| !examples.contains(&image.image_path))
+                .map(|image| (image.id, model.score(&image.embedding))),
+        );
+    }
+    let scores: Vec<f32> = scored.iter().map(|(_, score)| *score).collect();
+    Ok(closest_to_boundary(&scores, MEMBER_THRESHOLD, count)
+        .into_iter()
+        .map(|index| Member {
+            id: scored[index].0.clone(),
+            score: scored[index].1,
+        })
+        .collect())
+}
+
+/// Adds the images of the last scan to every concept they score high enough for.
+/// Returns the number of concepts.
+pub async fn score_new_images(db: &Surreal<Client>) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let mut response = db.query("SELECT id, model, members FROM concept").await?;
+    let mut concepts: Vec<ConceptModel> = response.take(0)?;
+    if concepts.is_empty() {
+        return Ok(0);
+    }
+    let models: Vec<&LinearModel> = concepts.iter().map(|concept| &concept.model).collect();
+    let new_members = score_images(db, &models, true).await?;
+    for (concept, new_members) in concepts.iter_mut().zip(new_members) {
+        let new_ids: HashSet<&RecordId> = new_members.iter().map(|member| &member.id).collect();
+        concept.members.retain(|member| !new_ids.contains(&member.id));
+        concept.members.extend(new_members);
+        sort_members(&mut concept.members);
+    }
+    for concept in &concepts {
+        // a concept retrained meanwhile has scored the whole library with its new model already,
+        // so the members are only written if the model is still the one they were scored with
+        db.query("UPDATE $id SET members = $members WHERE model = $model")
+            .bind(("id", concept.id.clone()))
+            .bind(("members", concept.members.clone()))
+            .bind(("model", concept.model.clone()))
+            .await?
+            .check()?;
+    }
+    Ok(concepts.len())
+}
+
+/// Embeddings of the indexed images at `image_paths`, also found by their alternate paths.
+async fn example_embeddings(
+    db: &Surreal<Client>,
+    image_paths: Vec<String>,
+) -> Result<Vec<Vec<f32>>, surrealdb::Error> {
+    let mut response = db
+        .query(
+            "SELECT VALUE embedding FROM image
+            WHERE parent IS NONE AND (image_path IN $image_paths OR alternate_paths CONTAINSANY $image_paths)",
+        )
+        .bind(("image_paths", image_paths))
+        .await?;
+    response.take(0)
+}
+
+/// Trains the concept on its examples plus random library images as negatives, scores the whole
+/// library and stores everything under `id`.
+async fn train_concept(
+    db: &Surreal<Client>,
+    key: String,
+    name: String,
+    positives: Vec<String>,
+    negatives: Vec<String>,
+) -> Result<usize, StatusCode> {
+    let positive_embeddings = example_embeddings(db, positives.clone()).await.map_err(|err| {
+        error!("DB query error: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if positive_embeddings.is_empty() {
+        error!("Concept {name} has no indexed positive examples.");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let mut negative_embeddings = example_embeddings(db, negatives.clone()).await.map_err(|err| {
+        error!("DB query error: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let examples: Vec<String> = positives.iter().chain(&negatives).cloned().collect();
+    let mut response = db
+        .query(
+            "SELECT VALUE embedding FROM image
+            WHERE parent IS NONE AND image_path NOTINSIDE $examples
+            ORDER BY rand() LIMIT $limit",
+        )
+        .bind(("examples", examples))
+        .bind(("limit", BACKGROUND_NEGATIVES))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let background: Vec<Vec<f32>> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    negative_embeddings.extend(background);
+
+    let positive_slices: Vec<&[f32]> = positive_embeddings.iter().map(Vec::as_slice).collect();
+    let negative_slices: Vec<&[f32]> = negative_embeddings.iter().map(Vec::as_slice).collect();
+    let model = train(&positive_slices, &negative_slices);
+    let members = score_images(db, &[&model], false)
+        .await
+        .map_err(|err| {
+            error!("Failed to score images: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .remove(0);
+    let size = members.len();
+    info!("Concept {name} matches {size} images.");
+
+    let concept = StoredConcept {
+        key: key.clone(),
+        name,
+        positives,
+        negatives,
+        model,
+        members,
+    };
+    db.query("UPSERT $id CONTENT $concept")
+        .bind(("id", concept_id(&key)))
+        .bind(("concept", concept))
+        .await
+        .and_then(|response| response.check())
+        .map_err(|err| {
+            error!("Failed to store concept: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(size)
+}
+
+fn concept_id(key: &str) -> RecordId {
+    RecordId::from(("concept", key))
+}
+
+fn absolute_paths(image_paths: &[String], media_dir_str: &str) -> Vec<String> {
+    image_paths
+        .iter()
+        .map(|path| path.replacen("media/", media_dir_str, 1))
+        .collect()
+}
+
+async fn load_concept(db: &Surreal<Client>, id: RecordId, media_dir_str: &str) -> Result<Concept, StatusCode> {
+    let mut response = db
+        .query("SELECT key, name, positives, negatives, array::len(members) AS size FROM ONLY $id")
+        .bind(("id", id))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let concept: Option<ConceptSummary> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(to_concept(concept.ok_or(StatusCode::NOT_FOUND)?, media_dir_str))
+}
+
+fn to_concept(concept: ConceptSummary, media_dir_str: &str) -> Concept {
+    let relative = |paths: Vec<String>| -> Vec<String> {
+        paths
+            .into_iter()
+            .map(|path| path.replace(media_dir_str, "media/"))
+            .collect()
+    };
+    Concept {
+        id: concept.key,
+        name: concept.name,
+        positives: relative(concept.positives),
+        negatives: relative(concept.negatives),
+        size: concept.size,
+    }
+}
+
+/// All saved concepts.
+pub async fn web_concepts(State(state): State<AppState>) -> Result<Json<Vec<Concept>>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let db = &state.db;
+    let mut response = db
+        .query("SELECT key, name, positives, negatives, array::len(members) AS size FROM concept ORDER BY name")
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let concepts: Vec<ConceptSummary> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(
+        concepts
+            .into_iter()
+            .map(|concept| to_concept(concept, &media_dir_str))
+            .collect(),
+    ))
+}
+
+/// Saves a new concept from example images and trains it.
+pub async fn web_create_concept(
+    State(state): State<AppState>,
+    Json(request): Json<ConceptRequest>,
+) -> Result<Json<Concept>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let key = random_key();
+    let id = concept_id(&key);
+    let db = &state.db;
+    train_concept(
+        db,
+        key,
+        request.name,
+        absolute_paths(&request.positives, &media_dir_str),
+        absolute_paths(&request.negatives, &media_dir_str),
+    )
+    .await?;
+    Ok(Json(load_concept(db, id, &media_dir_str).await?))
+}
+
+pub async fn web_concept(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+) -> Result<Json<Concept>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let db = &state.db;
+    Ok(Json(load_concept(db, concept_id(&key), &media_dir_str).await?))
+}
+
+/// Replaces name and examples of a concept and trains it again.
+pub async fn web_update_concept(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+    Json(request): Json<ConceptRequest>,
+) -> Result<Json<Concept>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let id = concept_id(&key);
+    let db = &state.db;
+    load_concept(db, id.clone(), &media_dir_str).await?;
+    train_concept(
+        db,
+        key,
+        request.name,
+        absolute_paths(&request.positives, &media_dir_str),
+        absolute_paths(&request.negatives, &media_dir_str),
+    )
+    .await?;
+    Ok(Json(load_concept(db, id, &media_dir_str).await?))
+}
+
+pub async fn web_delete_concept(State(state): State<AppState>, UrlPath(key): UrlPath<String>) -> StatusCode {
+    let db = &state.db;
+    match db.query("DELETE $id").bind(("id", concept_id(&key))).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(err) => {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Adds examples to a concept, if any, and trains it again with fresh background negatives.
+pub async fn web_retrain_concept(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+    Json(examples): Json<ConceptExamples>,
+) -> Result<Json<Concept>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let id = concept_id(&key);
+    let db = &state.db;
+    let concept = load_concept(db, id.clone(), &media_dir_str).await?;
+    let merge = |mut current: Vec<String>, added: Vec<String>| {
+        for path in added {
+            if !current.contains(&path) {
+                current.push(path);
+            }
+        }
+        absolute_paths(&current, &media_dir_str)
+    };
+    train_concept(
+        db,
+        key,
+        concept.name,
+        merge(concept.positives, examples.positives),
+        merge(concept.negatives, examples.negatives),
+    )
+    .await?;
+    Ok(Json(load_concept(db, id, &media_dir_str).await?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConceptImagesQuery {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default)]
+    limit: Option<usize>,
+    /// Number of images near the decision boundary to return in `uncertain`.
+    #[serde(default)]
+    suggestions: usize,
+}
+
+/// Images of the smart album of a concept, best scoring first. The classifier weights are returned
+/// as the query vector, they point in the direction of the concept. With `suggestions` the images
+/// the classifier is least sure about are returned as well, to be added as examples.
+pub async fn web_concept_images(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+    Query(query): Query<ConceptImagesQuery>,
+) -> Result<Json<SearchResponse>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(SEARCH_LIMIT);
+    let db = &state.db;
+    let mut response = db
+        .query("SELECT model, positives, negatives FROM ONLY $id")
+        .query("SELECT VALUE array::slice(members, $offset, $limit) FROM ONLY $id")
+        .bind(("id", concept_id(&key)))
+        .bind(("offset", query.offset))
+        .bind(("limit", limit))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let concept: Option<ConceptExamplesModel> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let concept = concept.ok_or(StatusCode::NOT_FOUND)?;
+    let members: Option<Vec<Member>> = response.take(1).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let mut members = members.unwrap_or_default();
+
+    let uncertain = if query.suggestions == 0 {
+        Vec::new()
+    } else {
+        let examples: HashSet<String> = concept.positives.into_iter().chain(concept.negatives).collect();
+        uncertain_images(db, &concept.model, &examples, query.suggestions)
+            .await
+            .map_err(|err| {
+                error!("DB query error: {:?}", err);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?
+    };
+
+    // suggested images are shown once, in their own grid
+    members.retain(|member| !uncertain.iter().any(|suggested| suggested.id == member.id));
+    let images = scored_images(db, members).await?;
+    let uncertain = scored_images(db, uncertain).await?;
+    Ok(Json(SearchResponse {
+        images: image_references(db, images, &media_dir_str).await?,
+        query_vector: concept.model.weights,
+        uncertain: image_references(db, uncertain, &media_dir_str).await?,
+    }))
+}
+
+/// The images of `members` in the same order, with `1 - score` as distance so the client sees
+/// how sure the classifier is.
+async fn scored_images(db: &Surreal<Client>, members: Vec<Member>) -> Result<Vec<DbImage>, StatusCode> {
+    let scores: HashMap<RecordId, f32> = members
+        .iter()
+        .map(|member| (member.id.clone(), member.score))
+        .collect();
+    let mut images = images_in_order(db, members.into_iter().map(|member| member.id).collect()).await?;
+    for image in &mut images {
+        image.distance = scores.get(&image.id).map_or(1.0, |score| 1.0 - score);
+    }
+    Ok(images)
+}
+
+#[derive(Debug, Deserialize)]
+struct ConceptExamplesModel {
+    model: LinearModel,
+    positives: Vec<String>,
+    negatives: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_train() {
+        let positives: Vec<[f32; 3]> = vec![[1.0, 0.2, 0.0], [0.9, 0.0, 0.1], [1.0, 0.1, 0.1]];
+        let negatives: Vec<[f32; 3]> = vec![[0.0, 1.0, 0.1], [0.1, 0.9, 0.0], [0.0, 0.2, 1.0], [0.1, 0.0, 1.0]];
+        let positive_slices: Vec<&[f32]> = positives.iter().map(|vector| vector.as_slice()).collect();
+        let negative_slices: Vec<&[f32]> = negatives.iter().map(|vector| vector.as_slice()).collect();
+        let model = train(&positive_slices, &negative_slices);
+        assert!(model.score(&[2.0, 0.1, 0.0]) > 0.9);
+        assert!(model.score(&[0.0, 1.0, 1.0]) < 0.1);
+        assert_eq!(model.score(&[1.0, 0.0]), 0.0);
+    }
+}
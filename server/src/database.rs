@@ -1,8 +1,18 @@
+use crate::history::define_history_index;
+use crate::map::define_map_index;
 use crate::server_arguments::ServerArguments;
+use crate::text_search::define_text_index;
 use log::info;
+use rand::Rng;
+use rand::distr::Alphanumeric;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
 use surrealdb::engine::remote::ws::{Client, Ws};
 use surrealdb::opt::auth::Root;
-use surrealdb::{Error, Surreal};
+use surrealdb::{Error, RecordId, Surreal};
+
+/// Rows fetched per round trip by `load_images` and `ImagePages`.
+const LOAD_PAGE_SIZE: usize = 1000;
 
 pub async fn init_database(cla: &ServerArguments) -> Result<Surreal<Client>, Error> {
     let surrealdb = Surreal::new::<Ws>(cla.surrealdb_uri.clone()).await?;
@@ -17,6 +27,85 @@ pub async fn init_database(cla: &ServerArguments) -> Result<Surreal<Client>, Err
         .use_db(&cla.surrealdb_database)
         .await
         .unwrap();
+    define_text_index(&surrealdb).await?;
+    define_map_index(&surrealdb).await?;
+    define_history_index(&surrealdb).await?;
     info!("SurrealDB initialized");
     Ok(surrealdb)
 }
+
+/// Random 20 character key of a new record, used where the key ends up in links.
+pub fn random_key() -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(20)
+        .map(char::from)
+        .collect()
+}
+
+/// Selects `fields` of every whole image matching `filter`, e.g. `AND cluster IS NONE`, in pages
+/// ordered by id, so a large library is not sent in a single response. `fields` has to include
+/// the id that `id` returns.
+pub async fn load_images<T: DeserializeOwned>(
+    db: &Surreal<Client>,
+    fields: &str,
+    filter: &str,
+    id: impl Fn(&T) -> &RecordId,
+) -> Result<Vec<T>, Error> {
+    let mut pages = ImagePages::new(db, fields, filter, id);
+    let mut images = Vec::new();
+    while let Some(page) = pages.next_page().await? {
+        images.extend(page);
+    }
+    Ok(images)
+}
+
+/// The pages `load_images` collects, for callers that store something for each page before
+/// fetching the next. Paging by id keeps working when that update removes the page from `filter`.
+pub struct ImagePages<'a, T, F> {
+    db: &'a Surreal<Client>,
+    fields: &'a str,
+    filter: &'a str,
+    id: F,
+    last_id: Option<RecordId>,
+    finished: bool,
+    images: PhantomData<T>,
+}
+
+impl<'a, T: DeserializeOwned, F: Fn(&T) -> &RecordId> ImagePages<'a, T, F> {
+    pub fn new(db: &'a Surreal<Client>, fields: &'a str, filter: &'a str, id: F) -> Self {
+        ImagePages {
+            db,
+            fields,
+            filter,
+            id,
+            last_id: None,
+            finished: false,
+            images: PhantomData,
+        }
+    }
+
+    /// The next page of at most `LOAD_PAGE_SIZE` images, `None` after the last one.
+    pub async fn next_page(&mut self) -> Result<Option<Vec<T>>, Error> {
+        if self.finished {
+            return Ok(None);
+        }
+        let (fields, filter) = (self.fields, self.filter);
+        let after = if self.last_id.is_some() { "AND id > $last" } else { "" };
+        let mut response = self
+            .db
+            .query(format!(
+                "SELECT {fields} FROM image WHERE parent IS NONE {filter} {after} ORDER BY id LIMIT $limit"
+            ))
+            .bind(("last", self.last_id.clone()))
+            .bind(("limit", LOAD_PAGE_SIZE))
+            .await?;
+        let page: Vec<T> = response.take(0)?;
+        self.finished = page.len() < LOAD_PAGE_SIZE;
+        match page.last() {
+            Some(image) => self.last_id = Some((self.id)(image).clone()),
+            None => return Ok(None),
+        }
+        Ok(Some(page))
+    }
+}
@@ -0,0 +1,327 @@
+use image::codecs::gif::GifDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+use image::{AnimationDecoder, DynamicImage, Frames, ImageError, ImageFormat, ImageReader};
+use std::fs::File;
+use std::io::{BufReader, Cursor};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Decodes one family of file formats into an image the vision model can embed.
+pub trait Decoder: Send + Sync {
+    /// Lower case file extensions handled by this decoder.
+    fn extensions(&self) -> &[&'static str];
+    fn decode(&self, path: &Path) -> Result<DynamicImage, ImageError>;
+    /// Whether browsers can display the original file, otherwise a JPEG derivative is served.
+    fn browser_viewable(&self) -> bool;
+}
+
+/// Everything the `image` crate decodes on its own.
+struct ImageCrateDecoder {
+    extensions: Vec<&'static str>,
+}
+
+impl Decoder for ImageCrateDecoder {
+    fn extensions(&self) -> &[&'static str] {
+        &self.extensions
+    }
+
+    fn decode(&self, path: &Path) -> Result<DynamicImage, ImageError> {
+        image::open(path)
+    }
+
+    fn browser_viewable(&self) -> bool {
+        true
+    }
+}
+
+/// Camera RAW files. Instead of developing the sensor data the largest embedded JPEG preview
+/// is used, which every camera writes and which is good enough for embedding and display.
+struct RawPreviewDecoder;
+
+impl Decoder for RawPreviewDecoder {
+    fn extensions(&self) -> &[&'static str] {
+        &["cr2", "nef", "arw", "dng"]
+    }
+
+    fn decode(&self, path: &Path) -> Result<DynamicImage, ImageError> {
+        let bytes = std::fs::read(path)?;
+        largest_embedded_jpeg(&bytes)
+    }
+
+    fn browser_viewable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(feature = "heif")]
+struct HeifDecoder;
+
+#[cfg(feature = "heif")]
+impl Decoder for HeifDecoder {
+    fn extensions(&self) -> &[&'static str] {
+        &["heic", "heif"]
+    }
+
+    fn decode(&self, path: &Path) -> Result<DynamicImage, ImageError> {
+        use image::error::DecodingError;
+        use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+        let heif_error = |err: libheif_rs::HeifError| {
+            ImageError::Decoding(DecodingError::new(ImageFormatHint::Name("HEIF".to_string()), err))
+        };
+        let path = path.to_str().ok_or_else(|| {
+            ImageError::Decoding(DecodingError::new(
+                ImageFormatHint::Name("HEIF".to_string()),
+                "path is not valid UTF-8",
+            ))
+        })?;
+        let lib_heif = LibHeif::new();
+        let context = HeifContext::read_from_file(path).map_err(heif_error)?;
+        let handle = context.primary_image_handle().map_err(heif_error)?;
+        let decoded = lib_heif
+            .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
+            .map_err(heif_error)?;
+        let planes = decoded.planes();
+        let plane = planes.interleaved.ok_or_else(|| {
+            ImageError::Decoding(DecodingError::new(
+                ImageFormatHint::Name("HEIF".to_string()),
+                "decoded image has no interleaved RGB plane",
+            ))
+        })?;
+
+        let row_len = plane.width as usize * 3;
+        let mut pixels = Vec::with_capacity(row_len * plane.height as usize);
+        for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+            pixels.extend_from_slice(&row[..row_len]);
+        }
+        image::RgbImage::from_raw(plane.width, plane.height, pixels)
+            .map(DynamicImage::ImageRgb8)
+            .ok_or_else(|| {
+                ImageError::Decoding(DecodingError::new(
+                    ImageFormatHint::Name("HEIF".to_string()),
+                    "decoded plane is smaller than its dimensions",
+                ))
+            })
+    }
+
+    fn browser_viewable(&self) -> bool {
+        false
+    }
+}
+
+fn decoders() -> &'static [Box<dyn Decoder>] {
+    static DECODERS: OnceLock<Vec<Box<dyn Decoder>>> = OnceLock::new();
+    DECODERS.get_or_init(|| {
+        #[allow(unused_mut)]
+        let mut extensions = vec!["jpg", "jpeg", "png", "gif", "bmp", "webp", "tiff"];
+        // decoding AVIF needs dav1d, the `image` crate handles it once the feature is enabled
+        #[cfg(feature = "avif")]
+        extensions.push("avif");
+
+        #[allow(unused_mut)]
+        let mut decoders: Vec<Box<dyn Decoder>> = vec![
+            Box::new(ImageCrateDecoder { extensions }),
+            Box::new(RawPreviewDecoder),
+        ];
+        #[cfg(feature = "heif")]
+        decoders.push(Box::new(HeifDecoder));
+        decoders
+    })
+}
+
+pub fn decoder_for(path: &Path) -> Option<&'static dyn Decoder> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    decoders()
+        .iter()
+        .find(|decoder| decoder.extensions().contains(&extension.as_str()))
+        .map(|decoder| decoder.as_ref())
+}
+
+pub fn is_supported(path: &Path) -> bool {
+    decoder_for(path).is_some()
+}
+
+/// Whether the file has to be converted before the browser can show it.
+pub fn needs_derivative(path: &Path) -> bool {
+    decoder_for(path).is_some_and(|decoder| !decoder.browser_viewable())
+}
+
+pub fn open(path: &Path) -> Result<DynamicImage, ImageError> {
+    match decoder_for(path) {
+        Some(decoder) => decoder.decode(path),
+        None => Err(ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+            ImageFormatHint::PathExtension(path.to_path_buf()),
+            UnsupportedErrorKind::Format(ImageFormatHint::PathExtension(path.to_path_buf())),
+        ))),
+    }
+}
+
+/// Frames embedded per animated image, spread evenly over the animation.
+const ANIMATION_FRAMES: usize = 8;
+/// Longer animations are only sampled from their beginning.
+const MAX_DECODED_FRAMES: usize = 1000;
+/// Decoded frames are shrunk right away, the vision model only needs 224 pixels.
+const FRAME_SIZE: u32 = 448;
+
+/// Samples frames of an animated GIF or WebP with their frame index. Returns `None` for files
+/// that are not animated, those are decoded with [`open`] as usual.
+pub fn animation_frames(path: &Path) -> Result<Option<Vec<(u32, DynamicImage)>>, ImageError> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+    let frames = match extension.as_deref() {
+        Some("gif") => GifDecoder::new(BufReader::new(File::open(path)?))?.into_frames(),
+        Some("webp") => {
+            let decoder = WebPDecoder::new(BufReader::new(File::open(path)?))?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+    let frames = decode_frames(frames)?;
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+    let mut frames: Vec<Option<(usize, DynamicImage)>> = frames.into_iter().map(Some).collect();
+    Ok(Some(
+        sample_evenly(frames.len(), ANIMATION_FRAMES)
+            .into_iter()
+            .filter_map(|position| {
+                let (index, frame) = frames[position].take()?;
+                Some((index as u32, frame))
+            })
+            .collect(),
+    ))
+}
+
+/// Decodes the frames and keeps at most `2 * ANIMATION_FRAMES + 1` of them, evenly spaced, with
+/// their frame index. Frames have to be decoded in order, the ones not kept are dropped right away.
+fn decode_frames(frames: Frames) -> Result<Vec<(usize, DynamicImage)>, ImageError> {
+    thin_out(frames.take(MAX_DECODED_FRAMES), ANIMATION_FRAMES, |frame| {
+        DynamicImage::ImageRgba8(frame.into_buffer()).thumbnail(FRAME_SIZE, FRAME_SIZE)
+    })
+}
+
+/// Keeps every `stride`-th item, converted with `keep`, without knowing the number of items in
+/// advance. Whenever more than `2 * count` items are held the stride doubles and every other held
+/// item is dropped, so the kept items stay evenly spaced over everything seen so far.
+fn thin_out<T, U, E>(
+    items: impl Iterator<Item = Result<T, E>>,
+    count: usize,
+    mut keep: impl FnMut(T) -> U,
+) -> Result<Vec<(usize, U)>, E> {
+    let mut stride = 1;
+    let mut kept: Vec<(usize, U)> = Vec::new();
+    for (index, item) in items.enumerate() {
+        let item = item?;
+        if index % stride != 0 {
+            continue;
+        }
+        kept.push((index, keep(item)));
+        if kept.len() > 2 * count.max(1) {
+            stride *= 2;
+            kept.retain(|(index, _)| index % stride == 0);
+        }
+    }
+    Ok(kept)
+}
+
+/// Indices of `count` items spread evenly over `len` items, including the first one.
+fn sample_evenly(len: usize, count: usize) -> Vec<usize> {
+    if len <= count {
+        return (0..len).collect();
+    }
+    (0..count).map(|i| i * len / count).collect()
+}
+
+/// Finds all JPEG streams in `bytes` and decodes the one with the most pixels. Streams that
+/// can't be decoded, such as lossless JPEG sensor data in DNG files, are skipped.
+fn largest_embedded_jpeg(bytes: &[u8]) -> Result<DynamicImage, ImageError> {
+    let mut candidates: Vec<(u64, usize)> = bytes
+        .windows(3)
+        .enumerate()
+        .filter(|(_, window)| *window == [0xFF, 0xD8, 0xFF])
+        .filter_map(|(start, _)| {
+            let reader = ImageReader::with_format(Cursor::new(&bytes[start..]), ImageFormat::Jpeg);
+            let (width, height) = reader.into_dimensions().ok()?;
+            Some((width as u64 * height as u64, start))
+        })
+        .collect();
+    candidates.sort_by_key(|&(pixels, _)| std::cmp::Reverse(pixels));
+
+    for (_, start) in candidates {
+        if let Ok(image) = image::load_from_memory_with_format(&bytes[start..], ImageFormat::Jpeg) {
+            return Ok(image);
+        }
+    }
+    Err(ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+        ImageFormatHint::Name("RAW".to_string()),
+        UnsupportedErrorKind::GenericFeature("RAW file without decodable JPEG preview".to_string()),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_is_supported() {
+        assert!(is_supported(Path::new("a/b/IMG_0001.JPG")));
+        assert!(is_supported(Path::new("DSC_0001.nef")));
+        assert!(!is_supported(Path::new("notes.txt")));
+        assert!(!is_supported(Path::new("no_extension")));
+        assert!(needs_derivative(Path::new("DSC_0001.NEF")));
+        assert!(!needs_derivative(Path::new("a.png")));
+    }
+
+    #[test]
+    fn test_sample_evenly() {
+        assert_eq!(sample_evenly(3, 8), vec![0, 1, 2]);
+        assert_eq!(sample_evenly(16, 4), vec![0, 4, 8, 12]);
+        assert_eq!(sample_evenly(10, 4), vec![0, 2, 5, 7]);
+    }
+
+    #[test]
+    fn test_thin_out() {
+        let frames = (0..1000).map(Ok::<usize, ()>);
+        let mut converted = 0;
+        let kept = thin_out(frames, 8, |frame| {
+            converted += 1;
+            frame
+        })
+        .unwrap();
+        let indices: Vec<usize> = kept.iter().map(|(index, _)| *index).collect();
+        assert_eq!(indices, (0..1000).step_by(64).collect::<Vec<_>>());
+        assert!(kept.iter().all(|(index, frame)| index == frame));
+        // only frames on the current stride are converted
+        assert!(converted < 100, "{converted}");
+        assert_eq!(thin_out((0..5).map(Ok::<usize, ()>), 8, |frame| frame).unwrap().len(), 5);
+        assert_eq!(thin_out([Ok(0), Err("broken")].into_iter(), 8, |frame| frame), Err("broken"));
+    }
+
+    #[test]
+    fn test_largest_embedded_jpeg() {
+        let mut raw = b"II*\0 sensor data".to_vec();
+        raw.extend(jpeg(16, 8));
+        raw.extend([0u8; 64]);
+        raw.extend(jpeg(64, 32));
+        raw.extend(b"trailing sensor data");
+
+        let preview = largest_embedded_jpeg(&raw).unwrap();
+        assert_eq!((preview.width(), preview.height()), (64, 32));
+        assert!(largest_embedded_jpeg(b"no preview in here").is_err());
+    }
+}
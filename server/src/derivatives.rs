@@ -0,0 +1,97 @@
+use crate::AppState;
+use crate::decoders;
+use crate::failures::fingerprint;
+use axum::extract::{Path as UrlPath, State};
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use image::ImageFormat;
+use log::{debug, error};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Cursor;
+use std::path::{Component, Path, PathBuf};
+
+/// Longest edge of a derivative, large enough for the zoomable modal.
+const DERIVATIVE_SIZE: u32 = 2048;
+
+/// URL of the browser-viewable derivative for an image path in the `media/...` form, if the
+/// original can't be displayed by the browser.
+pub fn preview_path(image_path: &str) -> Option<String> {
+    decoders::needs_derivative(Path::new(image_path))
+        .then(|| image_path.replacen("media/", "derivative/", 1))
+}
+
+/// Serves a JPEG version of an image in a format browsers can't display. Derivatives are
+/// cached on disk and rebuilt when the original changes.
+pub async fn web_derivative(
+    State(state): State<AppState>,
+    UrlPath(relative_path): UrlPath<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let relative_path = PathBuf::from(relative_path.trim_start_matches('/'));
+    if relative_path
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let media_dir = state.arguments.shellexpand_media_dir().map_err(|err| {
+        error!("media dir could not be loaded: {err}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let original = media_dir.join(&relative_path);
+    if !decoders::needs_derivative(&original) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let fingerprint = fingerprint(&original).ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut hasher = DefaultHasher::new();
+    relative_path.hash(&mut hasher);
+    fingerprint.hash(&mut hasher);
+    let cached = Path::new(&state.arguments.derivative_dir).join(format!("{:016x}.jpg", hasher.finish()));
+
+    let bytes = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, StatusCode> {
+        if let Ok(bytes) = std::fs::read(&cached) {
+            return Ok(bytes);
+        }
+        debug!("Creating derivative of {original:?}");
+        let image = decoders::open(&original).map_err(|err| {
+            error!("Failed to decode {original:?}: {err}");
+            StatusCode::UNPROCESSABLE_ENTITY
+        })?;
+        let mut bytes = Vec::new();
+        image
+            .thumbnail(DERIVATIVE_SIZE, DERIVATIVE_SIZE)
+            .to_rgb8()
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg)
+            .map_err(|err| {
+                error!("Failed to encode derivative of {original:?}: {err}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        if let Err(err) = std::fs::create_dir_all(cached.parent().unwrap_or(Path::new(".")))
+            .and_then(|_| std::fs::write(&cached, &bytes))
+        {
+            error!("Failed to cache derivative {cached:?}: {err}");
+        }
+        Ok(bytes)
+    })
+    .await
+    .map_err(|err| {
+        error!("Failed to join derivative task: {err}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })??;
+
+    Ok(([(header::CONTENT_TYPE, "image/jpeg")], bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preview_path() {
+        assert_eq!(
+            preview_path("media/2023/DSC_0001.NEF"),
+            Some("derivative/2023/DSC_0001.NEF".to_string())
+        );
+        assert_eq!(preview_path("media/2023/IMG_0001.jpg"), None);
+    }
+}
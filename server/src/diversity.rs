@@ -0,0 +1,143 @@
+use crate::vector::{dot, norm};
+use axum::http::StatusCode;
+use data::ImageReference;
+use log::error;
+use serde::Deserialize;
+use std::collections::HashMap;
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::{RecordId, Surreal};
+
+/// Number of top results that are re-ranked, the rest keep their order behind them. Covers the
+/// first screens, where repeated scenes are noticed.
+const MMR_CANDIDATES: usize = 200;
+
+/// Maximal marginal relevance. Picks the results one by one, each time the candidate with the
+/// best `(1 - diversity) * relevance - diversity * similarity to the closest result picked so far`.
+/// `embeddings` must be normalized. Returns the indices in the new order.
+///
+/// Relevance is scaled to `0..1` over the candidates first. Text-to-image similarities of CLIP
+/// only spread over a few hundredths while image-to-image similarities range from about 0.7 to 1,
+/// unscaled the redundancy would outweigh the relevance already at small `diversity`.
+pub fn mmr(relevance: &[f32], embeddings: &[Vec<f32>], diversity: f32) -> Vec<usize> {
+    let min = relevance.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = relevance.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let relevance: Vec<f32> = relevance
+        .iter()
+        .map(|value| if max > min { (value - min) / (max - min) } else { 1.0 })
+        .collect();
+    let mut remaining: Vec<usize> = (0..relevance.len()).collect();
+    let mut closest = vec![f32::MIN; relevance.len()];
+    let mut order = Vec::with_capacity(relevance.len());
+    while !remaining.is_empty() {
+        let score = |index: usize| {
+            let redundancy = if order.is_empty() { 0.0 } else { closest[index] };
+            (1.0 - diversity) * relevance[index] - diversity * redundancy
+        };
+        let (position, _) = remaining
+            .iter()
+            .enumerate()
+            .max_by(|(a_position, a), (b_position, b)| {
+                // ties go to the better ranked result
+                score(**a).total_cmp(&score(**b)).then(b_position.cmp(a_position))
+            })
+            .expect("remaining is not empty");
+        let picked = remaining.remove(position);
+        order.push(picked);
+        for &index in &remaining {
+            let (a, b) = (&embeddings[index], &embeddings[picked]);
+            let similarity = if a.len() == b.len() { dot(a, b) } else { 0.0 };
+            closest[index] = closest[index].max(similarity);
+        }
+    }
+    order
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredEmbedding {
+    id: RecordId,
+    embedding: Vec<f32>,
+}
+
+/// Re-ranks the top results so that near-identical images, like the frames of a burst, don't
+/// fill the first screen. `relevance` holds the ranking score of each image.
+pub async fn diversify(
+    db: &Surreal<Client>,
+    mut images: Vec<ImageReference>,
+    relevance: &[f32],
+    diversity: f32,
+) -> Result<Vec<ImageReference>, StatusCode> {
+    let count = images.len().min(MMR_CANDIDATES);
+    let ids: Vec<RecordId> = images[..count]
+        .iter()
+        .filter_map(|image| image.id.parse().ok())
+        .collect();
+    let mut response = db
+        .query("SELECT id, embedding FROM image WHERE id IN $ids")
+        .bind(("ids", ids))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let stored: Vec<StoredEmbedding> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let mut by_id: HashMap<String, Vec<f32>> = stored
+        .into_iter()
+        .map(|stored| {
+            let norm = norm(&stored.embedding);
+            let normalized = if norm == 0.0 {
+                stored.embedding
+            } else {
+                stored.embedding.iter().map(|value| value / norm).collect()
+            };
+            (stored.id.to_string(), normalized)
+        })
+        .collect();
+    // images without an embedding are never similar to anything
+    let embeddings: Vec<Vec<f32>> = images[..count]
+        .iter()
+        .map(|image| by_id.remove(&image.id).unwrap_or_default())
+        .collect();
+
+    let rest = images.split_off(count);
+    let mut top: Vec<Option<ImageReference>> = images.into_iter().map(Some).collect();
+    Ok(mmr(&relevance[..count], &embeddings, diversity)
+        .into_iter()
+        .filter_map(|index| top[index].take())
+        .chain(rest)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mmr() {
+        // two near-identical frames of a burst and a different scene
+        let embeddings = vec![vec![1.0, 0.0], vec![0.999, 0.045], vec![0.0, 1.0]];
+        let relevance = [0.30, 0.29, 0.25];
+        assert_eq!(mmr(&relevance, &embeddings, 0.0), vec![0, 1, 2]);
+        assert_eq!(mmr(&relevance, &embeddings, 0.5), vec![0, 2, 1]);
+        assert_eq!(mmr(&[], &[], 0.5), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_mmr_clip_scale() {
+        // two frames of a burst (similarity 0.97), a different photo of the same scene (0.75) and
+        // two weak matches, with text similarities as CLIP gives them
+        let embeddings = vec![
+            vec![0.866, 0.5, 0.0, 0.0, 0.0, 0.0],
+            vec![0.866, 0.44, 0.2375, 0.0, 0.0, 0.0],
+            vec![0.866, 0.0, 0.0, 0.5, 0.0, 0.0],
+            vec![0.866, 0.0, 0.0, 0.0, 0.5, 0.0],
+            vec![0.75, 0.0, 0.0, 0.0, 0.0, 0.661],
+        ];
+        let relevance = [0.32, 0.316, 0.31, 0.22, 0.20];
+        // the near tie goes to the other photo, but weak matches don't overtake the second frame
+        assert_eq!(mmr(&relevance, &embeddings, 0.3), vec![0, 2, 1, 3, 4]);
+        assert_eq!(mmr(&relevance, &embeddings, 0.0), vec![0, 1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,318 @@
+use crate::AppState;
+use crate::database::load_images;
+use crate::failures::media_dir_str;
+use crate::vector::{cosine_distance_with_norm, norm};
+use axum::Json;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use data::{DuplicateGroup, DuplicateImage};
+use image::DynamicImage;
+use image::imageops::FilterType;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::{RecordId, Surreal};
+
+/// Perceptual hashes further apart than this are never duplicates, whatever the embeddings say.
+const MAX_HAMMING_DISTANCE: u32 = 12;
+/// The 64 bit hash is split into this many bands, images sharing a band are compared.
+const BANDS: u32 = 4;
+/// Buckets with more images are not compared. Blank or uniform images all hash to the same bits
+/// and would otherwise be compared pair by pair.
+const MAX_BUCKET_SIZE: usize = 500;
+
+/// Difference hash: the image is shrunk to 9x8 grey pixels and every bit tells whether a pixel is
+/// brighter than its right neighbour. Re-saves, exports and small edits keep most bits.
+pub fn dhash(img: &DynamicImage) -> i64 {
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash: u64 = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+    // SurrealDB integers are signed, the bits are stored unchanged
+    hash as i64
+}
+
+fn hamming(a: i64, b: i64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+struct UnionFind {
+    parents: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(len: usize) -> Self {
+        Self {
+            parents: (0..len).collect(),
+        }
+    }
+
+    fn find(&mut self, mut item: usize) -> usize {
+        while self.parents[item] != item {
+            self.parents[item] = self.parents[self.parents[item]];
+            item = self.parents[item];
+        }
+        item
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parents[a.max(b)] = a.min(b);
+        }
+    }
+}
+
+/// Groups of indices into `images` that are near-duplicates of each other: perceptual hashes
+/// within [`MAX_HAMMING_DISTANCE`] and a cosine similarity of the embeddings of at least
+/// `threshold`. Only pairs sharing a band of their hash are compared, which finds every pair
+/// within `BANDS - 1` bits and most pairs above. Bands shared by more than [`MAX_BUCKET_SIZE`]
+/// images are skipped, so images with such a common hash are only grouped through other bands.
+pub fn duplicate_groups(images: &[(i64, &[f32])], threshold: f32) -> Vec<Vec<usize>> {
+    let norms: Vec<f32> = images.iter().map(|(_, embedding)| norm(embedding)).collect();
+    let band_bits = 64 / BANDS;
+    let mut union_find = UnionFind::new(images.len());
+    for band in 0..BANDS {
+        let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (index, (hash, _)) in images.iter().enumerate() {
+            let key = (*hash as u64 >> (band * band_bits)) & ((1 << band_bits) - 1);
+            buckets.entry(key).or_default().push(index);
+        }
+        for bucket in buckets
+            .values()
+            .filter(|bucket| bucket.len() > 1 && bucket.len() <= MAX_BUCKET_SIZE)
+        {
+            for (position, &a) in bucket.iter().enumerate() {
+                for &b in &bucket[position + 1..] {
+                    let (hash_a, embedding_a) = images[a];
+                    let (hash_b, embedding_b) = images[b];
+                    if hamming(hash_a, hash_b) > MAX_HAMMING_DISTANCE
+                        || union_find.find(a) == union_find.find(b)
+                    {
+                        continue;
+                    }
+                    let similarity = 1.0 - cosine_distance_with_norm(embedding_a, norms[a], embedding_b);
+                    if similarity >= threshold {
+                        union_find.union(a, b);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for index in 0..images.len() {
+        groups.entry(union_find.find(index)).or_default().push(index);
+    }
+    let mut groups: Vec<Vec<usize>> = groups.into_values().filter(|group| group.len() > 1).collect();
+    groups.sort();
+    groups
+}
+
+#[derive(Debug, Deserialize)]
+struct HashedImage {
+    id: RecordId,
+    phash: i64,
+    embedding: Vec<f32>,
+    duplicates_checked: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct GroupAssignment {
+    id: RecordId,
+    duplicate_group: String,
+}
+
+/// Finds near-duplicates among all images with a perceptual hash and stores the group on each
+/// member as `duplicate_group`, named after its first member. Groups are only recomputed if an
+/// image was hashed since the last run or `rebuild` is set, otherwise returns `None`. Returns the
+/// number of groups.
+pub async fn update_duplicate_groups(
+    db: &Surreal<Client>,
+    threshold: f32,
+    rebuild: bool,
+) -> Result<Option<usize>, Box<dyn Error + Send + Sync>> {
+    if !rebuild {
+        let mut response = db
+            .query("SELECT VALUE id FROM image WHERE parent IS NONE AND phash IS NOT NONE AND duplicates_checked IS NONE LIMIT 1")
+            .await?;
+        let unchecked: Vec<RecordId> = response.take(0)?;
+        if unchecked.is_empty() {
+            return Ok(None);
+        }
+    }
+    let images: Vec<HashedImage> = load_images(
+        db,
+        "id, phash, embedding, duplicates_checked OR false AS duplicates_checked",
+        "AND phash IS NOT NONE",
+        |image: &HashedImage| &image.id,
+    )
+    .await?;
+    let hashed: Vec<(i64, &[f32])> = images
+        .iter()
+        .map(|image| (image.phash, image.embedding.as_slice()))
+        .collect();
+    let groups = duplicate_groups(&hashed, threshold);
+
+    let assignments: Vec<GroupAssignment> = groups
+        .iter()
+        .flat_map(|group| {
+            let name = images[group[0]].id.to_string();
+            group.iter().map(move |&index| GroupAssignment {
+                id: images[index].id.clone(),
+                duplicate_group: name.clone(),
+            })
+        })
+        .collect();
+    let checked: Vec<RecordId> = images
+        .iter()
+        .filter(|image| !image.duplicates_checked)
+        .map(|image| image.id.clone())
+        .collect();
+    // readers of `/duplicates` see the old or the new groups, never none
+    db.query("BEGIN TRANSACTION")
+        .query("UPDATE image SET duplicate_group = NONE WHERE duplicate_group IS NOT NONE")
+        .query("FOR $image IN $images { UPDATE $image.id SET duplicate_group = $image.duplicate_group; }")
+        .query("UPDATE image SET duplicates_checked = true WHERE id IN $checked")
+        .query("COMMIT TRANSACTION")
+        .bind(("images", assignments))
+        .bind(("checked", checked))
+        .await?
+        .check()?;
+    Ok(Some(groups.len()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DuplicatesQuery {
+    #[serde(default)]
+    threshold: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredDuplicate {
+    id: RecordId,
+    image_path: String,
+    duplicate_group: String,
+    #[serde(default)]
+    width: Option<u32>,
+    #[serde(default)]
+    height: Option<u32>,
+    #[serde(default)]
+    file_size: Option<u64>,
+    #[serde(default)]
+    modified: Option<i64>,
+}
+
+/// Groups of near-duplicate images with what is needed to pick the copy to keep.
+pub async fn web_duplicates(State(state): State<AppState>) -> Result<Json<Vec<DuplicateGroup>>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let db = &state.db;
+    let mut response = db
+        .query(
+            "SELECT id, image_path, duplicate_group, width, height, file_size, modified FROM image
+            WHERE parent IS NONE AND duplicate_group IS NOT NONE",
+        )
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let stored: Vec<StoredDuplicate> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut groups: HashMap<String, Vec<DuplicateImage>> = HashMap::new();
+    for image in stored {
+        groups.entry(image.duplicate_group).or_default().push(DuplicateImage {
+            id: image.id.to_string(),
+            image_path: image.image_path.replace(&media_dir_str, "media/"),
+            width: image.width,
+            height: image.height,
+            file_size: image.file_size,
+            modified: image.modified,
+        });
+    }
+    let mut groups: Vec<DuplicateGroup> = groups
+        .into_iter()
+        .map(|(id, images)| DuplicateGroup { id, images })
+        .collect();
+    groups.sort_by(|a, b| b.images.len().cmp(&a.images.len()).then_with(|| a.id.cmp(&b.id)));
+    Ok(Json(groups))
+}
+
+/// Recomputes the duplicate groups, optionally with a different similarity threshold.
+pub async fn web_refresh_duplicates(
+    State(state): State<AppState>,
+    Query(query): Query<DuplicatesQuery>,
+) -> Result<Json<usize>, StatusCode> {
+    let threshold = query.threshold.unwrap_or(state.arguments.duplicate_threshold);
+    let db = &state.db;
+    let groups = update_duplicate_groups(db, threshold, true)
+        .await
+        .map_err(|err| {
+            error!("Failed to update duplicate groups: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .unwrap_or_default();
+    info!("Found {groups} groups of duplicates.");
+    Ok(Json(groups))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    fn gradient(width: u32, height: u32, flip: bool) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, _| {
+            let value = (x * 255 / width) as u8;
+            Luma([if flip { 255 - value } else { value }])
+        }))
+    }
+
+    #[test]
+    fn test_dhash() {
+        // resizing keeps the hash, mirroring the gradient flips every bit
+        assert_eq!(dhash(&gradient(640, 480, false)), dhash(&gradient(320, 240, false)));
+        assert_eq!(
+            hamming(dhash(&gradient(640, 480, false)), dhash(&gradient(640, 480, true))),
+            64
+        );
+    }
+
+    #[test]
+    fn test_duplicate_groups() {
+        let a = [1.0, 0.0];
+        let near_a = [0.99, 0.05];
+        let other = [0.0, 1.0];
+        let images = vec![
+            (0b1011_0000, &a[..]),
+            (0b1011_0001, &near_a[..]),
+            // same hash, different content
+            (0b1011_0000, &other[..]),
+            (-1, &a[..]),
+        ];
+        assert_eq!(duplicate_groups(&images, 0.95), vec![vec![0, 1]]);
+    }
+
+    #[test]
+    fn test_duplicate_groups_blank_images() {
+        let a = [1.0, 0.0];
+        let near_a = [0.99, 0.05];
+        // blank images share every band, the pair differs from them in every band
+        let mut images = vec![(0, &a[..]); MAX_BUCKET_SIZE + 1];
+        images.push((0x0101_0101_0101_0101, &a[..]));
+        images.push((0x0101_0101_0101_0101, &near_a[..]));
+        assert_eq!(
+            duplicate_groups(&images, 0.95),
+            vec![vec![MAX_BUCKET_SIZE + 1, MAX_BUCKET_SIZE + 2]]
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use crate::AppState;
+use crate::search::search;
+use anyhow::anyhow;
+use data::SearchParams;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// One line of the labelled query file: a search request plus the images that should be found.
+#[derive(Debug, Deserialize)]
+struct LabelledQuery {
+    #[serde(flatten)]
+    params: SearchParams,
+    relevant: Vec<String>,
+}
+
+/// Settings applied to every labelled query before it is searched.
+#[derive(Debug, Deserialize, Default)]
+struct EvaluationConfig {
+    #[serde(default)]
+    name: Option<String>,
+    /// Template for the text query, `{}` is replaced with the query of the labelled line.
+    #[serde(default)]
+    prompt_template: Option<String>,
+    /// Fields of `SearchParams` that override the values of the labelled line.
+    #[serde(default)]
+    params: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct QueryMetrics {
+    q: String,
+    recall_at_k: f32,
+    reciprocal_rank: f32,
+    ndcg_at_k: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct ConfigurationReport {
+    name: String,
+    queries: usize,
+    recall_at_k: f32,
+    mrr: f32,
+    ndcg_at_k: f32,
+    per_query: Vec<QueryMetrics>,
+}
+
+#[derive(Debug, Serialize)]
+struct MetricDelta {
+    recall_at_k: f32,
+    mrr: f32,
+    ndcg_at_k: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct EvaluationReport {
+    k: usize,
+    configurations: Vec<ConfigurationReport>,
+    /// Second configuration minus the first one, only present when comparing.
+    delta: Option<MetricDelta>,
+}
+
+/// Runs every labelled query through the regular search and prints recall@k, MRR and nDCG@k as
+/// JSON. With `compare` both configurations are evaluated on the same queries.
+pub async fn run_evaluation(
+    state: &AppState,
+    queries: &Path,
+    k: usize,
+    config: Option<&Path>,
+    compare: Option<&Path>,
+) -> anyhow::Result<()> {
+    let labelled: Vec<LabelledQuery> = std::fs::read_to_string(queries)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    info!("Loaded {} labelled queries.", labelled.len());
+
+    let mut configurations = vec![evaluate(state, &labelled, k, &load_config(config)?).await?];
+    if let Some(compare) = compare {
+        configurations.push(evaluate(state, &labelled, k, &load_config(Some(compare))?).await?);
+    }
+    let delta = match configurations.as_slice() {
+        [baseline, candidate] => Some(MetricDelta {
+            recall_at_k: candidate.recall_at_k - baseline.recall_at_k,
+            mrr: candidate.mrr - baseline.mrr,
+            ndcg_at_k: candidate.ndcg_at_k - baseline.ndcg_at_k,
+        }),
+        _ => None,
+    };
+
+    let report = EvaluationReport {
+        k,
+        configurations,
+        delta,
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+fn load_config(path: Option<&Path>) -> anyhow::Result<EvaluationConfig> {
+    let Some(path) = path else {
+        return Ok(EvaluationConfig::default());
+    };
+    let mut config: EvaluationConfig = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    if config.name.is_none() {
+        config.name = path.file_stem().map(|stem| stem.to_string_lossy().to_string());
+    }
+    Ok(config)
+}
+
+async fn evaluate(
+    state: &AppState,
+    labelled: &[LabelledQuery],
+    k: usize,
+    config: &EvaluationConfig,
+) -> anyhow::Result<ConfigurationReport> {
+    let name = config.name.clone().unwrap_or_else(|| "default".to_string());
+    let db = &state.db;
+    let mut per_query = Vec::with_capacity(labelled.len());
+    for query in labelled {
+        let params = apply_config(&query.params, config)?;
+        let response = search(state, db, params)
+            .await
+            .map_err(|status| anyhow!("search for {:?} failed with {status}", query.params.q))?;
+        let ranked: Vec<String> = response
+            .images
+            .iter()
+            .map(|image| normalize_path(&image.image_path))
+            .collect();
+        let relevant: HashSet<String> = query.relevant.iter().map(|p| normalize_path(p)).collect();
+        per_query.push(QueryMetrics {
+            q: query.params.q.clone(),
+            recall_at_k: recall_at_k(&ranked, &relevant, k),
+            reciprocal_rank: reciprocal_rank(&ranked, &relevant, k),
+            ndcg_at_k: ndcg_at_k(&ranked, &relevant, k),
+        });
+    }
+
+    let mean = |metric: fn(&QueryMetrics) -> f32| {
+        if per_query.is_empty() {
+            0.0
+        } else {
+            per_query.iter().map(metric).sum::<f32>() / per_query.len() as f32
+        }
+    };
+    let report = ConfigurationReport {
+        name,
+        queries: per_query.len(),
+        recall_at_k: mean(|q| q.recall_at_k),
+        mrr: mean(|q| q.reciprocal_rank),
+        ndcg_at_k: mean(|q| q.ndcg_at_k),
+        per_query,
+    };
+    info!(
+        "{}: recall@{k} {:.3}, MRR {:.3}, nDCG@{k} {:.3}",
+        report.name, report.recall_at_k, report.mrr, report.ndcg_at_k
+    );
+    Ok(report)
+}
+
+fn apply_config(params: &SearchParams, config: &EvaluationConfig) -> anyhow::Result<SearchParams> {
+    let mut value = serde_json::to_value(params)?;
+    if let Value::Object(fields) = &mut value {
+        for (key, override_value) in &config.params {
+            fields.insert(key.clone(), override_value.clone());
+        }
+    }
+    let mut params: SearchParams = serde_json::from_value(value)?;
+    if let Some(template) = &config.prompt_template {
+        params.q = template.replace("{}", &params.q);
+    }
+    Ok(params)
+}
+
+/// Makes `media/a.jpg`, `media//a.jpg` and `a.jpg` comparable.
+fn normalize_path(path: &str) -> String {
+    path.trim_start_matches("media/").trim_start_matches('/').to_string()
+}
+
+fn recall_at_k(ranked: &[String], relevant: &HashSet<String>, k: usize) -> f32 {
+    if relevant.is_empty() {
+        return 0.0;
+    }
+    let hits = ranked.iter().take(k).filter(|p| relevant.contains(*p)).count();
+    hits as f32 / relevant.len() as f32
+}
+
+fn reciprocal_rank(ranked: &[String], relevant: &HashSet<String>, k: usize) -> f32 {
+    ranked
+        .iter()
+        .take(k)
+        .position(|p| relevant.contains(p))
+        .map_or(0.0, |rank| 1.0 / (rank + 1) as f32)
+}
+
+fn ndcg_at_k(ranked: &[String], relevant: &HashSet<String>, k: usize) -> f32 {
+    let gain = |rank: usize| 1.0 / (rank as f32 + 2.0).log2();
+    let dcg: f32 = ranked
+        .iter()
+        .take(k)
+        .enumerate()
+        .filter(|(_, p)| relevant.contains(*p))
+        .map(|(rank, _)| gain(rank))
+        .sum();
+    let ideal: f32 = (0..relevant.len().min(k)).map(gain).sum();
+    if ideal == 0.0 { 0.0 } else { dcg / ideal }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_metrics() {
+        let ranked = paths(&["a", "b", "c", "d"]);
+        let relevant: HashSet<String> = paths(&["b", "d"]).into_iter().collect();
+        assert_eq!(recall_at_k(&ranked, &relevant, 2), 0.5);
+        assert_eq!(recall_at_k(&ranked, &relevant, 4), 1.0);
+        assert_eq!(reciprocal_rank(&ranked, &relevant, 4), 0.5);
+        assert_eq!(reciprocal_rank(&ranked, &relevant, 1), 0.0);
+
+        let perfect = paths(&["b", "d", "a"]);
+        assert!((ndcg_at_k(&perfect, &relevant, 3) - 1.0).abs() < 1e-6);
+        let ndcg = ndcg_at_k(&ranked, &relevant, 4);
+        assert!(ndcg > 0.0 && ndcg < 1.0);
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("media//2023/a.jpg"), "2023/a.jpg");
+        assert_eq!(normalize_path("media/2023/a.jpg"), "2023/a.jpg");
+        assert_eq!(normalize_path("2023/a.jpg"), "2023/a.jpg");
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let config = EvaluationConfig {
+            name: None,
+            prompt_template: Some("a photo of {}".to_string()),
+            params: serde_json::from_str(r#"{"exact": true}"#).unwrap(),
+        };
+        let params = SearchParams {
+            q: "a cat".to_string(),
+            ..Default::default()
+        };
+        let applied = apply_config(&params, &config).unwrap();
+        assert_eq!(applied.q, "a photo of a cat");
+        assert!(applied.exact);
+    }
+}
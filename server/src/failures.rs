@@ -0,0 +1,176 @@
+use crate::AppState;
+use crate::search::run_scan;
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use data::{FailureKind, ImageFailure, RetryFailuresRequest};
+use image::ImageError;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+/// A failed decode as reported by the indexer, before it is stored.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DecodeFailure {
+    pub image_path: String,
+    pub kind: FailureKind,
+    pub message: String,
+    pub fingerprint: Option<String>,
+}
+
+impl DecodeFailure {
+    pub fn new(image_path: String, error: &ImageError) -> Self {
+        let fingerprint = fingerprint(Path::new(&image_path));
+        Self {
+            image_path,
+            kind: classify(error),
+            message: error.to_string(),
+            fingerprint,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FailureFingerprint {
+    pub image_path: String,
+    pub fingerprint: Option<String>,
+}
+
+pub fn classify(error: &ImageError) -> FailureKind {
+    match error {
+        ImageError::IoError(io) if io.kind() == ErrorKind::PermissionDenied => FailureKind::Permission,
+        ImageError::IoError(_) => FailureKind::Io,
+        ImageError::Unsupported(_) => FailureKind::UnsupportedFormat,
+        ImageError::Limits(_) => FailureKind::TooLarge,
+        ImageError::Decoding(_) | ImageError::Encoding(_) | ImageError::Parameter(_) => {
+            FailureKind::Decode
+        }
+    }
+}
+
+/// Size and modification time of a file. A quarantined image is retried once this changes.
+pub fn fingerprint(path: &Path) -> Option<String> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some(format!("{}-{}", metadata.len(), modified))
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64)
+}
+
+pub async fn store_failure(db: &Surreal<Client>, failure: DecodeFailure) -> Result<(), surrealdb::Error> {
+    db.query(
+        "UPSERT type::thing('image_failure', $image_path) SET
+            image_path = $image_path,
+            kind = $kind,
+            message = $message,
+            fingerprint = $fingerprint,
+            failed_at = $failed_at,
+            attempts = (attempts OR 0) + 1",
+    )
+    .bind(("image_path", failure.image_path))
+    .bind(("kind", failure.kind))
+    .bind(("message", failure.message))
+    .bind(("fingerprint", failure.fingerprint))
+    .bind(("failed_at", unix_now()))
+    .await?
+    .check()?;
+    Ok(())
+}
+
+pub async fn web_failures(State(state): State<AppState>) -> Result<Json<Vec<ImageFailure>>, StatusCode> {
+    let media_dir_str = media_dir_str(&state)?;
+    let db = &state.db;
+    let mut response = db
+        .query("SELECT image_path, kind, message, failed_at, attempts FROM image_failure ORDER BY failed_at DESC")
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let failures: Vec<ImageFailure> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(
+        failures
+            .into_iter()
+            .map(|failure| ImageFailure {
+                image_path: failure.image_path.replace(&media_dir_str, "media/"),
+                ..failure
+            })
+            .collect(),
+    ))
+}
+
+/// Removes images from the quarantine and starts a scan so they are indexed again.
+pub async fn web_retry_failures(
+    State(state): State<AppState>,
+    Json(request): Json<RetryFailuresRequest>,
+) -> StatusCode {
+    let media_dir_str = match media_dir_str(&state) {
+        Ok(media_dir_str) => media_dir_str,
+        Err(status) => return status,
+    };
+    let result = {
+        let db = &state.db;
+        if request.image_paths.is_empty() {
+            db.query("DELETE image_failure").await
+        } else {
+            let image_paths: Vec<String> = request
+                .image_paths
+                .iter()
+                .map(|path| path.replacen("media/", &media_dir_str, 1))
+                .collect();
+            db.query("DELETE image_failure WHERE image_path IN $image_paths")
+                .bind(("image_paths", image_paths))
+                .await
+        }
+    };
+    if let Err(err) = result {
+        error!("DB query error: {:?}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    info!("Retrying failed images.");
+    run_scan(state).await
+}
+
+pub fn media_dir_str(state: &AppState) -> Result<String, StatusCode> {
+    state
+        .arguments
+        .shellexpand_media_dir()
+        .ok()
+        .and_then(|media_dir| media_dir.into_os_string().into_string().ok())
+        .ok_or_else(|| {
+            error!("media dir could not be loaded");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::error::{LimitError, LimitErrorKind};
+
+    #[test]
+    fn test_classify() {
+        let denied = ImageError::IoError(std::io::Error::from(ErrorKind::PermissionDenied));
+        assert_eq!(classify(&denied), FailureKind::Permission);
+        let missing = ImageError::IoError(std::io::Error::from(ErrorKind::NotFound));
+        assert_eq!(classify(&missing), FailureKind::Io);
+        let limits = ImageError::Limits(LimitError::from_kind(LimitErrorKind::DimensionError));
+        assert_eq!(classify(&limits), FailureKind::TooLarge);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::AppState;
+use crate::clip::image_prepare_resnet;
+use crate::decoders;
+use crate::search::image_record_id;
+use crate::vector::{cosine_distance_with_norm, norm};
+use crate::vision::{EMBEDDING_DIMENSION, IMAGE_LEN};
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use data::{Heatmap, HeatmapRequest, ImagePathResult};
+use log::error;
+use std::path::PathBuf;
+
+const DEFAULT_GRID: usize = 7;
+/// Every patch costs a forward pass, 14 x 14 patches of 16 pixels is as fine as it gets.
+const MAX_GRID: usize = 14;
+const IMAGE_SIZE: usize = 224;
+
+/// Explains a search result by occlusion sensitivity: each patch of the image is greyed out in
+/// turn and the drop of the similarity to the query is that patch's contribution.
+pub async fn web_heatmap(
+    State(state): State<AppState>,
+    Json(request): Json<HeatmapRequest>,
+) -> Result<Json<Heatmap>, StatusCode> {
+    if request.query_vector.len() != EMBEDDING_DIMENSION {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let grid = request.grid.unwrap_or(DEFAULT_GRID).clamp(2, MAX_GRID);
+    let id = image_record_id(&request.image_id)?;
+
+    let image: Option<ImagePathResult> = {
+        let db = &state.db;
+        let mut response = db
+            .query("SELECT image_path FROM ONLY $id")
+            .bind(("id", id))
+            .await
+            .map_err(|err| {
+                error!("DB query error: {:?}", err);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        response.take(0).map_err(|err| {
+            error!("Failed to deserialize response: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+    };
+    let image_path = PathBuf::from(image.ok_or(StatusCode::NOT_FOUND)?.image_path);
+
+    let pixels = tokio::task::spawn_blocking(move || {
+        decoders::open(&image_path)
+            .map(|img| occluded_batch(&image_prepare_resnet(img), grid))
+            .map_err(|err| {
+                error!("Failed to decode {image_path:?}: {err}");
+                StatusCode::UNPROCESSABLE_ENTITY
+            })
+    })
+    .await
+    .map_err(|err| {
+        error!("Failed to join heatmap task: {err}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })??;
+
+    let embeddings = state
+        .vision
+        .embed_adaptive(&pixels.chunks(IMAGE_LEN).collect::<Vec<_>>())
+        .await
+        .map_err(|err| {
+            error!("Failed to embed occluded images: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let query_norm = norm(&request.query_vector);
+    let similarities: Vec<f32> = embeddings
+        .iter()
+        .map(|embedding| 1.0 - cosine_distance_with_norm(&request.query_vector, query_norm, embedding))
+        .collect();
+    Ok(Json(Heatmap {
+        grid,
+        values: contributions(similarities[0], &similarities[1..]),
+    }))
+}
+
+/// The preprocessed image followed by one copy per patch with that patch set to zero, which is
+/// the dataset mean after normalization.
+fn occluded_batch(pixels: &[f32], grid: usize) -> Vec<f32> {
+    let mut batch = Vec::with_capacity((grid * grid + 1) * IMAGE_LEN);
+    batch.extend_from_slice(pixels);
+    for row in 0..grid {
+        for column in 0..grid {
+            let mut occluded = pixels.to_vec();
+            let (y0, y1) = (row * IMAGE_SIZE / grid, (row + 1) * IMAGE_SIZE / grid);
+            let (x0, x1) = (column * IMAGE_SIZE / grid, (column + 1) * IMAGE_SIZE / grid);
+            for channel in occluded.chunks_exact_mut(IMAGE_SIZE * IMAGE_SIZE) {
+                for y in y0..y1 {
+                    channel[y * IMAGE_SIZE + x0..y * IMAGE_SIZE + x1].fill(0.0);
+                }
+            }
+            batch.extend(occluded);
+        }
+    }
+    batch
+}
+
+/// Similarity lost by occluding each patch, scaled so the most important patch is 1. Patches
+/// whose occlusion increases the similarity count as 0.
+fn contributions(baseline: f32, occluded: &[f32]) -> Vec<f32> {
+    let drops: Vec<f32> = occluded.iter().map(|similarity| (baseline - similarity).max(0.0)).collect();
+    let max = drops.iter().copied().fold(0.0, f32::max);
+    if max == 0.0 {
+        return drops;
+    }
+    drops.iter().map(|drop| drop / max).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_occluded_batch() {
+        let pixels = vec![1.0; IMAGE_LEN];
+        let batch = occluded_batch(&pixels, 2);
+        assert_eq!(batch.len(), 5 * IMAGE_LEN);
+        let first = &batch[IMAGE_LEN..2 * IMAGE_LEN];
+        // top left patch is occluded in every channel, the rest is untouched
+        assert_eq!(first[0], 0.0);
+        assert_eq!(first[IMAGE_SIZE * IMAGE_SIZE + 111], 0.0);
+        assert_eq!(first[112], 1.0);
+        assert_eq!(first[112 * IMAGE_SIZE], 1.0);
+        assert_eq!(batch.iter().filter(|value| **value == 0.0).count(), IMAGE_LEN);
+    }
+
+    #[test]
+    fn test_contributions() {
+        assert_eq!(contributions(0.5, &[0.25, 0.375, 0.75]), vec![1.0, 0.5, 0.0]);
+        assert_eq!(contributions(0.5, &[0.5, 0.75]), vec![0.0, 0.0]);
+    }
+}
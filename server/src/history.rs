@@ -0,0 +1,170 @@
+use crate::AppState;
+use crate::failures::unix_now;
+use axum::Json;
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use data::{HistoryEntry, SearchParams, USER_HEADER};
+use log::error;
+use serde::Deserialize;
+use surrealdb::Surreal;
+use surrealdb::engine::remote::ws::Client;
+
+const DEFAULT_HISTORY_SIZE: usize = 20;
+const MAX_HISTORY_SIZE: usize = 200;
+/// Searches kept per user, older ones are deleted when a new one is recorded.
+const STORED_HISTORY_SIZE: usize = 1000;
+
+/// Lets the history of a user be read and pruned without scanning everyone's searches.
+pub async fn define_history_index(db: &Surreal<Client>) -> Result<(), surrealdb::Error> {
+    db.query("DEFINE INDEX IF NOT EXISTS search_history_user ON search_history FIELDS user, searched_at;")
+        .await?
+        .check()?;
+    Ok(())
+}
+
+pub fn user_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(USER_HEADER)?
+        .to_str()
+        .ok()
+        .map(str::trim)
+        .filter(|user| !user.is_empty())
+        .map(str::to_string)
+}
+
+/// Adds a search to the history of the user and drops the user's searches beyond the newest
+/// `STORED_HISTORY_SIZE`. Failing to record it is logged and does not fail the search.
+pub async fn record_search(db: &Surreal<Client>, user: Option<String>, params: &SearchParams) {
+    let Some(user) = user else {
+        return;
+    };
+    if params.q.trim().is_empty() && params.referenced_images.is_empty() {
+        return;
+    }
+    let result = db
+        .query("CREATE search_history SET user = $user, params = $params, searched_at = $searched_at")
+        .query(
+            "DELETE search_history WHERE user = $user AND searched_at < (
+                SELECT VALUE searched_at FROM search_history WHERE user = $user
+                ORDER BY searched_at DESC LIMIT 1 START $keep
+            )[0]",
+        )
+        .bind(("user", user))
+        .bind(("params", params.clone()))
+        .bind(("searched_at", unix_now()))
+        .bind(("keep", STORED_HISTORY_SIZE - 1))
+        .await;
+    if let Err(err) = result {
+        error!("Failed to record search: {:?}", err);
+    }
+}
+
+/// Newest entries first, a search repeated later only shows up at its latest time.
+fn unique_recent(entries: &[HistoryEntry], limit: usize) -> Vec<HistoryEntry> {
+    let mut unique: Vec<HistoryEntry> = Vec::new();
+    for entry in entries {
+        if unique.len() == limit {
+            break;
+        }
+        if !unique.iter().any(|seen| seen.params == entry.params) {
+            unique.push(entry.clone());
+        }
+    }
+    unique
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+/// Recent searches of the user named in the `x-user-id` header.
+pub async fn web_history(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<HistoryEntry>>, StatusCode> {
+    let Some(user) = user_id(&headers) else {
+        return Ok(Json(Vec::new()));
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_SIZE).min(MAX_HISTORY_SIZE);
+    // repeated searches are merged, so pages are fetched until there are enough distinct ones
+    let page_size = limit * 4;
+    let db = &state.db;
+    let mut entries: Vec<HistoryEntry> = Vec::new();
+    loop {
+        let mut response = db
+            .query(
+                "SELECT params, searched_at FROM search_history WHERE user = $user
+                ORDER BY searched_at DESC LIMIT $limit START $start",
+            )
+            .bind(("user", user.clone()))
+            .bind(("limit", page_size))
+            .bind(("start", entries.len()))
+            .await
+            .map_err(|err| {
+                error!("DB query error: {:?}", err);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        let page: Vec<HistoryEntry> = response.take(0).map_err(|err| {
+            error!("Failed to deserialize response: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let page_len = page.len();
+        entries.extend(page);
+        let unique = unique_recent(&entries, limit);
+        if unique.len() == limit || page_len < page_size {
+            return Ok(Json(unique));
+        }
+    }
+}
+
+/// Forgets the history of the user.
+pub async fn web_clear_history(State(state): State<AppState>, headers: HeaderMap) -> StatusCode {
+    let Some(user) = user_id(&headers) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let db = &state.db;
+    match db.query("DELETE search_history WHERE user = $user").bind(("user", user)).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(err) => {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(q: &str, searched_at: i64) -> HistoryEntry {
+        HistoryEntry {
+            params: SearchParams {
+                q: q.to_string(),
+                ..Default::default()
+            },
+            searched_at,
+        }
+    }
+
+    #[test]
+    fn test_unique_recent() {
+        let recent = unique_recent(
+            &[entry("cat", 4), entry("dog", 3), entry("cat", 2), entry("boat", 1)],
+            2,
+        );
+        assert_eq!(recent.len(), 2);
+        assert_eq!((recent[0].params.q.as_str(), recent[0].searched_at), ("cat", 4));
+        assert_eq!(recent[1].params.q, "dog");
+    }
+
+    #[test]
+    fn test_user_id() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(user_id(&headers), None);
+        headers.insert(USER_HEADER, " abc ".parse().unwrap());
+        assert_eq!(user_id(&headers), Some("abc".to_string()));
+    }
+}
@@ -0,0 +1,919 @@
+use crate::AppState;
+use crate::clip::image_prepare_resnet;
+use crate::clusters::update_clusters;
+use crate::concepts::score_new_images;
+use crate::database::{init_database, random_key};
+use crate::decoders;
+use crate::duplicates::{dhash, update_duplicate_groups};
+use crate::failures::{DecodeFailure, FailureFingerprint, fingerprint, store_failure};
+use crate::map::update_map;
+use crate::regions::region_tiles;
+use crate::search::{ImageType, average_slices};
+use crate::tags::tag_images;
+use crate::text_search::update_text_index;
+use crate::video::{KeyframeSampling, extract_keyframes, is_video};
+use crate::vision::InferenceError;
+use data::{ImagePathResult, MediaKind, Region, ScanStatus, StageStatus};
+use image::ImageError;
+use image::error::{DecodingError, ImageFormatHint};
+use log::{error, info, warn};
+use rand::prelude::SliceRandom;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use surrealdb::{RecordId, Surreal};
+use surrealdb::engine::remote::ws::Client;
+use tokio::sync::mpsc;
+use walkdir::WalkDir;
+
+type IndexError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Default)]
+pub struct StageMetrics {
+    items: AtomicU64,
+    busy_micros: AtomicU64,
+}
+
+impl StageMetrics {
+    fn record(&self, items: usize, busy: Duration) {
+        self.items.fetch_add(items as u64, Ordering::Relaxed);
+        self.busy_micros
+            .fetch_add(busy.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.items.store(0, Ordering::Relaxed);
+        self.busy_micros.store(0, Ordering::Relaxed);
+    }
+
+    fn status(&self, name: &str, elapsed: Duration) -> StageStatus {
+        let items = self.items.load(Ordering::Relaxed);
+        let elapsed_seconds = elapsed.as_secs_f64();
+        StageStatus {
+            name: name.to_string(),
+            items,
+            busy_seconds: self.busy_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+            items_per_second: if elapsed_seconds > 0.0 {
+                items as f64 / elapsed_seconds
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// Finishes the scan it was created for when dropped.
+pub struct ScanGuard(Arc<ScanMetrics>);
+
+impl Drop for ScanGuard {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+/// Progress of the current or last scan, one [`StageMetrics`] per pipeline stage.
+#[derive(Default)]
+pub struct ScanMetrics {
+    running: AtomicBool,
+    started: Mutex<Option<Instant>>,
+    finished: Mutex<Option<Instant>>,
+    walk: StageMetrics,
+    filter: StageMetrics,
+    decode: StageMetrics,
+    batch: StageMetrics,
+    infer: StageMetrics,
+    write: StageMetrics,
+}
+
+impl ScanMetrics {
+    /// Marks a scan as running and resets the counters. Returns false if a scan is already running.
+    pub fn try_start(&self) -> bool {
+        if self
+            .running
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return false;
+        }
+        for (_, stage) in self.stages() {
+            stage.reset();
+        }
+        *self.started.lock().unwrap() = Some(Instant::now());
+        *self.finished.lock().unwrap() = None;
+        true
+    }
+
+    /// Marks a scan as running like [`Self::try_start`]. The scan is finished when the returned
+    /// guard is dropped, so a panicking scan does not block every later one.
+    pub fn start_guarded(self: &Arc<Self>) -> Option<ScanGuard> {
+        self.try_start().then(|| ScanGuard(self.clone()))
+    }
+
+    pub fn finish(&self) {
+        *self.finished.lock().unwrap() = Some(Instant::now());
+        self.running.store(false, Ordering::Release);
+        for stage in self.status().stages {
+            info!(
+                "Stage {}: {} items, {:.1} s busy, {:.1} items/s",
+                stage.name, stage.items, stage.busy_seconds, stage.items_per_second
+            );
+        }
+    }
+
+    pub fn status(&self) -> ScanStatus {
+        let started = *self.started.lock().unwrap();
+        let finished = *self.finished.lock().unwrap();
+        let elapsed = match (started, finished) {
+            (Some(started), Some(finished)) => finished - started,
+            (Some(started), None) => started.elapsed(),
+            _ => Duration::ZERO,
+        };
+        ScanStatus {
+            running: self.running.load(Ordering::Acquire),
+            elapsed_seconds: elapsed.as_secs_f64(),
+            stages: self
+                .stages()
+                .into_iter()
+                .map(|(name, stage)| stage.status(name, elapsed))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn stages(&self) -> [(&'static str, &StageMetrics); 6] {
+        [
+            ("walk", &self.walk),
+            ("filter", &self.filter),
+            ("decode", &self.decode),
+            ("batch", &self.batch),
+            ("infer", &self.infer),
+            ("write", &self.write),
+        ]
+    }
+}
+
+/// Which part of a media file an embedding belongs to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Part {
+    Whole,
+    Keyframe(f64),
+    Frame(u32),
+    Region(Region),
+}
+
+/// Facts about a file stored on its record, shared by all of its parts.
+#[derive(Debug, Clone, Default)]
+struct FileInfo {
+    content_hash: Option<String>,
+    phash: Option<i64>,
+    width: Option<u32>,
+    height: Option<u32>,
+    file_size: Option<u64>,
+    modified: Option<i64>,
+}
+
+impl FileInfo {
+    fn read(path: &Path, content_hash: Option<String>) -> Self {
+        let metadata = std::fs::metadata(path).ok();
+        Self {
+            content_hash,
+            file_size: metadata.as_ref().map(|metadata| metadata.len()),
+            modified: metadata
+                .and_then(|metadata| metadata.modified().ok())
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_secs() as i64),
+            ..Default::default()
+        }
+    }
+}
+
+struct PreparedImage {
+    image_path: String,
+    pixels: Vec<f32>,
+    file: FileInfo,
+    kind: MediaKind,
+    part: Part,
+    /// Number of parts of the file, its records are written once all of them are embedded.
+    parts: usize,
+}
+
+struct EmbeddedPart {
+    image_path: String,
+    embedding: Vec<f32>,
+    file: FileInfo,
+    kind: MediaKind,
+    part: Part,
+    parts: usize,
+}
+
+/// Indexes all new images in the media directory. The work runs as a pipeline of stages
+/// connected by bounded channels (walk → filter → decode → batch → infer → write), so decoding
+/// on the CPU overlaps with inference on the GPU and a slow stage throttles the ones before it.
+pub async fn embed_all_images_in_dir(state: &AppState) -> Result<(), IndexError> {
+    let db: Surreal<Client> = init_database(&state.arguments).await?;
+    let metrics = &state.scan_metrics;
+    let media_dir = state.arguments.shellexpand_media_dir()?;
+    info!("Searching directory {media_dir:?}.");
+    let all_image_paths = {
+        let started = Instant::now();
+        let index_videos = state.arguments.index_videos;
+        let paths =
+            tokio::task::spawn_blocking(move || walk_media_dir(&media_dir, index_videos)).await?;
+        metrics.walk.record(paths.len(), started.elapsed());
+        paths
+    };
+    info!("Found {} images in directory.", all_image_paths.len());
+    load_batch_size_limit(state, &db).await?;
+
+    let image_chunk_size = state.arguments.image_chunk_size;
+    let inference_batch_size = state.arguments.inference_batch_size;
+    let (path_sender, path_receiver) = mpsc::channel::<NewFile>(image_chunk_size);
+    let (prepared_sender, prepared_receiver) =
+        mpsc::channel::<PreparedImage>(2 * inference_batch_size);
+    let (embedded_sender, embedded_receiver) = mpsc::channel::<Vec<EmbeddedPart>>(2);
+    let (failure_sender, failure_receiver) = mpsc::unbounded_channel::<DecodeFailure>();
+
+    let decode_state = state.clone();
+    let decode = tokio::task::spawn_blocking(move || {
+        decode_stage(&decode_state, path_receiver, prepared_sender, failure_sender)
+    });
+    let (alternates, ..) = tokio::try_join!(
+        filter_stage(state, &db, all_image_paths, path_sender),
+        batch_stage(state, prepared_receiver, embedded_sender),
+        write_stage(state, &db, embedded_receiver),
+        failure_stage(&db, failure_receiver),
+    )?;
+    decode.await?;
+    store_alternate_paths(&db, alternates).await?;
+    store_batch_size_limit(state, &db).await?;
+    if state.arguments.index_regions {
+        match backfill_regions(state, &db).await {
+            Ok(0) => {}
+            Ok(backfilled) => info!("Added regions to {backfilled} images indexed without them."),
+            Err(err) => error!("Failed to add regions to images indexed without them: {err}"),
+        }
+    }
+
+    // searches need the text and the vector index of the new images
+    let media_dir_str = state
+        .arguments
+        .shellexpand_media_dir()?
+        .into_os_string()
+        .into_string()
+        .map_err(|path| format!("media dir {path:?} is not valid UTF-8"))?;
+    let indexed = update_text_index(&db, &media_dir_str).await?;
+    if indexed > 0 {
+        info!("Indexed the paths of {indexed} new images for full-text search.");
+    }
+    let index_update_result = db.query(
+        "DEFINE INDEX IF NOT EXISTS mt_pts ON image FIELDS embedding MTREE DIMENSION 768 DIST COSINE TYPE F32;")
+        .query("
+        REBUILD INDEX IF EXISTS mt_pts ON image;").await;
+    if let Err(e) = index_update_result {
+        error!("Failed to update index: {}", e);
+        return Err(e.into());
+    }
+
+    // the rest only feeds optional features, a failure there leaves the new images searchable
+    match tag_images(state, &db, true).await {
+        Ok(0) => {}
+        Ok(tagged) => info!("Tagged {tagged} new images."),
+        Err(err) => error!("Failed to tag new images: {err}"),
+    }
+    match update_duplicate_groups(&db, state.arguments.duplicate_threshold, false).await {
+        Ok(Some(groups)) => info!("Found {groups} groups of duplicates."),
+        Ok(None) => {}
+        Err(err) => error!("Failed to update duplicate groups: {err}"),
+    }
+    if let Err(err) = update_clusters(state, &db, false).await {
+        error!("Failed to cluster images: {err}");
+    }
+    if let Err(err) = update_map(&db, false).await {
+        error!("Failed to place images on the map: {err}");
+    }
+    if let Err(err) = score_new_images(&db).await {
+        error!("Failed to score new images with the concepts: {err}");
+    }
+    Ok(())
+}
+
+/// Starts the vision worker from the batch size limit learned on this device in an earlier scan.
+async fn load_batch_size_limit(state: &AppState, db: &Surreal<Client>) -> Result<(), IndexError> {
+    if state.vision.batch_size_limit().is_some() {
+        return Ok(());
+    }
+    let mut response = db
+        .query("SELECT VALUE max_batch_size FROM type::thing('inference_device', $device)")
+        .bind(("device", state.vision.device_name()))
+        .await?;
+    let limit: Option<usize> = response.take(0)?;
+    if let Some(limit) = limit {
+        state.vision.remember_batch_size_limit(limit);
+    }
+    Ok(())
+}
+
+async fn store_batch_size_limit(state: &AppState, db: &Surreal<Client>) -> Result<(), IndexError> {
+    if let Some(limit) = state.vision.batch_size_limit() {
+        info!("Largest batch that fits on {}: {limit} images", state.vision.device_name());
+        db.query("UPSERT type::thing('inference_device', $device) SET max_batch_size = $limit, updated_at = time::now()")
+            .bind(("device", state.vision.device_name()))
+            .bind(("limit", limit))
+            .await?;
+    }
+    Ok(())
+}
+
+fn walk_media_dir(media_dir: &Path, index_videos: bool) -> Vec<String> {
+    let mut all_image_paths: Vec<String> = WalkDir::new(media_dir)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(|entry| {
+            // permission errors are encountered here
+            entry.inspect_err(|error| error!("Image load error: {:?}", error))
+        }.ok())
+        .filter(|e| e.metadata().is_ok_and(|file| file.is_file()))
+        .filter(|e| decoders::is_supported(e.path()) || (index_videos && is_video(e.path())))
+        .map(|e| e.path().display().to_string())
+        .collect();
+    all_image_paths.shuffle(&mut rand::rng());
+    all_image_paths
+}
+
+/// A file that is not indexed yet, with the hash of its contents.
+struct NewFile {
+    image_path: String,
+    content_hash: Option<String>,
+}
+
+/// Paths of a file whose contents are indexed under another path.
+#[derive(Debug, Serialize)]
+struct AlternatePaths {
+    content_hash: String,
+    paths: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IndexedContent {
+    content_hash: String,
+}
+
+/// Drops paths that are already in the database or quarantined after a failed decode of the same
+/// file contents, checked one DB chunk at a time. The remaining files are hashed, a file whose
+/// contents are already indexed or queued under another path is returned as an alternate path
+/// instead of being decoded and embedded again.
+async fn filter_stage(
+    state: &AppState,
+    db: &Surreal<Client>,
+    all_image_paths: Vec<String>,
+    path_sender: mpsc::Sender<NewFile>,
+) -> Result<Vec<AlternatePaths>, IndexError> {
+    let mut queued_hashes: HashSet<String> = HashSet::new();
+    let mut alternates: HashMap<String, Vec<String>> = HashMap::new();
+    for image_paths in all_image_paths.chunks(state.arguments.image_chunk_size) {
+        let started = Instant::now();
+        let mut response = db
+            .query("SELECT image_path FROM image WHERE image_path IN $paths")
+            .query("SELECT image_path, fingerprint FROM image_failure WHERE image_path IN $paths")
+            .query("SELECT VALUE alternate_paths FROM image WHERE parent IS NONE AND alternate_paths CONTAINSANY $paths")
+            .bind(("paths", image_paths.to_vec()))
+            .await?;
+        let mut existing_paths: HashSet<String> = response
+            .take::<Vec<ImagePathResult>>(0)?
+            .into_iter()
+            .map(|img| img.image_path)
+            .collect();
+        let quarantined: HashMap<String, Option<String>> = response
+            .take::<Vec<FailureFingerprint>>(1)?
+            .into_iter()
+            .map(|failure| (failure.image_path, failure.fingerprint))
+            .collect();
+        existing_paths.extend(response.take::<Vec<Vec<String>>>(2)?.into_iter().flatten());
+        let new_paths: Vec<String> = image_paths
+            .iter()
+            .filter(|p| !existing_paths.contains(p.as_str()))
+            .filter(|p| match quarantined.get(p.as_str()) {
+                Some(known) => known.is_none() || *known != fingerprint(Path::new(p)),
+                None => true,
+            })
+            .cloned()
+            .collect();
+
+        let hashed: Vec<(String, Option<String>)> = tokio::task::spawn_blocking(move || {
+            new_paths
+                .into_par_iter()
+                .map(|path| {
+                    let content_hash = content_hash(Path::new(&path));
+                    (path, content_hash)
+                })
+                .collect()
+        })
+        .await?;
+        let hashes: Vec<String> = hashed.iter().filter_map(|(_, hash)| hash.clone()).collect();
+        let mut response = db
+            .query("SELECT content_hash FROM image WHERE parent IS NONE AND content_hash IN $hashes")
+            .bind(("hashes", hashes))
+            .await?;
+        let indexed: HashSet<String> = response
+            .take::<Vec<IndexedContent>>(0)?
+            .into_iter()
+            .map(|content| content.content_hash)
+            .collect();
+
+        let mut new_files = Vec::with_capacity(hashed.len());
+        for (image_path, content_hash) in hashed {
+            match content_hash {
+                Some(hash) if indexed.contains(&hash) || queued_hashes.contains(&hash) => {
+                    alternates.entry(hash).or_default().push(image_path);
+                }
+                content_hash => {
+                    queued_hashes.extend(content_hash.clone());
+                    new_files.push(NewFile {
+                        image_path,
+                        content_hash,
+                    });
+                }
+            }
+        }
+        info!(
+            "Found {} images in chunk of which are {} new, {} quarantined",
+            &image_paths.len(),
+            new_files.len(),
+            quarantined.len()
+        );
+        state.scan_metrics.filter.record(image_paths.len(), started.elapsed());
+        for new_file in new_files {
+            if path_sender.send(new_file).await.is_err() {
+                // the pipeline was torn down because a later stage failed
+                return Ok(Vec::new());
+            }
+        }
+    }
+    Ok(alternates
+        .into_iter()
+        .map(|(content_hash, paths)| AlternatePaths { content_hash, paths })
+        .collect())
+}
+
+/// BLAKE3 hash of the file contents, `None` if the file can't be read.
+fn content_hash(path: &Path) -> Option<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher
+        .update_reader(File::open(path).ok()?)
+        .inspect_err(|err| error!("Failed to hash {path:?}: {err}"))
+        .ok()?;
+    Some(hasher.finalize().to_hex().to_string())
+}
+
+/// Links paths with already indexed contents to the record of those contents.
+async fn store_alternate_paths(db: &Surreal<Client>, alternates: Vec<AlternatePaths>) -> Result<(), IndexError> {
+    if alternates.is_empty() {
+        return Ok(());
+    }
+    info!("Linking {} files to identical indexed files.", alternates.len());
+    db.query(
+        "FOR $alternate IN $alternates {
+            UPDATE image SET alternate_paths = array::union(alternate_paths OR [], $alternate.paths)
+            WHERE parent IS NONE AND content_hash = $alternate.content_hash;
+        }",
+    )
+    .bind(("alternates", alternates))
+    .await?
+    .check()?;
+    Ok(())
+}
+
+/// Decodes and preprocesses images on the rayon pool. Takes up to one inference batch of paths
+/// at a time so the number of decoded images in flight stays bounded.
+fn decode_stage(
+    state: &AppState,
+    mut path_receiver: mpsc::Receiver<NewFile>,
+    prepared_sender: mpsc::Sender<PreparedImage>,
+    failure_sender: mpsc::UnboundedSender<DecodeFailure>,
+) {
+    let group_size = state.arguments.inference_batch_size;
+    while let Some(first) = path_receiver.blocking_recv() {
+        let mut group = vec![first];
+        while group.len() < group_size {
+            match path_receiver.try_recv() {
+                Ok(path) => group.push(path),
+                Err(_) => break,
+            }
+        }
+        group
+            .into_par_iter()
+            .for_each_with(prepared_sender.clone(), |sender, new_file| {
+                let started = Instant::now();
+                let NewFile {
+                    image_path,
+                    content_hash,
+                } = new_file;
+                let prepared = match prepare(state, &image_path, content_hash) {
+                    Ok(prepared) => prepared,
+                    Err(err) => {
+                        error!("Failed to open image {}: {}", image_path, err);
+                        let _ = failure_sender.send(DecodeFailure::new(image_path, &err));
+                        return;
+                    }
+                };
+                state.scan_metrics.decode.record(prepared.len(), started.elapsed());
+                for prepared_image in prepared {
+                    let _ = sender.blocking_send(prepared_image);
+                }
+            });
+    }
+}
+
+/// Decodes a file into the images to embed: the image itself and its region tiles, sampled frames
+/// of an animation or the keyframes of a video.
+fn prepare(
+    state: &AppState,
+    image_path: &str,
+    content_hash: Option<String>,
+) -> Result<Vec<PreparedImage>, ImageError> {
+    let path = Path::new(image_path);
+    let file = FileInfo::read(path, content_hash);
+    if !is_video(path) {
+        if let Some(frames) = decoders::animation_frames(path)? {
+            let parts = frames.len();
+            return Ok(frames
+                .into_iter()
+                .map(|(index, frame)| PreparedImage {
+                    image_path: image_path.to_string(),
+                    pixels: image_prepare_resnet(frame),
+                    file: file.clone(),
+                    kind: MediaKind::Image,
+                    part: Part::Frame(index),
+                    parts,
+                })
+                .collect());
+        }
+        let img = decoders::open(path)?;
+        let file = FileInfo {
+            phash: Some(dhash(&img)),
+            width: Some(img.width()),
+            height: Some(img.height()),
+            ..file
+        };
+        let tiles = if state.arguments.index_regions {
+            region_tiles(&img, state.arguments.region_grid)
+        } else {
+            Vec::new()
+        };
+        let parts = tiles.len() + 1;
+        let mut prepared = vec![PreparedImage {
+            image_path: image_path.to_string(),
+            pixels: image_prepare_resnet(img),
+            file: file.clone(),
+            kind: MediaKind::Image,
+            part: Part::Whole,
+            parts,
+        }];
+        prepared.extend(tiles.into_iter().map(|(region, tile)| PreparedImage {
+            image_path: image_path.to_string(),
+            pixels: image_prepare_resnet(tile),
+            file: file.clone(),
+            kind: MediaKind::Image,
+            part: Part::Region(region),
+            parts,
+        }));
+        return Ok(prepared);
+    }
+
+    let sampling = match state.arguments.scene_threshold {
+        Some(threshold) => KeyframeSampling::SceneChange(threshold),
+        None => KeyframeSampling::Interval(state.arguments.keyframe_interval),
+    };
+    let keyframes = extract_keyframes(path, sampling)?;
+    if keyframes.is_empty() {
+        return Err(ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Name("video".to_string()),
+            "no keyframes found",
+        )));
+    }
+    let parts = keyframes.len();
+    Ok(keyframes
+        .into_iter()
+        .map(|(timestamp, frame)| PreparedImage {
+            image_path: image_path.to_string(),
+            pixels: image_prepare_resnet(frame),
+            file: file.clone(),
+            kind: MediaKind::Video,
+            part: Part::Keyframe(timestamp),
+            parts,
+        })
+        .collect())
+}
+
+/// Collects prepared images into inference batches and runs them through the vision worker.
+async fn batch_stage(
+    state: &AppState,
+    mut prepared_receiver: mpsc::Receiver<PreparedImage>,
+    embedded_sender: mpsc::Sender<Vec<EmbeddedPart>>,
+) -> Result<(), IndexError> {
+    let inference_batch_size = state.arguments.inference_batch_size;
+    let mut batch: Vec<PreparedImage> = Vec::with_capacity(inference_batch_size);
+    loop {
+        let next = prepared_receiver.recv().await;
+        let closed = next.is_none();
+        batch.extend(next);
+        if batch.len() >= inference_batch_size || (closed && !batch.is_empty()) {
+            let images = infer_batch(state, std::mem::take(&mut batch)).await?;
+            if embedded_sender.send(images).await.is_err() {
+                return Ok(());
+            }
+        }
+        if closed {
+            return Ok(());
+        }
+    }
+}
+
+async fn infer_batch(state: &AppState, batch: Vec<PreparedImage>) -> Result<Vec<EmbeddedPart>, IndexError> {
+    let started = Instant::now();
+    let pixels: Vec<&[f32]> = batch.iter().map(|image| image.pixels.as_slice()).collect();
+    state.scan_metrics.batch.record(batch.len(), started.elapsed());
+
+    let started = Instant::now();
+    let embeddings = match state.vision.embed_adaptive(&pixels).await {
+        Ok(embeddings) => embeddings,
+        Err(err @ (InferenceError::Failed(_) | InferenceError::OutOfMemory(_))) => {
+            // only this batch is lost, the images are retried on the next scan
+            warn!("Skipping batch of {} images: {err}", batch.len());
+            return Ok(Vec::new());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    state.scan_metrics.infer.record(batch.len(), started.elapsed());
+
+    Ok(batch
+        .into_iter()
+        .zip(embeddings)
+        .map(|(image, embedding)| EmbeddedPart {
+            image_path: image.image_path,
+            embedding,
+            file: image.file,
+            kind: image.kind,
+            part: image.part,
+            parts: image.parts,
+        })
+        .collect())
+}
+
+/// Collects the parts of each file and inserts the records one DB chunk at a time.
+async fn write_stage(
+    state: &AppState,
+    db: &Surreal<Client>,
+    mut embedded_receiver: mpsc::Receiver<Vec<EmbeddedPart>>,
+) -> Result<(), IndexError> {
+    let image_chunk_size = state.arguments.image_chunk_size;
+    let mut pending: HashMap<String, Vec<EmbeddedPart>> = HashMap::new();
+    let mut buffer: Vec<ImageType> = Vec::with_capacity(image_chunk_size);
+    let mut written_paths: Vec<String> = Vec::new();
+    loop {
+        let next = embedded_receiver.recv().await;
+        let closed = next.is_none();
+        for part in next.into_iter().flatten() {
+            let image_path = part.image_path.clone();
+            let parts = pending.entry(image_path.clone()).or_default();
+            let complete = parts.len() + 1 == part.parts;
+            parts.push(part);
+            if complete {
+                let parts = pending.remove(&image_path).unwrap_or_default();
+                buffer.extend(media_records(parts));
+                written_paths.push(image_path);
+            }
+        }
+        if buffer.len() >= image_chunk_size || (closed && !buffer.is_empty()) {
+            let started = Instant::now();
+            let image_types = std::mem::take(&mut buffer);
+            let written_paths = std::mem::take(&mut written_paths);
+            db.insert::<Vec<ImageType>>("image")
+                .content(image_types)
+                .await?;
+            // images that failed before and were fixed leave the quarantine
+            db.query("DELETE image_failure WHERE image_path IN $paths")
+                .bind(("paths", written_paths.clone()))
+                .await?;
+            state.scan_metrics.write.record(written_paths.len(), started.elapsed());
+        }
+        if closed {
+            if !pending.is_empty() {
+                warn!("{} files are missing parts and are retried on the next scan", pending.len());
+            }
+            return Ok(());
+        }
+    }
+}
+
+/// Turns the embedded parts of one file into records. A plain image is a single record. Videos,
+/// animations and images with regions get a record per part pointing to a parent record, which
+/// holds the embedding of the whole image or else the mean of the parts.
+fn media_records(mut parts: Vec<EmbeddedPart>) -> Vec<ImageType> {
+    let whole = parts
+        .iter()
+        .position(|part| part.part == Part::Whole)
+        .map(|index| parts.remove(index));
+    let first = whole.as_ref().or(parts.first());
+    let Some(first) = first else {
+        return Vec::new();
+    };
+    let id = if parts.is_empty() {
+        None
+    } else {
+        let key = random_key();
+        Some(RecordId::from(("image", key)))
+    };
+    let embedding = match &whole {
+        Some(whole) => whole.embedding.clone(),
+        None => {
+            let slices: Vec<&[f32]> = parts.iter().map(|part| part.embedding.as_slice()).collect();
+            average_slices(&slices)
+        }
+    };
+    let mut records = vec![ImageType {
+        id: id.clone(),
+        image_path: first.image_path.clone(),
+        embedding,
+        kind: first.kind,
+        parent: None,
+        timestamp: None,
+        frame: None,
+        region: None,
+        phash: first.file.phash,
+        width: first.file.width,
+        height: first.file.height,
+        file_size: first.file.file_size,
+        modified: first.file.modified,
+        content_hash: first.file.content_hash.clone(),
+    }];
+    records.extend(parts.into_iter().map(|part| ImageType {
+        id: None,
+        image_path: part.image_path,
+        embedding: part.embedding,
+        kind: part.kind,
+        parent: id.clone(),
+        timestamp: match part.part {
+            Part::Keyframe(timestamp) => Some(timestamp),
+            _ => None,
+        },
+        frame: match part.part {
+            Part::Frame(index) => Some(index),
+            _ => None,
+        },
+        region: match part.part {
+            Part::Region(region) => Some(region),
+            _ => None,
+        },
+        phash: None,
+        width: None,
+        height: None,
+        file_size: None,
+        modified: None,
+        content_hash: None,
+    }));
+    records
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct UncheckedImage {
+    id: RecordId,
+    image_path: String,
+}
+
+/// Adds region records to still images indexed without them, e.g. before `--index-regions` was
+/// enabled. Every image is checked once and then marked with `region_grid`, images that already
+/// have parts are only marked. Returns the number of images that got regions.
+async fn backfill_regions(state: &AppState, db: &Surreal<Client>) -> Result<usize, IndexError> {
+    let grid = state.arguments.region_grid;
+    let mut backfilled = 0;
+    loop {
+        let mut response = db
+            .query(
+                "SELECT id, image_path FROM image
+                WHERE parent IS NONE AND kind != 'video' AND region_grid IS NONE LIMIT $limit",
+            )
+            .bind(("limit", state.arguments.image_chunk_size))
+            .await?;
+        let images: Vec<UncheckedImage> = response.take(0)?;
+        if images.is_empty() {
+            return Ok(backfilled);
+        }
+        let ids: Vec<RecordId> = images.iter().map(|image| image.id.clone()).collect();
+        let mut response = db
+            .query("SELECT VALUE parent FROM image WHERE parent IN $ids")
+            .bind(("ids", ids.clone()))
+            .await?;
+        let with_parts: HashSet<RecordId> = response.take::<Vec<RecordId>>(0)?.into_iter().collect();
+        let images: Vec<UncheckedImage> = images
+            .into_iter()
+            .filter(|image| !with_parts.contains(&image.id))
+            .collect();
+
+        for group in images.chunks(state.arguments.inference_batch_size) {
+            let Some((regioned, records)) = region_records(state, group.to_vec(), grid).await? else {
+                // images that got no regions yet are retried on the next scan
+                return Ok(backfilled);
+            };
+            if !records.is_empty() {
+                db.insert::<Vec<ImageType>>("image").content(records).await?;
+            }
+            backfilled += regioned;
+        }
+        db.query("UPDATE image SET region_grid = $grid WHERE id IN $ids")
+            .bind(("grid", grid))
+            .bind(("ids", ids))
+            .await?
+            .check()?;
+    }
+}
+
+struct RegionTile {
+    parent: RecordId,
+    image_path: String,
+    region: Region,
+    pixels: Vec<f32>,
+}
+
+/// Embeds the region tiles of `images` as records pointing to the image. Returns the number of
+/// images that could be decoded with their records, `None` if the vision model failed.
+async fn region_records(
+    state: &AppState,
+    images: Vec<UncheckedImage>,
+    grid: usize,
+) -> Result<Option<(usize, Vec<ImageType>)>, IndexError> {
+    let tiles: Vec<Vec<RegionTile>> = tokio::task::spawn_blocking(move || {
+        images
+            .into_par_iter()
+            .map(|image| match decoders::open(Path::new(&image.image_path)) {
+                Ok(img) => region_tiles(&img, grid)
+                    .into_iter()
+                    .map(|(region, tile)| RegionTile {
+                        parent: image.id.clone(),
+                        image_path: image.image_path.clone(),
+                        region,
+                        pixels: image_prepare_resnet(tile),
+                    })
+                    .collect(),
+                Err(err) => {
+                    error!("Failed to open image {}: {}", image.image_path, err);
+                    Vec::new()
+                }
+            })
+            .collect()
+    })
+    .await?;
+    let regioned = tiles.iter().filter(|tiles| !tiles.is_empty()).count();
+    let tiles: Vec<RegionTile> = tiles.into_iter().flatten().collect();
+
+    let mut records = Vec::with_capacity(tiles.len());
+    for batch in tiles.chunks(state.arguments.inference_batch_size) {
+        let pixels: Vec<&[f32]> = batch.iter().map(|tile| tile.pixels.as_slice()).collect();
+        let embeddings = match state.vision.embed_adaptive(&pixels).await {
+            Ok(embeddings) => embeddings,
+            Err(err @ (InferenceError::Failed(_) | InferenceError::OutOfMemory(_))) => {
+                warn!("Stopping the region backfill: {err}");
+                return Ok(None);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        records.extend(
+            batch
+                .iter()
+                .zip(embeddings)
+                .map(|(tile, embedding)| ImageType {
+                    id: None,
+                    image_path: tile.image_path.clone(),
+                    embedding,
+                    kind: MediaKind::Image,
+                    parent: Some(tile.parent.clone()),
+                    timestamp: None,
+                    frame: None,
+                    region: Some(tile.region),
+                    phash: None,
+                    width: None,
+                    height: None,
+                    file_size: None,
+                    modified: None,
+                    content_hash: None,
+                }),
+        );
+    }
+    Ok(Some((regioned, records)))
+}
+
+/// Records images that could not be decoded so later scans skip them.
+async fn failure_stage(
+    db: &Surreal<Client>,
+    mut failure_receiver: mpsc::UnboundedReceiver<DecodeFailure>,
+) -> Result<(), IndexError> {
+    while let Some(failure) = failure_receiver.recv().await {
+        store_failure(db, failure).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use crate::AppState;
+use crate::search::image_record_id;
+use crate::tags::{LabelPrompts, Vocabulary, label_embeddings};
+use crate::vector::{cosine_distance_with_norm, norm};
+use axum::Json;
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::StatusCode;
+use data::Tag;
+use log::error;
+use serde::Deserialize;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::Surreal;
+
+const DEFAULT_K: usize = 10;
+const BUILTIN_TEMPLATE: &str = "a photo of {}";
+/// General concepts every library is described with, the tag vocabulary adds to them.
+const BUILTIN_LABELS: &[&str] = &[
+    "a person", "a group of people", "a child", "a baby", "a selfie", "a portrait", "a crowd",
+    "a dog", "a cat", "a bird", "a horse", "a cow", "a fish", "an insect", "a wild animal",
+    "a car", "a bicycle", "a motorcycle", "a bus", "a train", "an airplane", "a boat",
+    "a street", "a city skyline", "a building", "a house", "a church", "a bridge", "a road",
+    "a room", "a kitchen", "a bedroom", "an office", "a restaurant", "a shop",
+    "a beach", "the sea", "a lake", "a river", "a waterfall", "a mountain", "a forest",
+    "a field", "a garden", "a desert", "snow", "a sunset", "the night sky", "clouds",
+    "flowers", "a tree", "food", "a drink", "a cake", "fruit", "vegetables",
+    "a computer", "a phone", "a screen", "a book", "a document", "a whiteboard", "text",
+    "a sign", "a map", "a chart", "a drawing", "a painting", "a screenshot", "a meme",
+    "a concert", "a party", "a wedding", "a birthday", "sports", "a football match",
+    "a museum", "a playground", "a swimming pool", "a car interior", "an airport",
+    "a christmas tree", "fireworks", "a tent", "a bonfire", "a boat trip", "a hike",
+    "a black and white photo", "a blurry photo", "a close-up", "an aerial view",
+];
+
+#[derive(Debug)]
+pub struct LabelEmbedding {
+    label: String,
+    embedding: Vec<f32>,
+    norm: f32,
+}
+
+/// Text embeddings of the label vocabulary, computed on first use and kept in memory.
+#[derive(Default)]
+pub struct LabelCache {
+    labels: RwLock<Option<Arc<Vec<LabelEmbedding>>>>,
+}
+
+impl LabelCache {
+    /// Forgets the labels so a changed vocabulary is picked up on the next request.
+    pub fn clear(&self) {
+        *self.labels.write().unwrap() = None;
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LabelsQuery {
+    #[serde(default)]
+    k: Option<usize>,
+}
+
+/// Describes an indexed image with the labels nearest to its embedding, best first.
+pub async fn web_image_labels(
+    State(state): State<AppState>,
+    UrlPath(image_id): UrlPath<String>,
+    Query(query): Query<LabelsQuery>,
+) -> Result<Json<Vec<Tag>>, StatusCode> {
+    let id = image_record_id(&image_id)?;
+    let db = &state.db;
+    let mut response = db
+        .query("SELECT VALUE embedding FROM ONLY $id")
+        .bind(("id", id))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let embedding: Option<Vec<f32>> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let embedding = embedding.ok_or(StatusCode::NOT_FOUND)?;
+
+    let labels = label_index(&state, db).await.map_err(|err| {
+        error!("Failed to load labels: {err}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(nearest_labels(
+        &embedding,
+        &labels,
+        query.k.unwrap_or(DEFAULT_K),
+    )))
+}
+
+/// Embeddings of the built-in and vocabulary labels, from memory after the first call.
+pub async fn label_index(
+    state: &AppState,
+    db: &Surreal<Client>,
+) -> Result<Arc<Vec<LabelEmbedding>>, Box<dyn Error + Send + Sync>> {
+    if let Some(labels) = state.labels.labels.read().unwrap().clone() {
+        return Ok(labels);
+    }
+    let prompts = vocabulary(state)?;
+    let embeddings = label_embeddings(state, db, &prompts).await?;
+    let labels: Arc<Vec<LabelEmbedding>> = Arc::new(
+        prompts
+            .into_iter()
+            .zip(embeddings)
+            .map(|(prompts, embedding)| LabelEmbedding {
+                label: prompts.label,
+                norm: norm(&embedding),
+                embedding,
+            })
+            .collect(),
+    );
+    *state.labels.labels.write().unwrap() = Some(labels.clone());
+    Ok(labels)
+}
+
+/// Built-in labels followed by the labels of `--tag-vocabulary` that are not built in.
+fn vocabulary(state: &AppState) -> Result<Vec<LabelPrompts>, Box<dyn Error + Send + Sync>> {
+    let mut labels: Vec<LabelPrompts> = BUILTIN_LABELS
+        .iter()
+        .map(|label| LabelPrompts {
+            label: label.to_string(),
+            prompts: vec![BUILTIN_TEMPLATE.replace("{}", label)],
+        })
+        .collect();
+    if let Some(path) = &state.arguments.tag_vocabulary {
+        for user_label in Vocabulary::load(path)?.prompts() {
+            if !labels.iter().any(|label| label.label == user_label.label) {
+                labels.push(user_label);
+            }
+        }
+    }
+    Ok(labels)
+}
+
+pub fn nearest_labels(embedding: &[f32], labels: &[LabelEmbedding], k: usize) -> Vec<Tag> {
+    let mut scored: Vec<Tag> = labels
+        .iter()
+        .filter(|label| label.embedding.len() == embedding.len())
+        .map(|label| Tag {
+            label: label.label.clone(),
+            score: 1.0 - cosine_distance_with_norm(&label.embedding, label.norm, embedding),
+        })
+        .collect();
+    scored.sort_by(|a, b| b.score.total_cmp(&a.score));
+    scored.truncate(k);
+    scored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(label: &str, embedding: Vec<f32>) -> LabelEmbedding {
+        LabelEmbedding {
+            label: label.to_string(),
+            norm: norm(&embedding),
+            embedding,
+        }
+    }
+
+    #[test]
+    fn test_nearest_labels() {
+        let labels = vec![
+            label("a beach", vec![1.0, 0.0]),
+            label("a forest", vec![0.0, 1.0]),
+            label("a sunset", vec![1.0, 1.0]),
+        ];
+        let nearest = nearest_labels(&[1.0, 0.2], &labels, 2);
+        let names: Vec<&str> = nearest.iter().map(|tag| tag.label.as_str()).collect();
+        assert_eq!(names, vec!["a beach", "a sunset"]);
+    }
+}
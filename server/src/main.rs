@@ -1,37 +1,107 @@
 #![recursion_limit = "256"]
-use crate::clip::init_embedder;
+use crate::albums::{
+    web_add_album_images, web_album, web_album_images, web_albums, web_create_album,
+    web_delete_album, web_remove_album_images, web_reorder_album, web_update_album,
+};
+use crate::bench::run_bench;
+use crate::clusters::{web_cluster_images, web_clusters, web_rebuild_clusters};
+use crate::concepts::{
+    web_concept, web_concept_images, web_concepts, web_create_concept, web_delete_concept,
+    web_retrain_concept, web_update_concept,
+};
 use crate::database::init_database;
-use crate::search::{web_scan, web_search_text};
-use crate::server_arguments::ServerArguments;
-use axum::routing::post;
+use crate::derivatives::web_derivative;
+use crate::duplicates::{web_duplicates, web_refresh_duplicates};
+use crate::evaluation::run_evaluation;
+use crate::failures::{web_failures, web_retry_failures};
+use crate::heatmap::web_heatmap;
+use crate::history::{web_clear_history, web_history};
+use crate::indexer::ScanMetrics;
+use crate::labels::{LabelCache, web_image_labels};
+use crate::map::{web_map_tile, web_rebuild_map};
+use crate::ratings::web_rate_image;
+use crate::recall::run_recall;
+use crate::saved_searches::{
+    web_delete_saved_search, web_run_saved_search, web_save_search, web_saved_searches,
+    web_update_saved_search,
+};
+use crate::search::{web_scan, web_scan_status, web_search_text};
+use crate::server_arguments::{Command, ServerArguments};
+use crate::sessions::{web_add_round, web_create_session, web_round, web_session};
+use crate::tags::{web_retag, web_tags};
+use crate::text_embedder::TextEmbedder;
+use crate::text_search::web_update_caption;
+use crate::vision::VisionWorker;
+use axum::routing::{post, put};
 use axum::{routing::get, Router};
 use clap::Parser;
-use embed_anything::embeddings::embed::Embedder;
+use data::{MediaKind, Region};
 use env_logger::Env;
 use log::info;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use surrealdb::engine::remote::ws::Client;
 use surrealdb::{RecordId, Surreal};
-use tokio::sync::Mutex;
 use tower_http::services::{ServeDir, ServeFile};
 
+mod albums;
+mod bench;
 mod clip;
+mod clusters;
+mod concepts;
 mod database;
+mod decoders;
+mod derivatives;
+mod diversity;
+mod duplicates;
+mod evaluation;
+mod failures;
+mod heatmap;
+mod history;
+mod indexer;
+mod labels;
+mod map;
+mod ratings;
+mod recall;
+mod regions;
+mod saved_searches;
 mod search;
 mod server_arguments;
+mod sessions;
+mod suggestions;
+mod tags;
+mod text_embedder;
+mod text_search;
+mod vector;
+mod video;
+mod vision;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct DbImage {
     id: RecordId,
     image_path: String,
+    distance: f32,
+    #[serde(default)]
+    kind: MediaKind,
+    /// Set on keyframes and animation frames, points to the record of the whole file.
+    #[serde(default)]
+    parent: Option<RecordId>,
+    #[serde(default)]
+    timestamp: Option<f64>,
+    #[serde(default)]
+    frame: Option<u32>,
+    #[serde(default)]
+    region: Option<Region>,
 }
 
 #[derive(Clone)]
 pub struct AppState {
     pub arguments: ServerArguments,
-    pub db: Arc<Mutex<Surreal<Client>>>,
-    pub embedder: Arc<Mutex<Embedder>>,
+    pub db: Surreal<Client>,
+    pub embedder: TextEmbedder,
+    pub vision: VisionWorker,
+    pub scan_metrics: Arc<ScanMetrics>,
+    pub labels: Arc<LabelCache>,
 }
 
 async fn tokio_main() -> anyhow::Result<()> {
@@ -42,14 +112,85 @@ async fn tokio_main() -> anyhow::Result<()> {
 
     let app_state = AppState {
         arguments: cla.clone(),
-        db: Arc::new(Mutex::new(init_database(&cla).await.unwrap())),
-        embedder: Arc::new(Mutex::new(init_embedder().await.unwrap())),
+        db: init_database(&cla).await.unwrap(),
+        embedder: TextEmbedder::spawn(cla.text_embedding_workers, cla.text_embedding_cache)
+            .await
+            .map_err(|err| anyhow::anyhow!(err))?,
+        vision: VisionWorker::spawn(cla.model_weights.clone())?,
+        scan_metrics: Arc::new(ScanMetrics::default()),
+        labels: Arc::new(LabelCache::default()),
     };
 
+    match &cla.command {
+        Some(Command::Recall { queries, k, sample }) => {
+            return run_recall(&app_state, queries, *k, *sample).await;
+        }
+        Some(Command::Evaluate {
+            queries,
+            k,
+            config,
+            compare,
+        }) => {
+            return run_evaluation(&app_state, queries, *k, config.as_deref(), compare.as_deref())
+                .await;
+        }
+        Some(Command::Bench {
+            queries,
+            concurrency,
+            requests,
+        }) => {
+            return run_bench(&app_state, queries, *concurrency, *requests).await;
+        }
+        None => {}
+    }
+
     let media_dir = cla.shellexpand_media_dir()?;
     let app = Router::new()
         .route("/search", post(web_search_text))
         .route("/scan", get(web_scan))
+        .route("/scan/status", get(web_scan_status))
+        .route("/failures", get(web_failures))
+        .route("/failures/retry", post(web_retry_failures))
+        .route("/derivative/{*path}", get(web_derivative))
+        .route("/heatmap", post(web_heatmap))
+        .route("/tags", get(web_tags))
+        .route("/tags/retag", post(web_retag))
+        .route("/images/{id}/labels", get(web_image_labels))
+        .route("/images/{id}/rating", put(web_rate_image))
+        .route("/images/{id}/caption", put(web_update_caption))
+        .route("/duplicates", get(web_duplicates))
+        .route("/duplicates/refresh", post(web_refresh_duplicates))
+        .route("/clusters", get(web_clusters))
+        .route("/clusters/rebuild", post(web_rebuild_clusters))
+        .route("/clusters/{id}/images", get(web_cluster_images))
+        .route("/map/tiles/{zoom}/{x}/{y}", get(web_map_tile))
+        .route("/map/rebuild", post(web_rebuild_map))
+        .route("/concepts", get(web_concepts).post(web_create_concept))
+        .route(
+            "/concepts/{id}",
+            get(web_concept).put(web_update_concept).delete(web_delete_concept),
+        )
+        .route("/concepts/{id}/retrain", post(web_retrain_concept))
+        .route("/concepts/{id}/images", get(web_concept_images))
+        .route("/sessions", post(web_create_session))
+        .route("/sessions/{id}", get(web_session))
+        .route("/sessions/{id}/rounds", post(web_add_round))
+        .route("/sessions/{id}/rounds/{round}", get(web_round))
+        .route("/saved-searches", get(web_saved_searches).post(web_save_search))
+        .route(
+            "/saved-searches/{id}",
+            put(web_update_saved_search).delete(web_delete_saved_search),
+        )
+        .route("/saved-searches/{id}/run", post(web_run_saved_search))
+        .route("/history", get(web_history).delete(web_clear_history))
+        .route("/albums", get(web_albums).post(web_create_album))
+        .route(
+            "/albums/{id}",
+            get(web_album).put(web_update_album).delete(web_delete_album),
+        )
+        .route("/albums/{id}/images", get(web_album_images).post(web_add_album_images))
+        .route("/albums/{id}/images/remove", post(web_remove_album_images))
+        .route("/albums/{id}/order", put(web_reorder_album))
         .with_state(app_state)
         .nest_service("/media", ServeDir::new(&media_dir))
         .fallback_service(
@@ -67,8 +208,6 @@ async fn tokio_main() -> anyhow::Result<()> {
 fn main() {
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
-        // increase thread stack size to 32 MB to prevent stack overflow when embedding images with WGPU
-        .thread_stack_size(32 * 1024 * 1024)
         .build()
         .unwrap()
         .block_on(async {
@@ -0,0 +1,519 @@
+use crate::AppState;
+use crate::clusters::kmeans;
+use crate::database::load_images;
+use crate::derivatives::preview_path;
+use crate::failures::media_dir_str;
+use crate::vector::{cosine_distance_with_norm, norm};
+use axum::Json;
+use axum::extract::{Path as UrlPath, State};
+use axum::http::StatusCode;
+use data::{MapPoint, MapTile};
+use log::{error, info};
+use rand::Rng;
+use rand::prelude::SliceRandom;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::{RecordId, Surreal};
+
+/// Dimensions kept by the PCA before neighbours are searched.
+const PCA_DIMENSIONS: usize = 32;
+const POWER_ITERATIONS: usize = 100;
+/// Number of neighbours every image is pulled towards in the layout.
+const NEIGHBOURS: usize = 15;
+/// Up to this many images all pairs are compared to find neighbours. Above, neighbours are only
+/// searched within the same k-means bucket.
+const EXACT_NEIGHBOURS_LIMIT: usize = 5000;
+const EPOCHS: usize = 200;
+const NEGATIVE_SAMPLES: usize = 5;
+/// Curve parameters UMAP uses for a minimum distance of 0.1.
+const CURVE_A: f32 = 1.577;
+const CURVE_B: f32 = 0.895;
+/// Free border around the layout, in map units.
+const MARGIN: f32 = 0.02;
+/// Deepest zoom level, a tile then covers 1/4096 of the width of the map.
+const MAX_ZOOM: u32 = 12;
+/// Upper bound of images per tile, the ones with the lowest `map_rank` are returned.
+const TILE_POINTS: usize = 200;
+/// Number of positions written per round trip.
+const WRITE_CHUNK_SIZE: usize = 1000;
+
+/// Projects the vectors onto their first `dimensions` principal components, found by power
+/// iteration on the covariance matrix with deflation.
+pub fn pca<R: Rng>(vectors: &[&[f32]], dimensions: usize, rng: &mut R) -> Vec<Vec<f32>> {
+    let Some(first) = vectors.first() else {
+        return Vec::new();
+    };
+    let dimension = first.len();
+    let mut mean = vec![0.0f64; dimension];
+    for vector in vectors {
+        for (mean, value) in mean.iter_mut().zip(*vector) {
+            *mean += *value as f64;
+        }
+    }
+    mean.iter_mut().for_each(|mean| *mean /= vectors.len() as f64);
+
+    let mut covariance = vectors
+        .par_iter()
+        .fold(
+            || vec![0.0f64; dimension * dimension],
+            |mut covariance, vector| {
+                let centered: Vec<f64> = vector.iter().zip(&mean).map(|(value, mean)| *value as f64 - mean).collect();
+                for (row, a) in covariance.chunks_exact_mut(dimension).zip(&centered) {
+                    for (cell, b) in row.iter_mut().zip(&centered) {
+                        *cell += a * b;
+                    }
+                }
+                covariance
+            },
+        )
+        .reduce(
+            || vec![0.0f64; dimension * dimension],
+            |mut a, b| {
+                a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                a
+            },
+        );
+
+    let mut components: Vec<Vec<f64>> = Vec::with_capacity(dimensions);
+    for _ in 0..dimensions.min(dimension) {
+        let mut component: Vec<f64> = (0..dimension).map(|_| rng.random::<f64>() - 0.5).collect();
+        let mut eigenvalue = 0.0;
+        for _ in 0..POWER_ITERATIONS {
+            let next: Vec<f64> = covariance
+                .chunks_exact(dimension)
+                .map(|row| row.iter().zip(&component).map(|(a, b)| a * b).sum())
+                .collect();
+            let length = next.iter().map(|value| value * value).sum::<f64>().sqrt();
+            if length == 0.0 {
+                // no variance left
+                component.fill(0.0);
+                break;
+            }
+            component = next.into_iter().map(|value| value / length).collect();
+            eigenvalue = length;
+        }
+        for (row, a) in covariance.chunks_exact_mut(dimension).zip(&component) {
+            for (cell, b) in row.iter_mut().zip(&component) {
+                *cell -= eigenvalue * a * b;
+            }
+        }
+        components.push(component);
+    }
+
+    vectors
+        .par_iter()
+        .map(|vector| {
+            components
+                .iter()
+                .map(|component| {
+                    vector
+                        .iter()
+                        .zip(&mean)
+                        .zip(component)
+                        .map(|((value, mean), weight)| (*value as f64 - mean) * weight)
+                        .sum::<f64>() as f32
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+/// The `k` nearest points of every point. Large libraries are split into buckets by k-means and
+/// neighbours are only searched within a bucket, which misses a few neighbours at the borders.
+fn nearest_neighbours<R: Rng>(points: &[Vec<f32>], k: usize, rng: &mut R) -> Vec<Vec<usize>> {
+    let buckets: Vec<Vec<usize>> = if points.len() <= EXACT_NEIGHBOURS_LIMIT {
+        vec![(0..points.len()).collect()]
+    } else {
+        let slices: Vec<&[f32]> = points.iter().map(Vec::as_slice).collect();
+        let clustering = kmeans(&slices, (points.len() as f64).sqrt() as usize, rng);
+        let mut buckets = vec![Vec::new(); clustering.centroids.len()];
+        for (index, (bucket, _)) in clustering.assignments.iter().enumerate() {
+            buckets[*bucket].push(index);
+        }
+        buckets
+    };
+
+    let mut neighbours = vec![Vec::new(); points.len()];
+    for bucket in buckets {
+        let found: Vec<Vec<usize>> = bucket
+            .par_iter()
+            .map(|&point| {
+                let mut distances: Vec<(f32, usize)> = bucket
+                    .iter()
+                    .filter(|&&other| other != point)
+                    .map(|&other| (squared_distance(&points[point], &points[other]), other))
+                    .collect();
+                distances.sort_by(|a, b| a.0.total_cmp(&b.0));
+                distances.into_iter().take(k).map(|(_, other)| other).collect()
+            })
+            .collect();
+        for (point, found) in bucket.into_iter().zip(found) {
+            neighbours[point] = found;
+        }
+    }
+    neighbours
+}
+
+/// UMAP-style layout: neighbours attract each other and randomly drawn points repel. Starts from
+/// the first two principal components so the global arrangement follows the largest variance.
+fn layout<R: Rng>(points: &[Vec<f32>], neighbours: &[Vec<usize>], rng: &mut R) -> Vec<[f32; 2]> {
+    let mut positions: Vec<[f32; 2]> = points
+        .iter()
+        .map(|point| [point.first().copied().unwrap_or(0.0), point.get(1).copied().unwrap_or(0.0)])
+        .collect();
+    let extent = positions
+        .iter()
+        .flat_map(|position| position.iter().map(|value| value.abs()))
+        .fold(0.0, f32::max);
+    if extent > 0.0 {
+        for position in &mut positions {
+            position.iter_mut().for_each(|value| *value *= 10.0 / extent);
+        }
+    }
+    if positions.len() < 2 {
+        return positions;
+    }
+
+    for epoch in 0..EPOCHS {
+        let learning_rate = 1.0 - epoch as f32 / EPOCHS as f32;
+        for (point, point_neighbours) in neighbours.iter().enumerate() {
+            for &neighbour in point_neighbours {
+                let delta = [
+                    positions[point][0] - positions[neighbour][0],
+                    positions[point][1] - positions[neighbour][1],
+                ];
+                let distance = delta[0] * delta[0] + delta[1] * delta[1];
+                if distance > 0.0 {
+                    let coefficient = -2.0 * CURVE_A * CURVE_B * distance.powf(CURVE_B - 1.0)
+                        / (CURVE_A * distance.powf(CURVE_B) + 1.0);
+                    for dimension in 0..2 {
+                        let gradient = (coefficient * delta[dimension]).clamp(-4.0, 4.0) * learning_rate;
+                        positions[point][dimension] += gradient;
+                        positions[neighbour][dimension] -= gradient;
+                    }
+                }
+
+                for _ in 0..NEGATIVE_SAMPLES {
+                    let other = rng.random_range(0..positions.len());
+                    if other == point {
+                        continue;
+                    }
+                    let delta = [
+                        positions[point][0] - positions[other][0],
+                        positions[point][1] - positions[other][1],
+                    ];
+                    let distance = delta[0] * delta[0] + delta[1] * delta[1];
+                    let coefficient =
+                        2.0 * CURVE_B / ((0.001 + distance) * (CURVE_A * distance.powf(CURVE_B) + 1.0));
+                    for dimension in 0..2 {
+                        positions[point][dimension] +=
+                            (coefficient * delta[dimension]).clamp(-4.0, 4.0) * learning_rate;
+                    }
+                }
+            }
+        }
+    }
+    positions
+}
+
+/// Scales the layout into the unit square keeping its aspect ratio, centred with a small margin.
+fn fit_unit_square(positions: &[[f32; 2]]) -> Vec<[f32; 2]> {
+    let mut min = [f32::INFINITY; 2];
+    let mut max = [f32::NEG_INFINITY; 2];
+    for position in positions {
+        for dimension in 0..2 {
+            min[dimension] = min[dimension].min(position[dimension]);
+            max[dimension] = max[dimension].max(position[dimension]);
+        }
+    }
+    let spans = [max[0] - min[0], max[1] - min[1]];
+    let span = spans[0].max(spans[1]);
+    if span <= 0.0 {
+        return vec![[0.5, 0.5]; positions.len()];
+    }
+    positions
+        .iter()
+        .map(|position| {
+            [0, 1].map(|dimension| {
+                let centred = position[dimension] - min[dimension] + (span - spans[dimension]) / 2.0;
+                MARGIN + (1.0 - 2.0 * MARGIN) * centred / span
+            })
+        })
+        .collect()
+}
+
+/// Map coordinates in 0..1 for every vector, similar vectors end up close to each other.
+pub fn project<R: Rng>(vectors: &[&[f32]], rng: &mut R) -> Vec<[f32; 2]> {
+    let reduced = pca(vectors, PCA_DIMENSIONS, rng);
+    let neighbours = nearest_neighbours(&reduced, NEIGHBOURS, rng);
+    fit_unit_square(&layout(&reduced, &neighbours, rng))
+}
+
+/// Position of an image added after the projection: the mean position of its nearest placed images.
+fn place(embedding: &[f32], placed: &[PlacedImage]) -> [f32; 2] {
+    let embedding_norm = norm(embedding);
+    let mut distances: Vec<(f32, [f32; 2])> = placed
+        .iter()
+        .filter(|image| image.embedding.len() == embedding.len())
+        .map(|image| {
+            (
+                cosine_distance_with_norm(embedding, embedding_norm, &image.embedding),
+                [image.map_x, image.map_y],
+            )
+        })
+        .collect();
+    distances.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let nearest = &distances[..distances.len().min(NEIGHBOURS)];
+    if nearest.is_empty() {
+        return [0.5, 0.5];
+    }
+    let sum = nearest
+        .iter()
+        .fold([0.0, 0.0], |sum, (_, position)| [sum[0] + position[0], sum[1] + position[1]]);
+    [sum[0] / nearest.len() as f32, sum[1] / nearest.len() as f32]
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredEmbedding {
+    id: RecordId,
+    embedding: Vec<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlacedImage {
+    id: RecordId,
+    embedding: Vec<f32>,
+    map_x: f32,
+    map_y: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct MapPosition {
+    id: RecordId,
+    map_x: f32,
+    map_y: f32,
+    /// Random order in which images appear when zooming in, lower ranks are shown first.
+    map_rank: u64,
+}
+
+/// Stores `map_x`, `map_y` and `map_rank` on every image without a position. All images are
+/// projected again if none has a position yet, the library more than doubled since the last full
+/// projection or `rebuild` is set. Otherwise new images are placed between their nearest neighbours
+/// and the rest of the map stays where it is. Returns the number of images placed.
+pub async fn update_map(db: &Surreal<Client>, rebuild: bool) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let new: Vec<StoredEmbedding> =
+        load_images(db, "id, embedding", "AND map_x IS NONE", |image: &StoredEmbedding| &image.id).await?;
+    if new.is_empty() && !rebuild {
+        return Ok(0);
+    }
+    let placed: Vec<PlacedImage> = load_images(
+        db,
+        "id, embedding, map_x, map_y",
+        "AND map_x IS NOT NONE",
+        |image: &PlacedImage| &image.id,
+    )
+    .await?;
+
+    let positions = if rebuild || placed.is_empty() || new.len() > placed.len() {
+        drop(placed);
+        drop(new);
+        let images: Vec<StoredEmbedding> =
+            load_images(db, "id, embedding", "", |image: &StoredEmbedding| &image.id).await?;
+        info!("Projecting {} images onto the map.", images.len());
+        tokio::task::spawn_blocking(move || {
+            let mut rng = rand::rng();
+            let vectors: Vec<&[f32]> = images.iter().map(|image| image.embedding.as_slice()).collect();
+            let coordinates = project(&vectors, &mut rng);
+            let mut ranks: Vec<u64> = (0..images.len() as u64).collect();
+            ranks.shuffle(&mut rng);
+            images
+                .into_iter()
+                .zip(coordinates)
+                .zip(ranks)
+                .map(|((image, [map_x, map_y]), map_rank)| MapPosition {
+                    id: image.id,
+                    map_x,
+                    map_y,
+                    map_rank,
+                })
+                .collect::<Vec<_>>()
+        })
+        .await?
+    } else {
+        let total = (placed.len() + new.len()) as u64;
+        let coordinates: Vec<[f32; 2]> = new
+            .par_iter()
+            .map(|image| place(&image.embedding, &placed))
+            .collect();
+        let mut rng = rand::rng();
+        new.into_iter()
+            .zip(coordinates)
+            .map(|(image, [map_x, map_y])| MapPosition {
+                id: image.id,
+                map_x,
+                map_y,
+                map_rank: rng.random_range(0..total),
+            })
+            .collect()
+    };
+
+    let placed_count = positions.len();
+    for chunk in positions.chunks(WRITE_CHUNK_SIZE) {
+        db.query(
+            "FOR $position IN $positions {
+                UPDATE $position.id SET map_x = $position.map_x, map_y = $position.map_y, map_rank = $position.map_rank;
+            }",
+        )
+        .bind(("positions", chunk.to_vec()))
+        .await?
+        .check()?;
+    }
+    info!("Placed {placed_count} images on the map.");
+    Ok(placed_count)
+}
+
+/// Lets tile queries find the images within a tile without reading the whole table.
+pub async fn define_map_index(db: &Surreal<Client>) -> Result<(), surrealdb::Error> {
+    db.query("DEFINE INDEX IF NOT EXISTS image_map_position ON image FIELDS map_x, map_y;")
+        .await?
+        .check()?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredMapPoint {
+    id: RecordId,
+    image_path: String,
+    map_x: f32,
+    map_y: f32,
+}
+
+/// Images of one tile of the map. At zoom level `z` the map is split into 2^z x 2^z tiles counted
+/// from the top left. Dense tiles only return the images with the lowest `map_rank`, a random
+/// sample that gets denser with every zoom level.
+pub async fn web_map_tile(
+    State(state): State<AppState>,
+    UrlPath((zoom, x, y)): UrlPath<(u32, u32, u32)>,
+) -> Result<Json<MapTile>, StatusCode> {
+    if zoom > MAX_ZOOM || x >= 1 << zoom || y >= 1 << zoom {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let media_dir_str = media_dir_str(&state)?;
+    let size = 1.0 / (1u32 << zoom) as f64;
+    let db = &state.db;
+    let mut response = db
+        .query(
+            "SELECT id, image_path, map_x, map_y, map_rank FROM image
+            WHERE parent IS NONE AND map_x >= $left AND map_x < $right AND map_y >= $top AND map_y < $bottom
+            ORDER BY map_rank LIMIT $limit",
+        )
+        .bind(("left", x as f64 * size))
+        .bind(("right", (x + 1) as f64 * size))
+        .bind(("top", y as f64 * size))
+        .bind(("bottom", (y + 1) as f64 * size))
+        .bind(("limit", TILE_POINTS))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let points: Vec<StoredMapPoint> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(MapTile {
+        zoom,
+        x,
+        y,
+        points: points
+            .into_iter()
+            .map(|point| {
+                let image_path = point.image_path.replace(&media_dir_str, "media/");
+                MapPoint {
+                    id: point.id.to_string(),
+                    preview_path: preview_path(&image_path),
+                    image_path,
+                    x: point.map_x,
+                    y: point.map_y,
+                }
+            })
+            .collect(),
+    }))
+}
+
+/// Projects the whole library again.
+pub async fn web_rebuild_map(State(state): State<AppState>) -> Result<Json<usize>, StatusCode> {
+    let db = &state.db;
+    let placed = update_map(db, true).await.map_err(|err| {
+        error!("Failed to project images: {err}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(placed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test_pca() {
+        // points on a line along (1, 1, 0) with a little noise along z
+        let vectors: Vec<[f32; 3]> = (0..10)
+            .map(|step| [step as f32, step as f32, if step % 2 == 0 { 0.1 } else { -0.1 }])
+            .collect();
+        let slices: Vec<&[f32]> = vectors.iter().map(|vector| vector.as_slice()).collect();
+        let reduced = pca(&slices, 1, &mut StdRng::seed_from_u64(1));
+        let expected = std::f32::consts::SQRT_2;
+        let step = (reduced[1][0] - reduced[0][0]).abs();
+        assert!((step - expected).abs() < 1e-3, "{step}");
+    }
+
+    #[test]
+    fn test_project_separates_groups() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let vectors: Vec<Vec<f32>> = (0..60)
+            .map(|index| {
+                let mut vector: Vec<f32> = (0..8).map(|_| rng.random::<f32>() * 0.1).collect();
+                vector[if index % 2 == 0 { 0 } else { 1 }] += 1.0;
+                vector
+            })
+            .collect();
+        let slices: Vec<&[f32]> = vectors.iter().map(Vec::as_slice).collect();
+        let positions = project(&slices, &mut rng);
+        assert!(positions.iter().flatten().all(|value| (0.0..=1.0).contains(value)));
+
+        let centre = |parity: usize| {
+            let members: Vec<&[f32; 2]> = positions.iter().skip(parity).step_by(2).collect();
+            let sum = members.iter().fold([0.0, 0.0], |sum, p| [sum[0] + p[0], sum[1] + p[1]]);
+            [sum[0] / members.len() as f32, sum[1] / members.len() as f32]
+        };
+        let (even, odd) = (centre(0), centre(1));
+        let between = squared_distance(&even, &odd).sqrt();
+        let spread = positions
+            .iter()
+            .enumerate()
+            .map(|(index, position)| squared_distance(position, if index % 2 == 0 { &even } else { &odd }).sqrt())
+            .sum::<f32>()
+            / positions.len() as f32;
+        assert!(between > 2.0 * spread, "between {between}, spread {spread}");
+    }
+
+    #[test]
+    fn test_fit_unit_square() {
+        let fitted = fit_unit_square(&[[-2.0, 0.0], [2.0, 1.0]]);
+        assert!((fitted[0][0] - MARGIN).abs() < 1e-6);
+        assert!((fitted[1][0] - (1.0 - MARGIN)).abs() < 1e-6);
+        // the shorter axis is centred
+        assert!((fitted[0][1] + fitted[1][1] - 1.0).abs() < 1e-6);
+        assert_eq!(fit_unit_square(&[[1.0, 1.0]]), vec![[0.5, 0.5]]);
+    }
+}
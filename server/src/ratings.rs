@@ -0,0 +1,66 @@
+use crate::AppState;
+use crate::search::image_record_id;
+use axum::Json;
+use axum::extract::{Path as UrlPath, State};
+use axum::http::StatusCode;
+use data::ImageRating;
+use log::error;
+use surrealdb::RecordId;
+
+const MAX_RATING: u8 = 5;
+
+/// Similarity moved by up to `boost` towards the rating: five stars add the full boost, zero
+/// stars subtract it and unrated images are treated as average and keep their similarity.
+pub fn boosted_similarity(similarity: f32, rating: Option<u8>, boost: f32) -> f32 {
+    let middle = MAX_RATING as f32 / 2.0;
+    match rating {
+        Some(rating) => similarity + boost * (rating.min(MAX_RATING) as f32 - middle) / middle,
+        None => similarity,
+    }
+}
+
+/// Sets the star rating and the favourite flag of an image.
+pub async fn web_rate_image(
+    State(state): State<AppState>,
+    UrlPath(image_id): UrlPath<String>,
+    Json(rating): Json<ImageRating>,
+) -> Result<Json<ImageRating>, StatusCode> {
+    if rating.rating.is_some_and(|stars| stars > MAX_RATING) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let id = image_record_id(&image_id)?;
+    let db = &state.db;
+    // keyframes, frames and regions are rated through their whole file
+    let mut response = db
+        .query("UPDATE $id SET rating = $rating, favourite = $favourite WHERE parent IS NONE RETURN VALUE id")
+        .bind(("id", id))
+        .bind(("rating", rating.rating))
+        .bind(("favourite", rating.favourite))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let updated: Vec<RecordId> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if updated.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Json(rating))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boosted_similarity() {
+        assert_eq!(boosted_similarity(0.3, None, 0.05), 0.3);
+        assert!((boosted_similarity(0.3, Some(5), 0.05) - 0.35).abs() < 1e-6);
+        assert!((boosted_similarity(0.3, Some(0), 0.05) - 0.25).abs() < 1e-6);
+        // a near tie is decided by the rating
+        assert!(boosted_similarity(0.300, Some(4), 0.05) > boosted_similarity(0.302, Some(2), 0.05));
+    }
+}
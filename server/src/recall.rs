@@ -0,0 +1,125 @@
+use crate::AppState;
+use crate::clip::clip;
+use crate::search::{approximate_nearest, exact_nearest};
+use log::info;
+use rand::prelude::SliceRandom;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Instant;
+
+#[derive(Debug, Serialize)]
+struct QueryRecall {
+    query: String,
+    recall: f32,
+    approximate_ms: f64,
+    exact_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LatencySummary {
+    mean_ms: f64,
+    p50_ms: f64,
+    p95_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct RecallReport {
+    k: usize,
+    queries: usize,
+    mean_recall: f32,
+    approximate_latency: LatencySummary,
+    exact_latency: LatencySummary,
+    per_query: Vec<QueryRecall>,
+}
+
+/// Runs a random sample of the queries in `queries` through the approximate index and the exact
+/// scan and prints a JSON report with recall@k of the index and the latency of both modes. Both
+/// return their hits collapsed to whole files, so a file found through a different part still counts.
+pub async fn run_recall(
+    state: &AppState,
+    queries: &Path,
+    k: usize,
+    sample: usize,
+) -> anyhow::Result<()> {
+    let mut lines: Vec<String> = std::fs::read_to_string(queries)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    lines.shuffle(&mut rand::rng());
+    lines.truncate(sample);
+    info!("Measuring recall@{k} for {} queries.", lines.len());
+
+    let db = &state.db;
+    let mut per_query = Vec::with_capacity(lines.len());
+    for query in lines {
+        let reference = clip(state, query.clone()).await?;
+
+        let started = Instant::now();
+        let approximate = approximate_nearest(db, reference.clone(), k).await?;
+        let approximate_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+        let started = Instant::now();
+        let exact = exact_nearest(db, &reference, k).await?;
+        let exact_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+        let exact_ids: HashSet<String> = exact.iter().map(|img| img.id.to_string()).collect();
+        let hits = approximate
+            .iter()
+            .take(k)
+            .filter(|img| exact_ids.contains(&img.id.to_string()))
+            .count();
+        let recall = if exact_ids.is_empty() {
+            1.0
+        } else {
+            hits as f32 / exact_ids.len() as f32
+        };
+        info!("recall@{k} {recall:.3} for {query:?}");
+        per_query.push(QueryRecall {
+            query,
+            recall,
+            approximate_ms,
+            exact_ms,
+        });
+    }
+
+    let mean_recall = if per_query.is_empty() {
+        0.0
+    } else {
+        per_query.iter().map(|q| q.recall).sum::<f32>() / per_query.len() as f32
+    };
+    let report = RecallReport {
+        k,
+        queries: per_query.len(),
+        mean_recall,
+        approximate_latency: summarize(per_query.iter().map(|q| q.approximate_ms).collect()),
+        exact_latency: summarize(per_query.iter().map(|q| q.exact_ms).collect()),
+        per_query,
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+pub fn summarize(mut latencies: Vec<f64>) -> LatencySummary {
+    if latencies.is_empty() {
+        return LatencySummary {
+            mean_ms: 0.0,
+            p50_ms: 0.0,
+            p95_ms: 0.0,
+        };
+    }
+    latencies.sort_by(f64::total_cmp);
+    LatencySummary {
+        mean_ms: latencies.iter().sum::<f64>() / latencies.len() as f64,
+        p50_ms: percentile(&latencies, 0.50),
+        p95_ms: percentile(&latencies, 0.95),
+    }
+}
+
+/// Nearest-rank percentile of an already sorted slice.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
@@ -0,0 +1,61 @@
+use data::Region;
+use image::DynamicImage;
+
+/// Overlapping crops of an image on a `grid` x `grid` raster. Every crop covers two grid cells
+/// in each direction, so neighbouring crops overlap by half and an object on a cell border is
+/// still fully inside one crop.
+pub fn region_tiles(img: &DynamicImage, grid: usize) -> Vec<(Region, DynamicImage)> {
+    tile_regions(grid)
+        .into_iter()
+        .map(|region| {
+            let x = (region.x * img.width() as f32) as u32;
+            let y = (region.y * img.height() as f32) as u32;
+            let width = ((region.width * img.width() as f32) as u32).max(1);
+            let height = ((region.height * img.height() as f32) as u32).max(1);
+            (region, img.crop_imm(x, y, width, height))
+        })
+        .collect()
+}
+
+/// Bounding boxes of the crops in relative coordinates, row by row.
+fn tile_regions(grid: usize) -> Vec<Region> {
+    if grid < 2 {
+        return Vec::new();
+    }
+    let stride = 1.0 / (grid + 1) as f32;
+    let size = 2.0 * stride;
+    (0..grid)
+        .flat_map(|row| {
+            (0..grid).map(move |column| Region {
+                x: column as f32 * stride,
+                y: row as f32 * stride,
+                width: size,
+                height: size,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    #[test]
+    fn test_tile_regions() {
+        let regions = tile_regions(3);
+        assert_eq!(regions.len(), 9);
+        assert_eq!(regions[0], Region { x: 0.0, y: 0.0, width: 0.5, height: 0.5 });
+        let last = regions[8];
+        assert_eq!((last.x + last.width, last.y + last.height), (1.0, 1.0));
+        assert!(tile_regions(1).is_empty());
+    }
+
+    #[test]
+    fn test_region_tiles() {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(400, 200));
+        let tiles = region_tiles(&img, 3);
+        assert_eq!(tiles.len(), 9);
+        assert!(tiles.iter().all(|(_, tile)| (tile.width(), tile.height()) == (200, 100)));
+    }
+}
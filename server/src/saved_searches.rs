@@ -0,0 +1,137 @@
+use crate::AppState;
+use crate::database::random_key;
+use crate::failures::unix_now;
+use crate::search::search;
+use axum::Json;
+use axum::extract::{Path as UrlPath, State};
+use axum::http::StatusCode;
+use data::{SavedSearch, SavedSearchRequest, SearchParams, SearchResponse};
+use log::error;
+use serde::Deserialize;
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::{RecordId, Surreal};
+
+#[derive(Debug, Deserialize)]
+struct StoredSearch {
+    key: String,
+    name: String,
+    params: SearchParams,
+    created: i64,
+}
+
+impl From<StoredSearch> for SavedSearch {
+    fn from(search: StoredSearch) -> Self {
+        SavedSearch {
+            id: search.key,
+            name: search.name,
+            params: search.params,
+            created: search.created,
+        }
+    }
+}
+
+fn saved_search_id(key: &str) -> RecordId {
+    RecordId::from(("saved_search", key))
+}
+
+async fn load_saved_search(db: &Surreal<Client>, key: &str) -> Result<StoredSearch, StatusCode> {
+    let mut response = db
+        .query("SELECT key, name, params, created FROM ONLY $id")
+        .bind(("id", saved_search_id(key)))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let search: Option<StoredSearch> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    search.ok_or(StatusCode::NOT_FOUND)
+}
+
+/// All saved searches by name.
+pub async fn web_saved_searches(State(state): State<AppState>) -> Result<Json<Vec<SavedSearch>>, StatusCode> {
+    let db = &state.db;
+    let mut response = db
+        .query("SELECT key, name, params, created FROM saved_search ORDER BY name")
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let searches: Vec<StoredSearch> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(searches.into_iter().map(SavedSearch::from).collect()))
+}
+
+/// Saves a search under a name, with its text, feedback images and filters.
+pub async fn web_save_search(
+    State(state): State<AppState>,
+    Json(request): Json<SavedSearchRequest>,
+) -> Result<Json<SavedSearch>, StatusCode> {
+    let params = request.params.ok_or(StatusCode::BAD_REQUEST)?;
+    if request.name.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let key = random_key();
+    let db = &state.db;
+    db.query("CREATE $id SET key = $key, name = $name, params = $params, created = $created")
+        .bind(("id", saved_search_id(&key)))
+        .bind(("key", key.clone()))
+        .bind(("name", request.name))
+        .bind(("params", params))
+        .bind(("created", unix_now()))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(load_saved_search(db, &key).await?.into()))
+}
+
+/// Renames a saved search, and replaces its parameters if the request has any.
+pub async fn web_update_saved_search(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+    Json(request): Json<SavedSearchRequest>,
+) -> Result<Json<SavedSearch>, StatusCode> {
+    if request.name.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let db = &state.db;
+    let current = load_saved_search(db, &key).await?;
+    db.query("UPDATE $id SET name = $name, params = $params")
+        .bind(("id", saved_search_id(&key)))
+        .bind(("name", request.name))
+        .bind(("params", request.params.unwrap_or(current.params)))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(load_saved_search(db, &key).await?.into()))
+}
+
+pub async fn web_delete_saved_search(State(state): State<AppState>, UrlPath(key): UrlPath<String>) -> StatusCode {
+    let db = &state.db;
+    match db.query("DELETE $id").bind(("id", saved_search_id(&key))).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(err) => {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Runs a saved search against the current library.
+pub async fn web_run_saved_search(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+) -> Result<Json<SearchResponse>, StatusCode> {
+    let db = &state.db;
+    let saved = load_saved_search(db, &key).await?;
+    Ok(Json(search(&state, db, saved.params).await?))
+}
@@ -1,112 +1,657 @@
-use crate::clip::{clip, embed_all_images_in_dir};
+use crate::albums::album_image_ids;
+use crate::clip::clip;
+use crate::ratings::boosted_similarity;
+use crate::derivatives::preview_path;
+use crate::diversity::diversify;
+use crate::failures::media_dir_str;
+use crate::history::{record_search, user_id};
+use crate::suggestions::{boundary, closest_to_boundary};
+use crate::text_search::{lexical_nearest, reciprocal_rank_fusion};
+use crate::indexer::embed_all_images_in_dir;
+use crate::vector::{cosine_distance_with_norm, norm};
 use crate::{AppState, DbImage};
 use axum::Json;
 use axum::extract::State;
-use axum::http::StatusCode;
+use axum::http::{HeaderMap, StatusCode};
 use axum::{debug_handler, response::IntoResponse};
-use data::{ImageReference, SearchParams, SearchResponse};
+use data::{
+    ImageReference, MediaKind, QueryWeights, Region, ScanStatus, SearchMode, SearchParams, SearchResponse, Tag,
+};
 use log::{debug, error, info, trace};
+use rayon::iter::{IntoParallelIterator, ParallelExtend, ParallelIterator};
 use serde::{Deserialize, Serialize};
-use surrealdb::RecordId;
+use std::collections::{HashMap, HashSet};
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::{RecordId, Surreal};
 use tokio::runtime::Handle;
 
+/// Number of results returned by a search.
+pub const SEARCH_LIMIT: usize = 1000;
+/// Number of embeddings fetched per round trip when scoring the whole table.
+const EXACT_PAGE_SIZE: usize = 1000;
+/// The KNN query fetches at most this many times `k` records while looking for `k` files. Parts of
+/// the same file share the index with it and take slots of their own.
+const MAX_OVERFETCH: usize = 16;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ImageType {
     pub id: Option<RecordId>,
     pub image_path: String,
     pub embedding: Vec<f32>,
+    #[serde(default)]
+    pub kind: MediaKind,
+    /// Set on keyframes, animation frames and regions, points to the record of the whole file.
+    #[serde(default)]
+    pub parent: Option<RecordId>,
+    #[serde(default)]
+    pub timestamp: Option<f64>,
+    #[serde(default)]
+    pub frame: Option<u32>,
+    #[serde(default)]
+    pub region: Option<Region>,
+    /// Perceptual hash of still images, used to find near-duplicates.
+    #[serde(default)]
+    pub phash: Option<i64>,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub file_size: Option<u64>,
+    #[serde(default)]
+    pub modified: Option<i64>,
+    /// BLAKE3 hash of the file, a file with the same contents under another path is stored in
+    /// `alternate_paths` of this record instead of getting its own.
+    #[serde(default)]
+    pub content_hash: Option<String>,
 }
 
 pub async fn web_search_text(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(params): Json<SearchParams>,
 ) -> Result<Json<SearchResponse>, StatusCode> {
     debug!("Handle Search with params: {:?}", params);
 
-    let db = state.db.lock().await; // oder wie du deine DB-Instanz nutzt
-    let embedding = clip(&state, params.q).await;
-    let mut query_vector = embedding.clone();
+    let db = &state.db;
+    record_search(db, user_id(&headers), &params).await;
+    let response = search(&state, db, params).await?;
+    Ok(Json(response))
+}
 
-    info!("image_paths: {:?}", params.referenced_images);
-    let media_dir = state.arguments.shellexpand_media_dir().expect("media dir could not be loaded");
-    let media_dir_str = media_dir.into_os_string().into_string().expect("media dir could not be converted to string");
+/// Runs a search the same way `/search` does. Shared with the offline tools so they measure
+/// exactly what the client sees.
+pub async fn search(
+    state: &AppState,
+    db: &Surreal<Client>,
+    params: SearchParams,
+) -> Result<SearchResponse, StatusCode> {
+    let media_dir_str = media_dir_str(state)?;
+    let query_vector = query_vector(state, db, &params, &media_dir_str).await?;
+    search_with_vector(db, &params, query_vector, &media_dir_str).await
+}
 
-    if !params.referenced_images.is_empty() {
-        let image_paths: Vec<String> = params
-            .referenced_images
-            .into_iter()
-            .filter(|img| img.starts_with("media/"))
-            .map(|img| img.replacen("media/", &media_dir_str, 1))
-            .collect::<Vec<String>>();
-        trace!("image_paths: {image_paths:?}");
-
-        let mut marked_image_embeddings_response = db
-            .query(
-                "
-        SELECT id, image_path, embedding FROM image WHERE image_path IN $image_paths",
-            )
-            .bind(("image_paths", image_paths))
+/// Ranks the library by an already computed query vector and applies the filters of `params`.
+/// Replaying a stored vector gives the same results as the search that computed it.
+pub async fn search_with_vector(
+    db: &Surreal<Client>,
+    params: &SearchParams,
+    query_vector: Vec<f32>,
+    media_dir_str: &str,
+) -> Result<SearchResponse, StatusCode> {
+    let album_ids = match &params.album {
+        Some(album) => Some(album_image_ids(db, album).await?),
+        None => None,
+    };
+    let candidate_ids = match (album_ids, filtered_image_ids(db, params).await?) {
+        (Some(album), Some(filtered)) => {
+            let filtered: HashSet<RecordId> = filtered.into_iter().collect();
+            Some(album.into_iter().filter(|id| filtered.contains(id)).collect())
+        }
+        (album, filtered) => album.or(filtered),
+    };
+    let vector_hits = if params.mode == SearchMode::Lexical {
+        Vec::new()
+    } else {
+        let nearest = match &candidate_ids {
+            // an album or a filter narrows the search, its images are scored directly
+            Some(ids) => nearest_among(db, ids, &query_vector, SEARCH_LIMIT).await,
+            None if params.exact => exact_nearest(db, &query_vector, SEARCH_LIMIT).await,
+            None => approximate_nearest(db, query_vector.clone(), SEARCH_LIMIT).await,
+        };
+        nearest.map_err(|err| {
+            tracing::error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+    };
+    let mut db_images: Vec<DbImage> = if params.mode == SearchMode::Vector || params.q.trim().is_empty() {
+        vector_hits
+    } else {
+        let reference_norm = norm(&query_vector);
+        let lexical_hits: Vec<DbImage> = lexical_nearest(db, &params.q, SEARCH_LIMIT, candidate_ids)
             .await
             .map_err(|err| {
                 error!("DB query error: {:?}", err);
                 StatusCode::INTERNAL_SERVER_ERROR
-            })?;
-        let marked_image: Vec<ImageType> =
-            marked_image_embeddings_response.take(0).map_err(|err| {
-                error!("Failed to deserialize response: {:?}", err);
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?;
-        debug!("marked_image_embeddings {}", marked_image.len());
-        if !marked_image.is_empty() {
-            let slices = marked_image
-                .iter()
-                .map(|embedding| &embedding.embedding)
-                .collect::<Vec<&Vec<f32>>>();
-            let selected_images_average = average_slices(&slices);
-            query_vector = average_slices(&vec![&selected_images_average, &embedding]);
+            })?
+            .into_iter()
+            .filter_map(|image| scored(image, &query_vector, reference_norm))
+            .collect();
+        match params.mode {
+            SearchMode::Lexical => lexical_hits,
+            _ => fuse(vector_hits, lexical_hits),
         }
+    };
+    if let Some(album) = &params.exclude_album {
+        let excluded: HashSet<RecordId> = album_image_ids(db, album).await?.into_iter().collect();
+        db_images.retain(|img| !excluded.contains(&img.id));
+    }
+
+    let similarities: HashMap<String, f32> = db_images
+        .iter()
+        .map(|img| (img.id.to_string(), 1.0 - img.distance))
+        .collect();
+    let mut images = image_references(db, db_images, media_dir_str).await?;
+    let mut seen_groups = HashSet::new();
+    // results are sorted, so the first member of a group is its best match
+    images.retain(|image| {
+        !params.collapse_duplicates
+            || image
+                .duplicate_group
+                .as_ref()
+                .is_none_or(|group| seen_groups.insert(group.clone()))
+    });
+
+    if params.mode == SearchMode::Vector && params.rating_boost != 0.0 {
+        let score = |image: &ImageReference| {
+            boosted_similarity(
+                similarities.get(&image.id).copied().unwrap_or_default(),
+                image.rating,
+                params.rating_boost,
+            )
+        };
+        images.sort_by(|a, b| score(b).total_cmp(&score(a)));
+    }
+
+    if params.diversity > 0.0 {
+        let relevance: Vec<f32> = images
+            .iter()
+            .enumerate()
+            .map(|(rank, image)| match params.mode {
+                SearchMode::Vector => boosted_similarity(
+                    similarities.get(&image.id).copied().unwrap_or_default(),
+                    image.rating,
+                    params.rating_boost,
+                ),
+                // text matches have no comparable score, their rank stands in for it
+                _ => 1.0 - rank as f32 / images.len() as f32,
+            })
+            .collect();
+        images = diversify(db, images, &relevance, params.diversity.min(1.0)).await?;
+    }
+
+    let uncertain = if params.suggestions == 0 {
+        Vec::new()
+    } else {
+        let candidates: Vec<&ImageReference> = images
+            .iter()
+            .filter(|image| {
+                !params.referenced_images.contains(&image.image_path)
+                    && !params.negative_images.contains(&image.image_path)
+            })
+            .collect();
+        let values: Vec<f32> = candidates
+            .iter()
+            .map(|image| similarities.get(&image.id).copied().unwrap_or_default())
+            .collect();
+        boundary(&values)
+            .map(|boundary| {
+                closest_to_boundary(&values, boundary, params.suggestions)
+                    .into_iter()
+                    .map(|index| candidates[index].clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    // suggested images are shown once, in their own grid
+    let suggested: HashSet<&str> = uncertain.iter().map(|image: &ImageReference| image.id.as_str()).collect();
+    images.retain(|image| !suggested.contains(image.id.as_str()));
+
+    Ok(SearchResponse {
+        images,
+        query_vector,
+        uncertain,
+    })
+}
+
+/// Turns database hits into the references sent to the client, with their tags, duplicate group
+/// and alternate paths.
+pub async fn image_references(
+    db: &Surreal<Client>,
+    db_images: Vec<DbImage>,
+    media_dir_str: &str,
+) -> Result<Vec<ImageReference>, StatusCode> {
+    let mut details = result_details(db, db_images.iter().map(|img| img.id.clone()).collect())
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(db_images
+        .into_iter()
+        .map(|img| {
+            let ResultDetails {
+                tags,
+                duplicate_group,
+                alternate_paths,
+                rating,
+                favourite,
+                caption,
+                notes,
+                ..
+            } = details.remove(&img.id).unwrap_or_default();
+            let image_path = img.image_path.replace(media_dir_str, "media/");
+            ImageReference {
+                id: img.id.to_string(),
+                preview_path: preview_path(&image_path),
+                image_path,
+                kind: img.kind,
+                timestamp: img.timestamp,
+                frame: img.frame,
+                region: img.region,
+                tags,
+                duplicate_group,
+                alternate_paths: alternate_paths
+                    .iter()
+                    .map(|path| path.replace(media_dir_str, "media/"))
+                    .collect(),
+                rating,
+                favourite,
+                caption,
+                notes,
+            }
+        })
+        .collect())
+}
+
+/// Parses an image id from a URL. Ids of other tables are rejected, so endpoints for images can't
+/// write to albums, concepts or sessions.
+pub fn image_record_id(image_id: &str) -> Result<RecordId, StatusCode> {
+    let id: RecordId = image_id.parse().map_err(|err| {
+        error!("Invalid image id {}: {:?}", image_id, err);
+        StatusCode::BAD_REQUEST
+    })?;
+    if id.table() != "image" {
+        error!("Not an image id: {}", image_id);
+        return Err(StatusCode::BAD_REQUEST);
     }
+    Ok(id)
+}
 
-    let query = r#"
-        SELECT
-            id,
-            image_path,
-            vector::distance::knn() AS similarity
-        FROM image
-        WHERE embedding <| 1000 |> $reference;
-    "#;
+/// The images with the given ids, in the same order.
+pub async fn images_in_order(db: &Surreal<Client>, ids: Vec<RecordId>) -> Result<Vec<DbImage>, StatusCode> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut response = db
+        .query("SELECT id, image_path, kind, 0.0 AS distance FROM image WHERE id IN $ids")
+        .bind(("ids", ids.clone()))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let mut images: Vec<DbImage> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    images.sort_by_key(|image| ids.iter().position(|id| *id == image.id));
+    Ok(images)
+}
 
+/// Stored fields of the results that the nearest neighbour queries don't return.
+#[derive(Debug, Deserialize, Default)]
+struct ResultDetails {
+    id: Option<RecordId>,
+    #[serde(default)]
+    tags: Vec<Tag>,
+    #[serde(default)]
+    duplicate_group: Option<String>,
+    #[serde(default)]
+    alternate_paths: Vec<String>,
+    #[serde(default)]
+    rating: Option<u8>,
+    #[serde(default)]
+    favourite: bool,
+    #[serde(default)]
+    caption: Option<String>,
+    #[serde(default)]
+    notes: Option<String>,
+}
+
+async fn result_details(
+    db: &Surreal<Client>,
+    ids: Vec<RecordId>,
+) -> Result<HashMap<RecordId, ResultDetails>, surrealdb::Error> {
     let mut response = db
-        .query(query)
-        .bind(("reference", query_vector))
+        .query("SELECT id, tags OR [] AS tags, duplicate_group, alternate_paths OR [] AS alternate_paths, rating, favourite OR false AS favourite, caption, notes FROM image WHERE id IN $ids")
+        .bind(("ids", ids))
+        .await?;
+    let details: Vec<ResultDetails> = response.take(0)?;
+    Ok(details
+        .into_iter()
+        .filter_map(|details| Some((details.id.clone()?, details)))
+        .collect())
+}
+
+/// Embeds the text query and mixes in the average embeddings of the referenced and the negative
+/// images.
+pub async fn query_vector(
+    state: &AppState,
+    db: &Surreal<Client>,
+    params: &SearchParams,
+    media_dir_str: &str,
+) -> Result<Vec<f32>, StatusCode> {
+    let embedding = clip(state, params.q.clone()).await.map_err(|err| {
+        error!("Failed to embed the query: {err}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    info!("image_paths: {:?}", params.referenced_images);
+    let positive = average_embedding(db, &params.referenced_images, media_dir_str).await?;
+    let negative = average_embedding(db, &params.negative_images, media_dir_str).await?;
+    Ok(mix_query(
+        &embedding,
+        positive.as_deref(),
+        negative.as_deref(),
+        params.weights,
+    ))
+}
+
+/// Average embedding of the given `media/` paths, `None` if none of them is indexed.
+async fn average_embedding(
+    db: &Surreal<Client>,
+    paths: &[String],
+    media_dir_str: &str,
+) -> Result<Option<Vec<f32>>, StatusCode> {
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    let image_paths: Vec<String> = paths
+        .iter()
+        .filter(|img| img.starts_with("media/"))
+        .map(|img| img.replacen("media/", media_dir_str, 1))
+        .collect::<Vec<String>>();
+    trace!("image_paths: {image_paths:?}");
+
+    let mut marked_image_embeddings_response = db
+        .query(
+            "
+        SELECT id, image_path, embedding FROM image
+        WHERE (image_path IN $image_paths OR alternate_paths CONTAINSANY $image_paths) AND parent IS NONE",
+        )
+        .bind(("image_paths", image_paths))
         .await
         .map_err(|err| {
-            tracing::error!("DB query error: {:?}", err);
+            error!("DB query error: {:?}", err);
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
+    let marked_image: Vec<ImageType> =
+        marked_image_embeddings_response.take(0).map_err(|err| {
+            error!("Failed to deserialize response: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    debug!("marked_image_embeddings {}", marked_image.len());
+    if marked_image.is_empty() {
+        return Ok(None);
+    }
+    let slices = marked_image
+        .iter()
+        .map(|embedding| embedding.embedding.as_slice())
+        .collect::<Vec<&[f32]>>();
+    Ok(Some(average_slices(&slices)))
+}
 
-    let db_images: Vec<DbImage> = response.take(0).map_err(|err| {
-        tracing::error!("Failed to deserialize response: {:?}", err);
+/// Weighted mix of the text embedding with the average of the referenced images, moved away
+/// from the average of the negative images (Rocchio feedback). Without referenced images the
+/// text alone is the starting point.
+pub fn mix_query(
+    text: &[f32],
+    positive: Option<&[f32]>,
+    negative: Option<&[f32]>,
+    weights: QueryWeights,
+) -> Vec<f32> {
+    let mut query = match positive {
+        Some(positive) if weights.text + weights.positive > 0.0 => {
+            let total = weights.text + weights.positive;
+            text.iter()
+                .zip(positive)
+                .map(|(t, p)| (weights.text * t + weights.positive * p) / total)
+                .collect()
+        }
+        _ => text.to_vec(),
+    };
+    if let Some(negative) = negative {
+        for (value, n) in query.iter_mut().zip(negative) {
+            *value -= weights.negative * n;
+        }
+    }
+    query
+}
+
+/// The `k` nearest files from the MTREE index, each with its best matching part. Fast, but may
+/// miss some of the true top `k`. Keyframes, frames and regions are in the index too, so more
+/// neighbours are fetched until `k` distinct files are found.
+pub async fn approximate_nearest(
+    db: &Surreal<Client>,
+    reference: Vec<f32>,
+    k: usize,
+) -> Result<Vec<DbImage>, surrealdb::Error> {
+    let mut fetch = k;
+    loop {
+        let query = format!(
+            r#"
+            SELECT
+                id,
+                image_path,
+                kind,
+                parent,
+                timestamp,
+                frame,
+                region,
+                vector::distance::knn() AS distance
+            FROM image
+            WHERE embedding <| {fetch} |> $reference;
+        "#
+        );
+
+        let mut response = db.query(query).bind(("reference", reference.clone())).await?;
+        let mut db_images: Vec<DbImage> = response.take(0)?;
+        let exhausted = db_images.len() < fetch;
+        db_images.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        let mut nearest = collapse_parts(db_images);
+        if nearest.len() >= k || exhausted || fetch >= k * MAX_OVERFETCH {
+            nearest.truncate(k);
+            return Ok(nearest);
+        }
+        fetch = (fetch * 4).min(k * MAX_OVERFETCH);
+    }
+}
+
+/// The `k` nearest files by scoring every stored embedding, each with its best matching part. The
+/// table is streamed page by page so only one page of vectors is held in memory at a time.
+pub async fn exact_nearest(
+    db: &Surreal<Client>,
+    reference: &[f32],
+    k: usize,
+) -> Result<Vec<DbImage>, surrealdb::Error> {
+    let reference_norm = norm(reference);
+    let mut nearest: Vec<DbImage> = Vec::with_capacity(k + EXACT_PAGE_SIZE);
+    let mut last_id: Option<RecordId> = None;
+    loop {
+        let mut response = match &last_id {
+            None => {
+                db.query("SELECT id, image_path, embedding, kind, parent, timestamp, frame, region FROM image ORDER BY id LIMIT $limit")
+                    .bind(("limit", EXACT_PAGE_SIZE))
+                    .await?
+            }
+            Some(last_id) => {
+                db.query("SELECT id, image_path, embedding, kind, parent, timestamp, frame, region FROM image WHERE id > $last ORDER BY id LIMIT $limit")
+                    .bind(("last", last_id.clone()))
+                    .bind(("limit", EXACT_PAGE_SIZE))
+                    .await?
+            }
+        };
+        let page: Vec<ImageType> = response.take(0)?;
+        let page_len = page.len();
+        last_id = page.last().and_then(|image| image.id.clone());
+
+        nearest.par_extend(
+            page.into_par_iter()
+                .filter_map(|image| scored(image, reference, reference_norm)),
+        );
+        nearest.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        nearest = collapse_parts(nearest);
+        nearest.truncate(k);
+
+        if page_len < EXACT_PAGE_SIZE || last_id.is_none() {
+            break;
+        }
+    }
+    Ok(nearest)
+}
+
+/// Whole files with all the tags of `params` and the wanted rating and favourite flag, `None`
+/// without such filters. Filtering before the ranking finds the best matches among the filtered
+/// images even when they are rare among the nearest neighbours of the whole library.
+async fn filtered_image_ids(db: &Surreal<Client>, params: &SearchParams) -> Result<Option<Vec<RecordId>>, StatusCode> {
+    let mut conditions = Vec::new();
+    if !params.tags.is_empty() {
+        conditions.push("$tags ALLINSIDE (tags OR []).label");
+    }
+    if params.favourites_only {
+        conditions.push("favourite = true");
+    }
+    if params.min_rating.is_some() {
+        conditions.push("rating >= $min_rating");
+    }
+    if conditions.is_empty() {
+        return Ok(None);
+    }
+    let mut response = db
+        .query(format!(
+            "SELECT VALUE id FROM image WHERE parent IS NONE AND {}",
+            conditions.join(" AND ")
+        ))
+        .bind(("tags", params.tags.clone()))
+        .bind(("min_rating", params.min_rating))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let ids: Vec<RecordId> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
+    Ok(Some(ids))
+}
+
+/// The `k` nearest of the given files, each scored by its best matching keyframe, frame or
+/// region, one page of files at a time.
+async fn nearest_among(
+    db: &Surreal<Client>,
+    ids: &[RecordId],
+    reference: &[f32],
+    k: usize,
+) -> Result<Vec<DbImage>, surrealdb::Error> {
+    let reference_norm = norm(reference);
+    let mut nearest: Vec<DbImage> = Vec::with_capacity(k + EXACT_PAGE_SIZE);
+    for page in ids.chunks(EXACT_PAGE_SIZE) {
+        let mut response = db
+            .query("SELECT id, image_path, embedding, kind, parent, timestamp, frame, region FROM image WHERE id IN $ids OR parent IN $ids")
+            .bind(("ids", page.to_vec()))
+            .await?;
+        let images: Vec<ImageType> = response.take(0)?;
+        nearest.par_extend(
+            images
+                .into_par_iter()
+                .filter_map(|image| scored(image, reference, reference_norm)),
+        );
+        nearest.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        nearest = collapse_parts(nearest);
+        nearest.truncate(k);
+    }
+    Ok(nearest)
+}
+
+fn scored(image: ImageType, reference: &[f32], reference_norm: f32) -> Option<DbImage> {
+    let distance = cosine_distance_with_norm(reference, reference_norm, &image.embedding);
+    image.id.map(|id| DbImage {
+        id,
+        image_path: image.image_path,
+        distance,
+        kind: image.kind,
+        parent: image.parent,
+        timestamp: image.timestamp,
+        frame: image.frame,
+        region: image.region,
+    })
+}
 
-    let images: Vec<ImageReference> = db_images
+/// Merges the vector and the full-text ranking. Hits found by both keep the vector entry, which
+/// knows the best matching keyframe or region.
+fn fuse(vector_hits: Vec<DbImage>, lexical_hits: Vec<DbImage>) -> Vec<DbImage> {
+    let ranking = reciprocal_rank_fusion(&[
+        vector_hits.iter().map(|img| img.id.clone()).collect(),
+        lexical_hits.iter().map(|img| img.id.clone()).collect(),
+    ]);
+    let mut hits: HashMap<RecordId, DbImage> = lexical_hits
         .into_iter()
-        .map(|img| ImageReference {
-            id: img.id.to_string(),
-            image_path: img.image_path.replace(&media_dir_str, "media/"),
-        })
+        .chain(vector_hits)
+        .map(|img| (img.id.clone(), img))
         .collect();
+    ranking
+        .into_iter()
+        .filter_map(|id| hits.remove(&id))
+        .collect()
+}
 
-    Ok(Json(SearchResponse { images }))
+/// Keyframes and other parts of a file are matched individually. Keeps the best match per file,
+/// identified by the record of the whole file, and drops worse matches of the same file. Collapsing
+/// collapsed hits again changes nothing.
+fn collapse_parts(db_images: Vec<DbImage>) -> Vec<DbImage> {
+    let mut seen = HashSet::new();
+    db_images
+        .into_iter()
+        .filter_map(|img| {
+            let id = img.parent.clone().unwrap_or(img.id);
+            seen.insert(id.clone()).then_some(DbImage {
+                id,
+                parent: None,
+                ..img
+            })
+        })
+        .collect()
 }
 
 #[debug_handler]
 pub async fn web_scan(State(state): State<AppState>) -> impl IntoResponse {
+    run_scan(state).await
+}
+
+/// Indexes the media directory and waits for the scan to finish.
+pub async fn run_scan(state: AppState) -> StatusCode {
+    let Some(scan) = state.scan_metrics.start_guarded() else {
+        info!("Scan requested while another scan is running.");
+        return StatusCode::CONFLICT;
+    };
     let state_cloned = state.clone();
 
     let runtime = Handle::current();
     let result = runtime.spawn(async move {
+            let _scan = scan;
             let result = embed_all_images_in_dir(&state_cloned).await;
             match result {
                 Ok(_) => info!("embedded all images successfully."),
@@ -124,7 +669,17 @@ pub async fn web_scan(State(state): State<AppState>) -> impl IntoResponse {
 
     StatusCode::OK
 }
-fn average_slices(vectors: &Vec<&Vec<f32>>) -> Vec<f32> {
+
+pub async fn web_scan_status(State(state): State<AppState>) -> Json<ScanStatus> {
+    Json(ScanStatus {
+        inference_batch_size: state.arguments.inference_batch_size,
+        batch_size_limit: state.vision.batch_size_limit(),
+        out_of_memory_retries: state.vision.out_of_memory_retries(),
+        ..state.scan_metrics.status()
+    })
+}
+
+pub fn average_slices(vectors: &[&[f32]]) -> Vec<f32> {
     assert!(!vectors.is_empty(), "Input must not be empty");
 
     let len = vectors[0].len();
@@ -152,6 +707,38 @@ fn average_slices(vectors: &Vec<&Vec<f32>>) -> Vec<f32> {
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    fn db_image(key: &str, parent: Option<&str>, distance: f32) -> DbImage {
+        DbImage {
+            id: RecordId::from(("image", key)),
+            image_path: format!("/pictures/{}", parent.unwrap_or(key)),
+            distance,
+            kind: MediaKind::Video,
+            parent: parent.map(|parent| RecordId::from(("image", parent))),
+            timestamp: parent.map(|_| distance as f64),
+            frame: None,
+            region: None,
+        }
+    }
+
+    #[test]
+    fn test_collapse_parts() {
+        let collapsed = collapse_parts(vec![
+            db_image("frame2", Some("clip"), 0.1),
+            db_image("photo", None, 0.2),
+            db_image("frame1", Some("clip"), 0.3),
+            db_image("clip", None, 0.4),
+        ]);
+        assert_eq!(collapsed.len(), 2);
+        assert_eq!(collapsed[0].id, RecordId::from(("image", "clip")));
+        assert_eq!(collapsed[0].timestamp, Some(0.1f32 as f64));
+        assert_eq!(collapsed[1].id, RecordId::from(("image", "photo")));
+        // the nearest neighbour searches collapse page by page
+        let again = collapse_parts(collapsed);
+        assert_eq!(again.len(), 2);
+        assert_eq!(again[0].timestamp, Some(0.1f32 as f64));
+    }
+
     #[test]
     fn tes_average_vector() {
         let a = vec![1.0, 2.0, 4.0, 4.0, 10.0];
@@ -159,4 +746,32 @@ mod tests {
         let result = average_slices(&vec![a.as_slice(), b.as_slice()]);
         assert_eq!(result, vec![1.0, 1.5, 3.0, 4.0, 5.0]);
     }
+
+    #[test]
+    fn test_mix_query() {
+        let text = [1.0, 0.0, 0.0];
+        let positive = [0.0, 1.0, 0.0];
+        let negative = [0.0, 0.0, 1.0];
+        let weights = QueryWeights::default();
+
+        // the default weights keep the plain average of text and referenced images
+        assert_eq!(
+            mix_query(&text, Some(&positive), None, weights),
+            average_slices(&[&positive, &text])
+        );
+        assert_eq!(mix_query(&text, None, None, weights), text.to_vec());
+        assert_eq!(
+            mix_query(&text, None, Some(&negative), weights),
+            vec![1.0, 0.0, -0.25]
+        );
+        let image_heavy = QueryWeights {
+            text: 1.0,
+            positive: 3.0,
+            negative: 0.0,
+        };
+        assert_eq!(
+            mix_query(&text, Some(&positive), Some(&negative), image_heavy),
+            vec![0.25, 0.75, 0.0]
+        );
+    }
 }
@@ -1,7 +1,8 @@
 use std::io;
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use clap::Parser;
+use clap::builder::RangedU64ValueParser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug, Clone)]
 pub struct ServerArguments {
@@ -9,8 +10,47 @@ pub struct ServerArguments {
     pub model_weights: String,
     #[clap(short = 'm', long = "media-dir", default_value = "~/Pictures")]
     pub media_dir: String,
-    #[clap(short = 'c', long = "chunk-size", default_value_t = 500)]
+    #[clap(short = 'c', long = "chunk-size", default_value_t = 500, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
     pub image_chunk_size: usize,
+    /// Number of images per forward pass of the vision model, independent of the DB chunk size.
+    #[clap(short = 'b', long = "inference-batch-size", default_value_t = 32, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub inference_batch_size: usize,
+    /// Cache for browser-viewable versions of HEIC and RAW files.
+    #[clap(long = "derivative-dir", default_value = "./derivatives")]
+    pub derivative_dir: String,
+    /// Also embed overlapping crops of every image so small objects can be found.
+    #[clap(long = "index-regions")]
+    pub index_regions: bool,
+    /// Number of crops per row and column when indexing regions, at least 2.
+    #[clap(long = "region-grid", default_value_t = 3, value_parser = RangedU64ValueParser::<usize>::new().range(2..))]
+    pub region_grid: usize,
+    /// JSON file with labels to tag images with, see `tags::Vocabulary`.
+    #[clap(long = "tag-vocabulary")]
+    pub tag_vocabulary: Option<PathBuf>,
+    /// Minimum cosine similarity between an image and a label for the image to get the tag.
+    #[clap(long = "tag-threshold", default_value_t = 0.24)]
+    pub tag_threshold: f32,
+    /// Minimum cosine similarity of two images with similar perceptual hashes to count as duplicates.
+    #[clap(long = "duplicate-threshold", default_value_t = 0.95)]
+    pub duplicate_threshold: f32,
+    /// Number of clusters the library is grouped into for browsing, 0 disables clustering.
+    #[clap(long = "cluster-count", default_value_t = 32)]
+    pub cluster_count: usize,
+    /// Also index videos by embedding sampled keyframes, needs `ffmpeg` on the PATH.
+    #[clap(long = "index-videos")]
+    pub index_videos: bool,
+    /// Seconds between sampled keyframes of a video.
+    #[clap(long = "keyframe-interval", default_value_t = 10.0, value_parser = positive_seconds)]
+    pub keyframe_interval: f64,
+    /// Sample keyframes at scene changes above this score (0..1) instead of a fixed interval.
+    #[clap(long = "scene-threshold")]
+    pub scene_threshold: Option<f32>,
+    /// Number of threads embedding search texts, each keeps its own copy of the text encoder.
+    #[clap(long = "text-embedding-workers", default_value_t = 2)]
+    pub text_embedding_workers: usize,
+    /// Number of recently used search texts whose embeddings are kept in memory, 0 disables the cache.
+    #[clap(long = "text-embedding-cache", default_value_t = 1024)]
+    pub text_embedding_cache: usize,
     #[clap(short = 'a', long = "addr", default_value = "127.0.0.1")]
     pub addr: String,
     #[clap(short = 'p', long = "port", default_value_t = 3000)]
@@ -25,6 +65,48 @@ pub struct ServerArguments {
     pub surrealdb_namespace: String,
     #[clap(long = "surrealdb-database", default_value = "pictures")]
     pub surrealdb_database: String,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Compare approximate and exact search on a sample of queries and report recall@k and latency.
+    Recall {
+        /// Text file with one query per line.
+        #[clap(short = 'q', long = "queries")]
+        queries: PathBuf,
+        #[clap(short = 'k', long = "k", default_value_t = 100)]
+        k: usize,
+        /// Number of queries randomly drawn from the file.
+        #[clap(short = 's', long = "sample", default_value_t = 50)]
+        sample: usize,
+    },
+    /// Run labelled queries through the search and report recall@k, MRR and nDCG@k.
+    Evaluate {
+        /// JSONL file, each line a search request with an additional `relevant` list of image paths.
+        #[clap(short = 'q', long = "queries")]
+        queries: PathBuf,
+        #[clap(short = 'k', long = "k", default_value_t = 10)]
+        k: usize,
+        /// JSON file with `name`, `prompt_template` and `params` overriding the search request.
+        #[clap(long = "config")]
+        config: Option<PathBuf>,
+        /// Second configuration evaluated side by side with `--config`.
+        #[clap(long = "compare")]
+        compare: Option<PathBuf>,
+    },
+    /// Send searches concurrently, like several users at once, and report the latency percentiles.
+    Bench {
+        /// Text file with one query per line, used in turn until all requests are sent.
+        #[clap(short = 'q', long = "queries")]
+        queries: PathBuf,
+        /// Number of searches in flight at the same time.
+        #[clap(short = 'c', long = "concurrency", default_value_t = 8)]
+        concurrency: usize,
+        #[clap(short = 'n', long = "requests", default_value_t = 200)]
+        requests: usize,
+    },
 }
 
 impl ServerArguments {
@@ -35,4 +117,11 @@ impl ServerArguments {
     pub fn shellexpand_media_dir(&self) -> io::Result<PathBuf> {
         expanduser::expanduser(&self.media_dir)
     }
-}
\ No newline at end of file
+}
+fn positive_seconds(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 && seconds.is_finite() => Ok(seconds),
+        Ok(_) => Err("must be more than 0 seconds".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
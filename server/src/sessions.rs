@@ -0,0 +1,189 @@
+use crate::AppState;
+use crate::database::random_key;
+use crate::failures::{media_dir_str, unix_now};
+use crate::history::{record_search, user_id};
+use crate::search::{query_vector, search_with_vector};
+use axum::Json;
+use axum::extract::{Path as UrlPath, State};
+use axum::http::{HeaderMap, StatusCode};
+use data::{RoundRequest, RoundResponse, SearchParams, SearchSession, SessionRound};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::{RecordId, Surreal};
+
+/// Round as stored in the `rounds` array of a `search_session` record. The query vector is kept
+/// so opening the round later ranks by exactly the same vector, without embedding the text again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredRound {
+    index: usize,
+    parent: Option<usize>,
+    params: SearchParams,
+    query_vector: Vec<f32>,
+    created: i64,
+}
+
+impl StoredRound {
+    fn summary(&self) -> SessionRound {
+        SessionRound {
+            index: self.index,
+            parent: self.parent,
+            params: self.params.clone(),
+            created: self.created,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredSession {
+    key: String,
+    created: i64,
+    #[serde(default)]
+    rounds: Vec<StoredRound>,
+}
+
+fn session_id(key: &str) -> RecordId {
+    RecordId::from(("search_session", key))
+}
+
+async fn load_session(db: &Surreal<Client>, key: &str) -> Result<StoredSession, StatusCode> {
+    let mut response = db
+        .query("SELECT key, created, rounds FROM ONLY $id")
+        .bind(("id", session_id(key)))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let session: Option<StoredSession> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    session.ok_or(StatusCode::NOT_FOUND)
+}
+
+fn to_session(session: StoredSession) -> SearchSession {
+    SearchSession {
+        id: session.key,
+        created: session.created,
+        rounds: session.rounds.iter().map(StoredRound::summary).collect(),
+    }
+}
+
+/// Starts an empty session, its id can be shared to show others the same rounds.
+pub async fn web_create_session(State(state): State<AppState>) -> Result<Json<SearchSession>, StatusCode> {
+    let key = random_key();
+    let db = &state.db;
+    db.query("CREATE $id SET key = $key, created = $created, rounds = []")
+        .bind(("id", session_id(&key)))
+        .bind(("key", key.clone()))
+        .bind(("created", unix_now()))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(to_session(load_session(db, &key).await?)))
+}
+
+/// A session with all its rounds.
+pub async fn web_session(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+) -> Result<Json<SearchSession>, StatusCode> {
+    let db = &state.db;
+    Ok(Json(to_session(load_session(db, &key).await?)))
+}
+
+/// Runs a search and records it as the next round of the session. `parent` names the round that
+/// was refined; pointing it at an earlier round branches the session from there.
+pub async fn web_add_round(
+    State(state): State<AppState>,
+    UrlPath(key): UrlPath<String>,
+    headers: HeaderMap,
+    Json(request): Json<RoundRequest>,
+) -> Result<Json<RoundResponse>, StatusCode> {
+    debug!("Add round to session {key}: {:?}", request);
+    let media_dir_str = media_dir_str(&state)?;
+    let db = &state.db;
+    let session = load_session(db, &key).await?;
+    if request.parent.is_some_and(|parent| parent >= session.rounds.len()) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    record_search(db, user_id(&headers), &request.params).await;
+    let query_vector = query_vector(&state, db, &request.params, &media_dir_str).await?;
+    let created = unix_now();
+    // the index is taken and the parent checked inside the update, so rounds added at the same
+    // time to a shared session get an index each
+    let mut response = db
+        .query(
+            "UPDATE $id SET rounds += {
+                index: array::len(rounds),
+                parent: $parent,
+                params: $params,
+                query_vector: $query_vector,
+                created: $created
+            }
+            WHERE $parent IS NONE OR $parent < array::len(rounds)
+            RETURN VALUE array::last(rounds).index",
+        )
+        .bind(("id", session_id(&key)))
+        .bind(("parent", request.parent))
+        .bind(("params", request.params.clone()))
+        .bind(("query_vector", query_vector.clone()))
+        .bind(("created", created))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let index: Vec<usize> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let round = StoredRound {
+        index: index.into_iter().next().ok_or(StatusCode::BAD_REQUEST)?,
+        parent: request.parent,
+        params: request.params,
+        query_vector: query_vector.clone(),
+        created,
+    };
+
+    let response = search_with_vector(db, &round.params, query_vector, &media_dir_str).await?;
+    Ok(Json(RoundResponse {
+        session: key,
+        round: round.summary(),
+        response,
+    }))
+}
+
+/// Results of an earlier round, ranked by its stored query vector.
+pub async fn web_round(
+    State(state): State<AppState>,
+    UrlPath((key, index)): UrlPath<(String, usize)>,
+) -> Result<Json<RoundResponse>, StatusCode> {
+    let db = &state.db;
+    let mut response = db
+        .query("SELECT VALUE rounds[$index] FROM ONLY $id")
+        .bind(("id", session_id(&key)))
+        .bind(("index", index))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let round: Option<StoredRound> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let round = round.ok_or(StatusCode::NOT_FOUND)?;
+
+    let media_dir_str = media_dir_str(&state)?;
+    let response = search_with_vector(db, &round.params, round.query_vector.clone(), &media_dir_str).await?;
+    Ok(Json(RoundResponse {
+        session: key,
+        round: round.summary(),
+        response,
+    }))
+}
@@ -0,0 +1,71 @@
+/// Number of histogram bins used to find the decision boundary.
+const BINS: usize = 64;
+
+/// Similarity that best splits the values into a high and a low group (Otsu's method): the
+/// threshold maximizing the variance between the two groups. Used as the decision boundary of a
+/// query that has no trained classifier.
+pub fn boundary(values: &[f32]) -> Option<f32> {
+    let min = values.iter().copied().reduce(f32::min)?;
+    let max = values.iter().copied().reduce(f32::max)?;
+    if max <= min {
+        return Some(min);
+    }
+    let bin_width = (max - min) / BINS as f32;
+    let mut histogram = [0usize; BINS];
+    for value in values {
+        histogram[(((value - min) / bin_width) as usize).min(BINS - 1)] += 1;
+    }
+    let center = |bin: usize| min + (bin as f32 + 0.5) * bin_width;
+    let total = values.len() as f32;
+    let total_sum: f32 = histogram.iter().enumerate().map(|(bin, count)| center(bin) * *count as f32).sum();
+
+    let (mut low_count, mut low_sum) = (0.0, 0.0);
+    let mut best = (f32::MIN, min);
+    for (bin, count) in histogram.iter().enumerate().take(BINS - 1) {
+        low_count += *count as f32;
+        low_sum += center(bin) * *count as f32;
+        let high_count = total - low_count;
+        if low_count == 0.0 || high_count == 0.0 {
+            continue;
+        }
+        let low_mean = low_sum / low_count;
+        let high_mean = (total_sum - low_sum) / high_count;
+        let between = low_count * high_count * (high_mean - low_mean).powi(2);
+        if between > best.0 {
+            best = (between, min + (bin + 1) as f32 * bin_width);
+        }
+    }
+    Some(best.1)
+}
+
+/// Indices of the `count` values closest to `boundary`, closest first. These are the results the
+/// model is least sure about, so feedback on them moves the boundary the most.
+pub fn closest_to_boundary(values: &[f32], boundary: f32, count: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..values.len()).collect();
+    indices.sort_by(|a, b| {
+        (values[*a] - boundary)
+            .abs()
+            .total_cmp(&(values[*b] - boundary).abs())
+    });
+    indices.truncate(count);
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boundary() {
+        let values = [0.30, 0.31, 0.29, 0.32, 0.30, 0.20, 0.21, 0.19, 0.22, 0.20, 0.21];
+        let boundary = boundary(&values).unwrap();
+        assert!(boundary > 0.22 && boundary < 0.29, "{boundary}");
+        assert_eq!(super::boundary(&[]), None);
+        assert_eq!(super::boundary(&[0.5, 0.5]), Some(0.5));
+    }
+
+    #[test]
+    fn test_closest_to_boundary() {
+        assert_eq!(closest_to_boundary(&[0.9, 0.55, 0.1, 0.48, 0.7], 0.5, 2), vec![3, 1]);
+    }
+}
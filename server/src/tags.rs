@@ -0,0 +1,303 @@
+use crate::AppState;
+use crate::clip::clip_texts;
+use crate::database::ImagePages;
+use crate::search::average_slices;
+use crate::vector::{cosine_distance_with_norm, norm};
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use data::{Tag, TagCount};
+use log::{error, info};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::{RecordId, Surreal};
+
+/// Upper bound of tags per image, the strongest ones are kept.
+const MAX_TAGS: usize = 10;
+
+/// Labels to tag images with, read from the file given by `--tag-vocabulary`:
+///
+/// ```json
+/// {"templates": ["a photo of {}"], "labels": ["dog", {"label": "selfie", "templates": ["a selfie"]}]}
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Vocabulary {
+    #[serde(default = "default_templates")]
+    templates: Vec<String>,
+    labels: Vec<VocabularyLabel>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum VocabularyLabel {
+    Plain(String),
+    Templated { label: String, templates: Vec<String> },
+}
+
+fn default_templates() -> Vec<String> {
+    vec!["a photo of {}".to_string()]
+}
+
+/// A label with the prompts its text embedding is averaged from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelPrompts {
+    pub label: String,
+    pub prompts: Vec<String>,
+}
+
+impl LabelPrompts {
+    /// Key of the cached embedding, it only depends on the prompts.
+    fn cache_key(&self) -> String {
+        self.prompts.join("\n")
+    }
+}
+
+impl Vocabulary {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Rejects labels without a prompt, there would be nothing to average their embedding from.
+    fn parse(json: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let vocabulary: Self = serde_json::from_str(json)?;
+        if let Some(label) = vocabulary.prompts().iter().find(|label| label.prompts.is_empty()) {
+            return Err(format!("label {:?} has no templates", label.label).into());
+        }
+        Ok(vocabulary)
+    }
+
+    pub fn prompts(&self) -> Vec<LabelPrompts> {
+        self.labels
+            .iter()
+            .map(|label| {
+                let (label, templates) = match label {
+                    VocabularyLabel::Plain(label) => (label, &self.templates),
+                    VocabularyLabel::Templated { label, templates } => (label, templates),
+                };
+                LabelPrompts {
+                    label: label.clone(),
+                    prompts: templates
+                        .iter()
+                        .map(|template| template.replace("{}", label))
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedLabelEmbedding {
+    key: String,
+    embedding: Vec<f32>,
+}
+
+/// Text embeddings of the labels, in the same order. Embeddings are cached in the
+/// `label_embedding` table so only new or changed labels go through the text encoder.
+pub async fn label_embeddings(
+    state: &AppState,
+    db: &Surreal<Client>,
+    labels: &[LabelPrompts],
+) -> Result<Vec<Vec<f32>>, Box<dyn Error + Send + Sync>> {
+    let keys: Vec<String> = labels.iter().map(LabelPrompts::cache_key).collect();
+    let mut response = db
+        .query("SELECT key, embedding FROM label_embedding WHERE key IN $keys")
+        .bind(("keys", keys))
+        .await?;
+    let mut cached: HashMap<String, Vec<f32>> = response
+        .take::<Vec<CachedLabelEmbedding>>(0)?
+        .into_iter()
+        .map(|cached| (cached.key, cached.embedding))
+        .collect();
+
+    let missing: Vec<&LabelPrompts> = labels
+        .iter()
+        .filter(|label| !cached.contains_key(&label.cache_key()))
+        .collect();
+    if !missing.is_empty() {
+        info!("Embedding {} new labels", missing.len());
+        let mut computed = Vec::with_capacity(missing.len());
+        for label in missing {
+            let prompt_embeddings = clip_texts(state, &label.prompts).await?;
+            let slices: Vec<&[f32]> = prompt_embeddings.iter().map(Vec::as_slice).collect();
+            let embedding = average_slices(&slices);
+            cached.insert(label.cache_key(), embedding.clone());
+            computed.push(CachedLabelEmbedding {
+                key: label.cache_key(),
+                embedding,
+            });
+        }
+        db.query("FOR $label IN $labels { UPSERT type::thing('label_embedding', $label.key) CONTENT $label; }")
+            .bind(("labels", computed))
+            .await?
+            .check()?;
+    }
+    Ok(labels
+        .iter()
+        .map(|label| cached.get(&label.cache_key()).cloned().unwrap_or_default())
+        .collect())
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredEmbedding {
+    id: RecordId,
+    embedding: Vec<f32>,
+}
+
+#[derive(Debug, Serialize)]
+struct ImageTags {
+    id: RecordId,
+    tags: Vec<Tag>,
+}
+
+/// Scores the stored embedding of every image against the vocabulary and stores the labels above
+/// `--tag-threshold` as its tags. Only text is embedded, so re-tagging never needs the GPU.
+/// Returns the number of images tagged.
+pub async fn tag_images(
+    state: &AppState,
+    db: &Surreal<Client>,
+    only_untagged: bool,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let Some(vocabulary_path) = &state.arguments.tag_vocabulary else {
+        return Ok(0);
+    };
+    let labels = Vocabulary::load(vocabulary_path)?.prompts();
+    let embeddings = label_embeddings(state, db, &labels).await?;
+    let vocabulary: Vec<(&str, &[f32], f32)> = labels
+        .iter()
+        .zip(&embeddings)
+        .map(|(label, embedding)| (label.label.as_str(), embedding.as_slice(), norm(embedding)))
+        .collect();
+    let threshold = state.arguments.tag_threshold;
+    let filter = if only_untagged { "AND tags IS NONE" } else { "" };
+
+    let mut tagged = 0;
+    let mut pages = ImagePages::new(db, "id, embedding", filter, |image: &StoredEmbedding| &image.id);
+    while let Some(page) = pages.next_page().await? {
+        let images: Vec<ImageTags> = page
+            .into_par_iter()
+            .map(|image| ImageTags {
+                tags: score_tags(&image.embedding, &vocabulary, threshold),
+                id: image.id,
+            })
+            .collect();
+        tagged += images.len();
+        db.query("FOR $image IN $images { UPDATE $image.id SET tags = $image.tags; }")
+            .bind(("images", images))
+            .await?
+            .check()?;
+    }
+    Ok(tagged)
+}
+
+/// Labels whose cosine similarity to the image embedding reaches `threshold`, best first.
+fn score_tags(embedding: &[f32], vocabulary: &[(&str, &[f32], f32)], threshold: f32) -> Vec<Tag> {
+    let mut tags: Vec<Tag> = vocabulary
+        .iter()
+        .filter(|(_, label_embedding, _)| label_embedding.len() == embedding.len())
+        .map(|(label, label_embedding, label_norm)| Tag {
+            label: label.to_string(),
+            score: 1.0 - cosine_distance_with_norm(label_embedding, *label_norm, embedding),
+        })
+        .filter(|tag| tag.score >= threshold)
+        .collect();
+    tags.sort_by(|a, b| b.score.total_cmp(&a.score));
+    tags.truncate(MAX_TAGS);
+    tags
+}
+
+#[derive(Debug, Deserialize)]
+struct TagLabels {
+    labels: Vec<String>,
+}
+
+/// All tags in the library with the number of images carrying them, most frequent first.
+pub async fn web_tags(State(state): State<AppState>) -> Result<Json<Vec<TagCount>>, StatusCode> {
+    let db = &state.db;
+    let mut response = db
+        .query("SELECT tags.label AS labels FROM image WHERE parent IS NONE AND tags IS NOT NONE")
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let images: Vec<TagLabels> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for label in images.into_iter().flat_map(|image| image.labels) {
+        *counts.entry(label).or_default() += 1;
+    }
+    let mut tags: Vec<TagCount> = counts
+        .into_iter()
+        .map(|(label, count)| TagCount { label, count })
+        .collect();
+    tags.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.label.cmp(&b.label)));
+    Ok(Json(tags))
+}
+
+/// Re-reads the vocabulary and re-tags every image from its stored embedding.
+pub async fn web_retag(State(state): State<AppState>) -> Result<Json<usize>, StatusCode> {
+    if state.arguments.tag_vocabulary.is_none() {
+        error!("Re-tagging requested without --tag-vocabulary.");
+        return Err(StatusCode::NOT_FOUND);
+    }
+    state.labels.clear();
+    let db = &state.db;
+    let tagged = tag_images(&state, db, false).await.map_err(|err| {
+        error!("Failed to tag images: {err}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    info!("Tagged {tagged} images.");
+    Ok(Json(tagged))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vocabulary_prompts() {
+        let vocabulary = Vocabulary::parse(
+            r#"{"templates": ["a photo of {}", "{} in the wild"], "labels": ["dog", {"label": "selfie", "templates": ["a selfie"]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vocabulary.prompts(),
+            vec![
+                LabelPrompts {
+                    label: "dog".to_string(),
+                    prompts: vec!["a photo of dog".to_string(), "dog in the wild".to_string()],
+                },
+                LabelPrompts {
+                    label: "selfie".to_string(),
+                    prompts: vec!["a selfie".to_string()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vocabulary_without_prompts() {
+        assert!(Vocabulary::parse(r#"{"labels": [{"label": "selfie", "templates": []}]}"#).is_err());
+        assert!(Vocabulary::parse(r#"{"templates": [], "labels": ["dog"]}"#).is_err());
+        assert!(Vocabulary::parse(r#"{"templates": [], "labels": [{"label": "selfie", "templates": ["a selfie"]}]}"#).is_ok());
+    }
+
+    #[test]
+    fn test_score_tags() {
+        let dog = [1.0, 0.0];
+        let cat = [0.0, 1.0];
+        let vocabulary = vec![("dog", &dog[..], 1.0), ("cat", &cat[..], 1.0)];
+        let tags = score_tags(&[0.8, 0.6], &vocabulary, 0.7);
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].label, "dog");
+        assert!((tags[0].score - 0.8).abs() < 1e-6);
+    }
+}
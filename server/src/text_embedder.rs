@@ -0,0 +1,293 @@
+use crate::clip::init_embedder;
+use log::{error, info};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+/// Requests waiting for a worker, senders wait once the queue is full.
+const QUEUE_LEN: usize = 64;
+/// A worker stops collecting waiting requests into its batch once it has this many texts.
+const MAX_BATCH_TEXTS: usize = 32;
+
+#[derive(Debug, Clone)]
+pub enum EmbeddingError {
+    WorkerStopped,
+    Failed(String),
+}
+
+impl Display for EmbeddingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmbeddingError::WorkerStopped => write!(f, "text embedding workers stopped"),
+            EmbeddingError::Failed(message) => write!(f, "text embedding failed: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for EmbeddingError {}
+
+/// Key of a prompt in the cache. CLIP lowercases its input anyway, so prompts differing only in
+/// case or whitespace get the same embedding.
+pub fn normalize_prompt(prompt: &str) -> String {
+    prompt.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Text embeddings of the most recently used prompts.
+pub struct EmbeddingCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<String, (Vec<f32>, u64)>,
+    /// Prompts by the tick of their last use, the first one is evicted next.
+    recency: BTreeMap<u64, String>,
+}
+
+impl EmbeddingCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, prompt: &str) -> Option<Vec<f32>> {
+        self.tick += 1;
+        let (embedding, used) = self.entries.get_mut(prompt)?;
+        let prompt = self.recency.remove(used).expect("cached prompts have a tick");
+        *used = self.tick;
+        self.recency.insert(self.tick, prompt);
+        Some(embedding.clone())
+    }
+
+    pub fn insert(&mut self, prompt: String, embedding: Vec<f32>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.insert(prompt.clone(), (embedding, self.tick)) {
+            self.recency.remove(&used);
+        }
+        self.recency.insert(self.tick, prompt);
+        while self.entries.len() > self.capacity {
+            let Some((_, evicted)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&evicted);
+        }
+    }
+}
+
+struct TextJob {
+    texts: Vec<String>,
+    reply: oneshot::Sender<Result<Vec<Vec<f32>>, EmbeddingError>>,
+}
+
+/// Handle to the threads that own a copy of the CLIP text encoder each. Requests arriving while
+/// the workers are busy are embedded together in one batch, and recently used prompts are
+/// answered from a cache without waiting for a worker at all.
+#[derive(Clone)]
+pub struct TextEmbedder {
+    sender: mpsc::Sender<TextJob>,
+    cache: Arc<Mutex<EmbeddingCache>>,
+    cache_hits: Arc<AtomicU64>,
+    cache_misses: Arc<AtomicU64>,
+}
+
+impl TextEmbedder {
+    /// Starts the workers and waits until each has loaded the text encoder, so a model that can't
+    /// be loaded stops the server at startup.
+    pub async fn spawn(
+        workers: usize,
+        cache_size: usize,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let (sender, receiver) = mpsc::channel::<TextJob>(QUEUE_LEN);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut loading = Vec::with_capacity(workers.max(1));
+        for worker in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            let (ready, loaded) = oneshot::channel();
+            std::thread::Builder::new()
+                .name(format!("text-embedder-{worker}"))
+                .spawn(move || run_worker(&receiver, ready))?;
+            loading.push(loaded);
+        }
+        for loaded in loading {
+            loaded.await.map_err(|_| EmbeddingError::WorkerStopped)??;
+        }
+        Ok(Self {
+            sender,
+            cache: Arc::new(Mutex::new(EmbeddingCache::new(cache_size))),
+            cache_hits: Arc::new(AtomicU64::new(0)),
+            cache_misses: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Number of prompts answered from the cache and number of prompts that had to be embedded.
+    pub fn cache_stats(&self) -> (u64, u64) {
+        (
+            self.cache_hits.load(Ordering::Relaxed),
+            self.cache_misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Embeds `texts`, in order. Only prompts missing from the cache are sent to a worker.
+    pub async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, EmbeddingError> {
+        let prompts: Vec<String> = texts.iter().map(|text| normalize_prompt(text)).collect();
+        let mut embeddings: Vec<Option<Vec<f32>>> = {
+            let mut cache = self.cache.lock().unwrap();
+            prompts.iter().map(|prompt| cache.get(prompt)).collect()
+        };
+        let mut missing: Vec<String> = prompts
+            .iter()
+            .zip(&embeddings)
+            .filter(|(_, embedding)| embedding.is_none())
+            .map(|(prompt, _)| prompt.clone())
+            .collect();
+        missing.sort();
+        missing.dedup();
+        let hits = embeddings.iter().filter(|embedding| embedding.is_some()).count();
+        self.cache_hits.fetch_add(hits as u64, Ordering::Relaxed);
+        self.cache_misses.fetch_add((prompts.len() - hits) as u64, Ordering::Relaxed);
+
+        if !missing.is_empty() {
+            let (reply, response) = oneshot::channel();
+            self.sender
+                .send(TextJob {
+                    texts: missing.clone(),
+                    reply,
+                })
+                .await
+                .map_err(|_| EmbeddingError::WorkerStopped)?;
+            let computed: HashMap<String, Vec<f32>> = missing
+                .into_iter()
+                .zip(response.await.map_err(|_| EmbeddingError::WorkerStopped)??)
+                .collect();
+            let mut cache = self.cache.lock().unwrap();
+            for (prompt, embedding) in &computed {
+                cache.insert(prompt.clone(), embedding.clone());
+            }
+            for (embedding, prompt) in embeddings.iter_mut().zip(&prompts) {
+                if embedding.is_none() {
+                    *embedding = computed.get(prompt).cloned();
+                }
+            }
+        }
+        embeddings
+            .into_iter()
+            .map(|embedding| {
+                embedding.ok_or_else(|| EmbeddingError::Failed("worker returned too few embeddings".to_string()))
+            })
+            .collect()
+    }
+}
+
+/// Loads the text encoder, reports through `ready` whether that worked and then embeds jobs
+/// until the handle is dropped.
+fn run_worker(
+    receiver: &Mutex<mpsc::Receiver<TextJob>>,
+    ready: oneshot::Sender<Result<(), EmbeddingError>>,
+) {
+    // the encoder is async, but the work is on the CPU, so it gets a runtime of its own instead
+    // of blocking the threads that serve requests
+    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            error!("Failed to start text embedding worker: {err}");
+            let _ = ready.send(Err(EmbeddingError::Failed(err.to_string())));
+            return;
+        }
+    };
+    let embedder = match runtime.block_on(init_embedder()) {
+        Ok(embedder) => embedder,
+        Err(err) => {
+            error!("Failed to load the text encoder: {err}");
+            let _ = ready.send(Err(EmbeddingError::Failed(err.to_string())));
+            return;
+        }
+    };
+    let _ = ready.send(Ok(()));
+    info!("Text embedding worker {:?} ready", std::thread::current().name());
+    loop {
+        let jobs = {
+            let mut receiver = receiver.lock().unwrap();
+            let Some(first) = receiver.blocking_recv() else {
+                return;
+            };
+            let mut texts = first.texts.len();
+            let mut jobs = vec![first];
+            while texts < MAX_BATCH_TEXTS {
+                let Ok(job) = receiver.try_recv() else {
+                    break;
+                };
+                texts += job.texts.len();
+                jobs.push(job);
+            }
+            jobs
+        };
+
+        let texts: Vec<&str> = jobs
+            .iter()
+            .flat_map(|job| job.texts.iter().map(String::as_str))
+            .collect();
+        let result = runtime
+            .block_on(embedder.embed(&texts, None, None))
+            .map_err(|err| EmbeddingError::Failed(err.to_string()))
+            .and_then(|embedding_results| {
+                embedding_results
+                    .iter()
+                    .map(|embedding_result| {
+                        embedding_result
+                            .to_dense()
+                            .map_err(|err| EmbeddingError::Failed(err.to_string()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            });
+        match result {
+            Ok(mut embeddings) => {
+                for job in jobs {
+                    let rest = embeddings.split_off(job.texts.len().min(embeddings.len()));
+                    let _ = job.reply.send(Ok(std::mem::replace(&mut embeddings, rest)));
+                }
+            }
+            Err(err) => {
+                for job in jobs {
+                    let _ = job.reply.send(Err(err.clone()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_prompt() {
+        assert_eq!(normalize_prompt("  A Dog\ton  the beach "), "a dog on the beach");
+        assert_eq!(normalize_prompt(""), "");
+    }
+
+    #[test]
+    fn test_embedding_cache() {
+        let mut cache = EmbeddingCache::new(2);
+        cache.insert("a".to_string(), vec![1.0]);
+        cache.insert("b".to_string(), vec![2.0]);
+        // using "a" makes "b" the least recently used prompt
+        assert_eq!(cache.get("a"), Some(vec![1.0]));
+        cache.insert("c".to_string(), vec![3.0]);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(vec![1.0]));
+        assert_eq!(cache.get("c"), Some(vec![3.0]));
+        cache.insert("c".to_string(), vec![4.0]);
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.get("c"), Some(vec![4.0]));
+
+        let mut disabled = EmbeddingCache::new(0);
+        disabled.insert("a".to_string(), vec![1.0]);
+        assert_eq!(disabled.get("a"), None);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::AppState;
+use crate::database::ImagePages;
+use crate::failures::media_dir_str;
+use crate::search::{ImageType, image_record_id};
+use axum::Json;
+use axum::extract::{Path as UrlPath, State};
+use axum::http::StatusCode;
+use data::ImageCaption;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::{RecordId, Surreal};
+
+/// Damping of reciprocal rank fusion, the usual value from the original paper. Larger values
+/// flatten the difference between the top ranks.
+const RRF_K: f32 = 60.0;
+
+/// Words of the path relative to the media directory, the caption and the notes of an image, as
+/// indexed for full-text search. Separators like `/`, `_` and `.` become spaces, so
+/// `2023_Rome_Trip/IMG_1234.jpg` is found by `rome trip`.
+pub fn search_text(relative_path: &str, caption: Option<&str>, notes: Option<&str>) -> String {
+    let path_words = relative_path
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    std::iter::once(path_words.as_str())
+        .chain(caption)
+        .chain(notes)
+        .filter(|text| !text.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Fuses several rankings, best first, into one: every item scores `1 / (RRF_K + rank)` in each
+/// ranking it appears in. Only ranks matter, so scores on different scales like BM25 and cosine
+/// similarity can be combined. Ties keep the order in which the items were first seen.
+pub fn reciprocal_rank_fusion<T: Eq + Hash + Clone>(rankings: &[Vec<T>]) -> Vec<T> {
+    let mut scores: HashMap<T, (f32, usize)> = HashMap::new();
+    for ranking in rankings {
+        for (rank, item) in ranking.iter().enumerate() {
+            let first_seen = scores.len();
+            let entry = scores.entry(item.clone()).or_insert((0.0, first_seen));
+            entry.0 += 1.0 / (RRF_K + rank as f32 + 1.0);
+        }
+    }
+    let mut fused: Vec<(T, (f32, usize))> = scores.into_iter().collect();
+    fused.sort_by(|(_, (a, a_seen)), (_, (b, b_seen))| b.total_cmp(a).then(a_seen.cmp(b_seen)));
+    fused.into_iter().map(|(item, _)| item).collect()
+}
+
+/// Defines the analyzer and the BM25 index of the search texts, once when connecting.
+pub async fn define_text_index(db: &Surreal<Client>) -> Result<(), surrealdb::Error> {
+    db.query("DEFINE ANALYZER IF NOT EXISTS image_text TOKENIZERS blank, class FILTERS lowercase, ascii, snowball(english);")
+        .query("DEFINE INDEX IF NOT EXISTS image_text_index ON image FIELDS search_text SEARCH ANALYZER image_text BM25;")
+        .await?
+        .check()?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct TextSource {
+    id: RecordId,
+    image_path: String,
+    #[serde(default)]
+    caption: Option<String>,
+    #[serde(default)]
+    notes: Option<String>,
+}
+
+impl TextSource {
+    fn search_text(&self, media_dir_str: &str) -> String {
+        search_text(
+            &self.image_path.replace(media_dir_str, ""),
+            self.caption.as_deref(),
+            self.notes.as_deref(),
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ImageText {
+    id: RecordId,
+    search_text: String,
+}
+
+/// Gives every whole file without one a search text, so new images can be found by their path.
+/// Returns the number of images updated.
+pub async fn update_text_index(db: &Surreal<Client>, media_dir_str: &str) -> Result<usize, surrealdb::Error> {
+    let mut updated = 0;
+    let mut pages = ImagePages::new(
+        db,
+        "id, image_path, caption, notes",
+        "AND search_text IS NONE",
+        |image: &TextSource| &image.id,
+    );
+    while let Some(page) = pages.next_page().await? {
+        let texts: Vec<ImageText> = page
+            .iter()
+            .map(|image| ImageText {
+                id: image.id.clone(),
+                search_text: image.search_text(media_dir_str),
+            })
+            .collect();
+        updated += texts.len();
+        db.query("FOR $image IN $texts { UPDATE $image.id SET search_text = $image.search_text; }")
+            .bind(("texts", texts))
+            .await?
+            .check()?;
+    }
+    Ok(updated)
+}
+
+/// Whole files whose path, caption or notes match the words of `q`, best BM25 score first.
+/// `within` restricts the matches to the given images.
+pub async fn lexical_nearest(
+    db: &Surreal<Client>,
+    q: &str,
+    k: usize,
+    within: Option<Vec<RecordId>>,
+) -> Result<Vec<ImageType>, surrealdb::Error> {
+    let mut response = match within {
+        None => db
+            .query(
+                "SELECT id, image_path, embedding, kind, search::score(1) AS score FROM image
+                WHERE search_text @1@ $q ORDER BY score DESC LIMIT $limit",
+            )
+            .bind(("q", q.to_string()))
+            .bind(("limit", k))
+            .await?,
+        Some(ids) => db
+            .query(
+                "SELECT id, image_path, embedding, kind, search::score(1) AS score FROM image
+                WHERE search_text @1@ $q AND id IN $ids ORDER BY score DESC LIMIT $limit",
+            )
+            .bind(("q", q.to_string()))
+            .bind(("ids", ids))
+            .bind(("limit", k))
+            .await?,
+    };
+    response.take(0)
+}
+
+/// Replaces the caption and notes of an image and indexes them for full-text search.
+pub async fn web_update_caption(
+    State(state): State<AppState>,
+    UrlPath(image_id): UrlPath<String>,
+    Json(caption): Json<ImageCaption>,
+) -> Result<Json<ImageCaption>, StatusCode> {
+    let id = image_record_id(&image_id)?;
+    let media_dir_str = media_dir_str(&state)?;
+    let db = &state.db;
+    // keyframes, frames and regions would become text matches that are not collapsed into their file
+    let mut response = db
+        .query("SELECT VALUE image_path FROM $id WHERE parent IS NONE")
+        .bind(("id", id.clone()))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let image_path: Vec<String> = response.take(0).map_err(|err| {
+        error!("Failed to deserialize response: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let non_empty = |text: Option<String>| text.filter(|text| !text.trim().is_empty());
+    let source = TextSource {
+        id: id.clone(),
+        image_path: image_path.into_iter().next().ok_or(StatusCode::NOT_FOUND)?,
+        caption: non_empty(caption.caption),
+        notes: non_empty(caption.notes),
+    };
+    db.query("UPDATE $id SET caption = $caption, notes = $notes, search_text = $search_text")
+        .bind(("id", id))
+        .bind(("search_text", source.search_text(&media_dir_str)))
+        .bind(("caption", source.caption.clone()))
+        .bind(("notes", source.notes.clone()))
+        .await
+        .map_err(|err| {
+            error!("DB query error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(ImageCaption {
+        caption: source.caption,
+        notes: source.notes,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_text() {
+        assert_eq!(
+            search_text("/2023_Rome_Trip/IMG_1234.jpg", Some("Colosseum at night"), None),
+            "2023 Rome Trip IMG 1234 jpg\nColosseum at night"
+        );
+        assert_eq!(search_text("a-b.png", Some(" "), Some("note")), "a b png\nnote");
+    }
+
+    #[test]
+    fn test_reciprocal_rank_fusion() {
+        // found by both rankings beats first place in only one of them
+        let fused = reciprocal_rank_fusion(&[vec!["a", "b", "c"], vec!["d", "b", "e"]]);
+        assert_eq!(fused, vec!["b", "a", "d", "c", "e"]);
+        assert_eq!(reciprocal_rank_fusion::<&str>(&[]), Vec::<&str>::new());
+    }
+}
@@ -0,0 +1,61 @@
+const LANES: usize = 8;
+
+/// Dot product with independent accumulators per lane so the compiler can vectorize the loop.
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    debug_assert_eq!(a.len(), b.len(), "vectors must have the same length");
+    let chunks_a = a.chunks_exact(LANES);
+    let chunks_b = b.chunks_exact(LANES);
+    let remainder: f32 = chunks_a
+        .remainder()
+        .iter()
+        .zip(chunks_b.remainder())
+        .map(|(x, y)| x * y)
+        .sum();
+
+    let mut lanes = [0.0f32; LANES];
+    for (chunk_a, chunk_b) in chunks_a.zip(chunks_b) {
+        for ((lane, x), y) in lanes.iter_mut().zip(chunk_a).zip(chunk_b) {
+            *lane += x * y;
+        }
+    }
+    lanes.iter().sum::<f32>() + remainder
+}
+
+pub fn norm(a: &[f32]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+/// Cosine distance as returned by SurrealDB's `vector::distance::knn()` on a `DIST COSINE` index.
+/// Takes the precomputed norm of `a` since it is usually scored against many vectors.
+pub fn cosine_distance_with_norm(a: &[f32], a_norm: f32, b: &[f32]) -> f32 {
+    let denominator = a_norm * norm(b);
+    if denominator == 0.0 {
+        return 1.0;
+    }
+    1.0 - dot(a, b) / denominator
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dot_matches_naive() {
+        let a: Vec<f32> = (0..21).map(|i| i as f32 * 0.5).collect();
+        let b: Vec<f32> = (0..21).map(|i| 3.0 - i as f32).collect();
+        let naive: f32 = a.iter().zip(&b).map(|(x, y)| x * y).sum();
+        assert!((dot(&a, &b) - naive).abs() < 1e-3);
+    }
+
+    fn cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+        cosine_distance_with_norm(a, norm(a), b)
+    }
+
+    #[test]
+    fn test_cosine_distance() {
+        assert!(cosine_distance(&[1.0, 0.0], &[2.0, 0.0]).abs() < 1e-6);
+        assert!((cosine_distance(&[1.0, 0.0], &[0.0, 3.0]) - 1.0).abs() < 1e-6);
+        assert!((cosine_distance(&[1.0, 0.0], &[-1.0, 0.0]) - 2.0).abs() < 1e-6);
+        assert_eq!(cosine_distance(&[0.0, 0.0], &[1.0, 0.0]), 1.0);
+    }
+}
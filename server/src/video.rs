@@ -0,0 +1,142 @@
+use image::error::{DecodingError, ImageFormatHint};
+use image::{DynamicImage, ImageError, RgbImage};
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+pub const VIDEO_EXTENSIONS: &[&str] = &["mp4", "mov", "m4v", "mkv", "webm", "avi"];
+/// Upper bound of keyframes per video, so a feature film does not stall the scan.
+const MAX_KEYFRAMES: usize = 256;
+/// Frames are scaled by ffmpeg to the input size of the vision model.
+const FRAME_SIZE: u32 = 224;
+
+pub fn is_video(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| VIDEO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Keyframe sampling, either every `interval` seconds or at scene changes.
+#[derive(Debug, Clone, Copy)]
+pub enum KeyframeSampling {
+    Interval(f64),
+    SceneChange(f32),
+}
+
+impl KeyframeSampling {
+    fn filter(&self) -> String {
+        match self {
+            KeyframeSampling::Interval(seconds) => format!("fps=1/{seconds}"),
+            // the first frame is always kept so short clips without cuts still get a keyframe
+            KeyframeSampling::SceneChange(threshold) => {
+                format!("select=eq(n\\,0)+gt(scene\\,{threshold})")
+            }
+        }
+    }
+}
+
+/// Samples keyframes with ffmpeg and returns them with their timestamp in seconds.
+pub fn extract_keyframes(
+    path: &Path,
+    sampling: KeyframeSampling,
+) -> Result<Vec<(f64, DynamicImage)>, ImageError> {
+    let mut child = Command::new("ffmpeg")
+        .args(["-hide_banner", "-nostdin", "-loglevel", "info", "-i"])
+        .arg(path)
+        .args([
+            "-vf",
+            &format!("{},showinfo,scale={FRAME_SIZE}:{FRAME_SIZE}", sampling.filter()),
+            "-fps_mode",
+            "vfr",
+            "-frames:v",
+            &MAX_KEYFRAMES.to_string(),
+            "-f",
+            "rawvideo",
+            "-pix_fmt",
+            "rgb24",
+            "pipe:1",
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // stderr has to be drained while reading frames, otherwise ffmpeg blocks on a full pipe
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let log_reader = std::thread::spawn(move || {
+        let mut log = String::new();
+        let _ = stderr.read_to_string(&mut log);
+        log
+    });
+
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let frame_len = (FRAME_SIZE * FRAME_SIZE * 3) as usize;
+    let mut frames = Vec::new();
+    loop {
+        let mut frame = vec![0u8; frame_len];
+        match stdout.read_exact(&mut frame) {
+            Ok(()) => frames.push(frame),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let status = child.wait()?;
+    let log = log_reader.join().unwrap_or_default();
+    if !status.success() {
+        let tail: String = log.lines().rev().take(3).collect::<Vec<_>>().join(" | ");
+        return Err(video_error(format!("ffmpeg exited with {status}: {tail}")));
+    }
+
+    let timestamps = parse_timestamps(&log);
+    if timestamps.len() < frames.len() {
+        return Err(video_error(format!(
+            "ffmpeg reported {} timestamps for {} frames",
+            timestamps.len(),
+            frames.len()
+        )));
+    }
+    Ok(timestamps
+        .into_iter()
+        .zip(frames)
+        .filter_map(|(timestamp, frame)| {
+            RgbImage::from_raw(FRAME_SIZE, FRAME_SIZE, frame)
+                .map(|image| (timestamp, DynamicImage::ImageRgb8(image)))
+        })
+        .collect())
+}
+
+/// Reads the `pts_time` of every frame from the output of ffmpeg's `showinfo` filter.
+fn parse_timestamps(log: &str) -> Vec<f64> {
+    log.lines()
+        .filter(|line| line.contains("Parsed_showinfo"))
+        .filter_map(|line| {
+            let value = line.split("pts_time:").nth(1)?;
+            value.split_whitespace().next()?.parse().ok()
+        })
+        .collect()
+}
+
+fn video_error(message: String) -> ImageError {
+    ImageError::Decoding(DecodingError::new(ImageFormatHint::Name("video".to_string()), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamps() {
+        let log = "\
+[Parsed_showinfo_1 @ 0x5581] config in time_base: 1/90000, frame_rate: 1/10
+[Parsed_showinfo_1 @ 0x5581] n:   0 pts:      0 pts_time:0       duration:9000 fmt:yuv420p
+[Parsed_showinfo_1 @ 0x5581] n:   1 pts: 900000 pts_time:10      duration:9000 fmt:yuv420p
+[Parsed_showinfo_1 @ 0x5581] n:   2 pts:1800000 pts_time:20.5    duration:9000 fmt:yuv420p
+frame=    3 fps=0.0 q=-0.0 Lsize=     441kB time=00:00:30.00";
+        assert_eq!(parse_timestamps(log), vec![0.0, 10.0, 20.5]);
+    }
+
+    #[test]
+    fn test_is_video() {
+        assert!(is_video(Path::new("clips/GOPR0001.MP4")));
+        assert!(!is_video(Path::new("IMG_0001.jpg")));
+    }
+}
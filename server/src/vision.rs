@@ -0,0 +1,330 @@
+use burn::prelude::Tensor;
+use burn_wgpu::graphics::AutoGraphicsApi;
+use burn_wgpu::{RuntimeOptions, Wgpu, WgpuDevice, init_setup};
+use clip::clip_vit_large_patch14::Model;
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+/// Number of floats of one preprocessed image in CHW layout.
+pub const IMAGE_LEN: usize = 3 * 224 * 224;
+pub const EMBEDDING_DIMENSION: usize = 768;
+
+#[derive(Debug)]
+pub enum InferenceError {
+    WorkerStopped,
+    /// The device could not allocate memory for a batch of this many images.
+    OutOfMemory(usize),
+    Failed(String),
+}
+
+impl Display for InferenceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InferenceError::WorkerStopped => write!(f, "vision worker stopped"),
+            InferenceError::OutOfMemory(images) => {
+                write!(f, "out of device memory for a batch of {images} images")
+            }
+            InferenceError::Failed(message) => write!(f, "inference failed: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for InferenceError {}
+
+struct VisionJob {
+    pixels: Vec<f32>,
+    images: usize,
+    reply: oneshot::Sender<Result<Vec<Vec<f32>>, InferenceError>>,
+}
+
+/// Learns how many images fit into one forward pass on the device. Starts without a limit and
+/// bisects between the largest batch that worked and the smallest batch that ran out of memory.
+#[derive(Debug, Default)]
+pub struct BatchSizer {
+    largest_ok: usize,
+    smallest_failed: Option<usize>,
+}
+
+impl BatchSizer {
+    /// Starts from a limit learned earlier on the same device.
+    pub fn remembered(limit: usize) -> Self {
+        Self {
+            largest_ok: limit,
+            smallest_failed: Some(limit + 1),
+        }
+    }
+
+    /// Largest batch that worked after a batch ran out of memory, `None` as long as no batch ran
+    /// out of memory or nothing smaller has worked yet.
+    pub fn limit(&self) -> Option<usize> {
+        self.smallest_failed
+            .filter(|_| self.largest_ok > 0)
+            .map(|_| self.largest_ok)
+    }
+
+    pub fn next_size(&self, requested: usize) -> usize {
+        match self.smallest_failed {
+            None => requested,
+            Some(failed) if self.largest_ok + 1 >= failed => requested.min(self.largest_ok.max(1)),
+            Some(failed) => requested.min(((self.largest_ok + failed) / 2).max(1)),
+        }
+    }
+
+    pub fn on_success(&mut self, images: usize) {
+        if self.smallest_failed.is_none_or(|failed| images < failed) {
+            self.largest_ok = self.largest_ok.max(images);
+        }
+    }
+
+    /// Returns the size to retry with, or `None` if a single image does not fit.
+    pub fn on_out_of_memory(&mut self, images: usize) -> Option<usize> {
+        self.smallest_failed = Some(self.smallest_failed.map_or(images, |f| f.min(images)));
+        if self.largest_ok >= images {
+            // the device has less memory free than when this size worked, start over below it
+            self.largest_ok = 0;
+        }
+        (images > 1).then(|| self.next_size(images))
+    }
+}
+
+/// Handle to the thread that owns the vision model on the GPU. The model is loaded on first use
+/// and then kept, so the indexer and request handlers share one copy.
+#[derive(Clone)]
+pub struct VisionWorker {
+    sender: mpsc::Sender<VisionJob>,
+    device_name: Arc<str>,
+    batch_sizer: Arc<Mutex<BatchSizer>>,
+    out_of_memory_retries: Arc<AtomicU64>,
+}
+
+impl VisionWorker {
+    pub fn spawn(model_weights: String) -> std::io::Result<Self> {
+        let (sender, mut receiver) = mpsc::channel::<VisionJob>(1);
+        let (name_sender, name_receiver) = std::sync::mpsc::channel::<String>();
+        std::thread::Builder::new()
+            .name("vision".to_string())
+            // WGPU needs a large stack when building the compute graph of the model
+            .stack_size(32 * 1024 * 1024)
+            .spawn(move || {
+                let device = WgpuDevice::DefaultDevice;
+                let _ = name_sender.send(adapter_name(&device));
+                let mut model: Option<Model<Wgpu>> = None;
+                while let Some(job) = receiver.blocking_recv() {
+                    let model = model.get_or_insert_with(|| {
+                        info!("Loading vision model from {model_weights}");
+                        Model::from_file(model_weights.as_str(), &device)
+                    });
+                    let images = job.images;
+                    // allocation failures in WGPU surface as panics, catch them so the caller can retry smaller
+                    let result = catch_unwind(AssertUnwindSafe(|| {
+                        forward(model, &device, job.pixels, images)
+                    }))
+                    .map_err(|panic| {
+                        let message = panic
+                            .downcast_ref::<&str>()
+                            .map(|message| message.to_string())
+                            .or_else(|| panic.downcast_ref::<String>().cloned())
+                            .unwrap_or_default();
+                        if is_out_of_memory(&message) {
+                            InferenceError::OutOfMemory(images)
+                        } else {
+                            InferenceError::Failed(message)
+                        }
+                    });
+                    let _ = job.reply.send(result);
+                }
+            })?;
+        let device_name = name_receiver
+            .recv()
+            .map_err(|_| std::io::Error::other("vision worker stopped before naming its device"))?;
+        Ok(Self {
+            sender,
+            device_name: device_name.into(),
+            batch_sizer: Arc::new(Mutex::new(BatchSizer::default())),
+            out_of_memory_retries: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Name of the GPU under which the learned batch size limit is stored.
+    pub fn device_name(&self) -> String {
+        self.device_name.to_string()
+    }
+
+    pub fn batch_size_limit(&self) -> Option<usize> {
+        self.batch_sizer.lock().unwrap().limit()
+    }
+
+    pub fn remember_batch_size_limit(&self, limit: usize) {
+        info!("Using remembered batch size limit of {limit} images for {}", self.device_name());
+        *self.batch_sizer.lock().unwrap() = BatchSizer::remembered(limit);
+    }
+
+    pub fn out_of_memory_retries(&self) -> u64 {
+        self.out_of_memory_retries.load(Ordering::Relaxed)
+    }
+
+    /// Embeds preprocessed images of `IMAGE_LEN` floats each. The images are split into batches
+    /// that fit the device, a batch that runs out of memory is split and retried. Each attempt
+    /// copies its images into the buffer the model consumes, so pixels are only copied again for
+    /// a retry.
+    pub async fn embed_adaptive(&self, images: &[&[f32]]) -> Result<Vec<Vec<f32>>, InferenceError> {
+        let mut embeddings = Vec::with_capacity(images.len());
+        let mut pending: VecDeque<Range<usize>> = VecDeque::new();
+        let first_size = self.batch_sizer.lock().unwrap().next_size(images.len());
+        split_front(&mut pending, 0..images.len(), first_size);
+
+        while let Some(batch) = pending.pop_front() {
+            let batch_images = batch.len();
+            let size = self.batch_sizer.lock().unwrap().next_size(batch_images);
+            if size < batch_images {
+                split_front(&mut pending, batch, size);
+                continue;
+            }
+            match self.embed(images[batch.clone()].concat(), batch_images).await {
+                Ok(batch_embeddings) => {
+                    self.batch_sizer.lock().unwrap().on_success(batch_images);
+                    embeddings.extend(batch_embeddings);
+                }
+                Err(InferenceError::OutOfMemory(_)) => {
+                    self.out_of_memory_retries.fetch_add(1, Ordering::Relaxed);
+                    let retry_size = self
+                        .batch_sizer
+                        .lock()
+                        .unwrap()
+                        .on_out_of_memory(batch_images)
+                        .ok_or(InferenceError::OutOfMemory(batch_images))?;
+                    warn!(
+                        "Out of device memory for a batch of {batch_images} images, retrying with {retry_size}"
+                    );
+                    split_front(&mut pending, batch, retry_size);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(embeddings)
+    }
+
+    /// Embeds `images` preprocessed images laid out back to back in `pixels` in one forward pass.
+    pub async fn embed(
+        &self,
+        pixels: Vec<f32>,
+        images: usize,
+    ) -> Result<Vec<Vec<f32>>, InferenceError> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(VisionJob {
+                pixels,
+                images,
+                reply,
+            })
+            .await
+            .map_err(|_| InferenceError::WorkerStopped)?;
+        response.await.map_err(|_| InferenceError::WorkerStopped)?
+    }
+}
+
+/// Puts the images in `batch` back at the front of the queue as batches of `size` images,
+/// keeping their order.
+fn split_front(pending: &mut VecDeque<Range<usize>>, batch: Range<usize>, size: usize) {
+    let starts: Vec<usize> = batch.clone().step_by(size.max(1)).collect();
+    for start in starts.into_iter().rev() {
+        pending.push_front(start..(start + size.max(1)).min(batch.end));
+    }
+}
+
+/// Name and graphics API of the adapter behind `device`, e.g. "NVIDIA GeForce RTX 3060 (Vulkan)".
+/// Sets up the device, so it has to run before the model is loaded on it.
+fn adapter_name(device: &WgpuDevice) -> String {
+    catch_unwind(AssertUnwindSafe(|| {
+        let setup = init_setup::<AutoGraphicsApi>(device, RuntimeOptions::default());
+        let info = setup.adapter.get_info();
+        format!("{} ({:?})", info.name, info.backend)
+    }))
+    .unwrap_or_else(|_| {
+        warn!("No graphics adapter found, inference will fail");
+        format!("{device:?}")
+    })
+}
+
+fn is_out_of_memory(message: &str) -> bool {
+    let message = message.to_lowercase();
+    ["out of memory", "outofmemory", "not enough memory", "failed to allocate"]
+        .iter()
+        .any(|pattern| message.contains(pattern))
+}
+
+fn forward(model: &Model<Wgpu>, device: &WgpuDevice, pixels: Vec<f32>, images: usize) -> Vec<Vec<f32>> {
+    let image_data = burn::tensor::TensorData::new(pixels, [images, 3, 224, 224]);
+    let image_tensor = Tensor::<Wgpu, 4>::from_data(image_data.convert::<f32>(), device);
+    let output = model.forward(image_tensor);
+
+    let data = output.to_data();
+    let bytes = data.bytes;
+    let float_data: Vec<f32> = bytemuck::cast_slice(&bytes).to_vec();
+    float_data
+        .chunks(EMBEDDING_DIMENSION)
+        .map(|chunk| chunk.to_vec())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_sizer() {
+        let mut sizer = BatchSizer::default();
+        assert_eq!(sizer.next_size(64), 64);
+        assert_eq!(sizer.on_out_of_memory(64), Some(32));
+        // nothing has worked yet, so there is no limit to remember
+        assert_eq!(sizer.limit(), None);
+        assert_eq!(sizer.next_size(64), 32);
+        sizer.on_success(32);
+        assert_eq!(sizer.limit(), Some(32));
+        // bisects between the largest batch that worked and the smallest that failed
+        assert_eq!(sizer.next_size(64), 48);
+        assert_eq!(sizer.on_out_of_memory(48), Some(40));
+        sizer.on_success(40);
+        assert_eq!(sizer.next_size(64), 44);
+        sizer.on_success(44);
+        sizer.on_success(46);
+        sizer.on_success(47);
+        assert_eq!(sizer.next_size(64), 47);
+        assert_eq!(sizer.limit(), Some(47));
+        // a batch above a known failure does not raise the limit
+        sizer.on_success(64);
+        assert_eq!(sizer.limit(), Some(47));
+        // a size that worked before fails, only sizes that work again count
+        assert_eq!(sizer.on_out_of_memory(32), Some(16));
+        assert_eq!(sizer.limit(), None);
+        assert_eq!(sizer.on_out_of_memory(1), None);
+    }
+
+    #[test]
+    fn test_split_front() {
+        let mut pending = VecDeque::from([10..12]);
+        split_front(&mut pending, 0..10, 4);
+        assert_eq!(pending, VecDeque::from([0..4, 4..8, 8..10, 10..12]));
+    }
+
+    #[test]
+    fn test_is_out_of_memory() {
+        assert!(is_out_of_memory("Out of Memory"));
+        assert!(is_out_of_memory("wgpu error: Validation Error: Not enough memory left"));
+        assert!(!is_out_of_memory("Allocation of the staging buffer must be mapped"));
+        assert!(!is_out_of_memory("index out of bounds"));
+    }
+
+    #[test]
+    fn test_remembered_batch_sizer() {
+        let sizer = BatchSizer::remembered(24);
+        assert_eq!(sizer.next_size(32), 24);
+        assert_eq!(sizer.next_size(8), 8);
+    }
+}